@@ -0,0 +1,253 @@
+// Acknowledgements mark a failed item as being looked at, so that
+// notifiers don't keep reporting it as new. An ack is keyed by source id
+// and item id (a state item such as a unit name, or a `Failure` id). It is
+// surfaced as an `acknowledged` attribute, and cleared once the item
+// recovers or the ack expires.
+
+use std::collections::{HashMap,BTreeMap};
+use std::ops::Deref;
+use std::sync::{Arc,Mutex};
+use rustc_serialize::json::{Json};
+use monitor::*;
+use config::AckRequest;
+use problems::PROBLEMS_TYPE;
+
+pub const ACKNOWLEDGED_ATTR: &'static str = "acknowledged";
+
+pub fn is_acknowledged(attrs: &Attributes) -> bool {
+	attrs.contains_key(ACKNOWLEDGED_ATTR)
+}
+
+// Whether notifiers should ignore this update
+pub fn is_acknowledged_update(update: &Update) -> bool {
+	match update.data {
+		Data::Event(ref event) => is_acknowledged(&event.attrs),
+		Data::Alert(ref alert) => is_acknowledged(&alert.attrs),
+		_ => false,
+	}
+}
+
+fn recovered(state: &State) -> bool {
+	match *state {
+		State::Active | State::Inactive => true,
+		_ => false,
+	}
+}
+
+struct Ack {
+	comment: Option<String>,
+	// in seconds since the epoch
+	time: i64,
+	expires: Option<i64>,
+}
+
+impl Ack {
+	fn to_json(&self) -> Json {
+		let mut rv = BTreeMap::new();
+		rv.insert("time".to_string(), Json::I64(self.time));
+		match self.comment {
+			Some(ref comment) => { rv.insert("comment".to_string(), Json::String(comment.clone())); },
+			None => (),
+		}
+		match self.expires {
+			Some(expires) => { rv.insert("expires".to_string(), Json::I64(expires)); },
+			None => (),
+		}
+		Json::Object(rv)
+	}
+}
+
+struct Inner {
+	// source id -> item id -> ack
+	acks: HashMap<String, HashMap<String, Ack>>,
+	// incremented whenever acks are added or removed from outside of `apply`
+	generation: u64,
+	seen: u64,
+}
+
+impl Inner {
+	fn get(&self, source: &str, id: &str) -> Option<&Ack> {
+		self.acks.get(source).and_then(|items| items.get(id))
+	}
+
+	fn remove(&mut self, source: &str, id: &str) -> bool {
+		let (removed, empty) = match self.acks.get_mut(source) {
+			Some(items) => (items.remove(id).is_some(), items.is_empty()),
+			None => (false, false),
+		};
+		if empty {
+			self.acks.remove(source);
+		}
+		removed
+	}
+}
+
+pub struct Acks {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl Clone for Acks {
+	fn clone(&self) -> Acks {
+		Acks { inner: self.inner.clone() }
+	}
+}
+
+impl Acks {
+	pub fn new() -> Acks {
+		Acks {
+			inner: Arc::new(Mutex::new(Inner {
+				acks: HashMap::new(),
+				generation: 0,
+				seen: 0,
+			})),
+		}
+	}
+
+	pub fn add(&self, request: AckRequest) -> Result<(), String> {
+		let now = Time::now().timestamp();
+		let expires = match (request.expires, request.duration) {
+			(Some(expires), _) => Some(expires),
+			(None, Some(duration)) => Some(now + duration.num_seconds()),
+			(None, None) => None,
+		};
+		match expires {
+			Some(expires) if expires <= now => return Err("ack has already expired".to_string()),
+			_ => (),
+		}
+		let mut inner = self.inner.lock().unwrap();
+		inner.acks.entry(request.source).or_insert_with(HashMap::new).insert(request.id, Ack {
+			comment: request.comment,
+			time: now,
+			expires: expires,
+		});
+		inner.generation += 1;
+		Ok(())
+	}
+
+	pub fn remove(&self, source: &str, id: &str) -> bool {
+		let mut inner = self.inner.lock().unwrap();
+		let removed = inner.remove(source, id);
+		if removed {
+			inner.generation += 1;
+		}
+		removed
+	}
+
+	pub fn get(&self, source: &str, id: &str) -> Option<Json> {
+		let inner = self.inner.lock().unwrap();
+		inner.get(source, id).map(Ack::to_json)
+	}
+
+	pub fn to_json(&self) -> Json {
+		let inner = self.inner.lock().unwrap();
+		let mut rv = Vec::new();
+		for (source, items) in inner.acks.iter() {
+			for (id, ack) in items.iter() {
+				let mut json = match ack.to_json() {
+					Json::Object(json) => json,
+					_ => BTreeMap::new(),
+				};
+				json.insert("source".to_string(), Json::String(source.clone()));
+				json.insert("id".to_string(), Json::String(id.clone()));
+				rv.push(Json::Object(json));
+			}
+		}
+		Json::Array(rv)
+	}
+
+	// Expires old acks, and returns whether any have been added or removed
+	// since the last call (in which case the current state should be re-flagged)
+	pub fn changed(&self) -> bool {
+		let now = Time::now().timestamp();
+		let mut inner = self.inner.lock().unwrap();
+		let expired: Vec<(String, String)> = inner.acks.iter()
+			.flat_map(|(source, items)| items.iter()
+				.filter(|&(_, ack)| ack.expires.map(|e| e <= now).unwrap_or(false))
+				.map(move |(id, _)| (source.clone(), id.clone())))
+			.collect();
+		for (source, id) in expired {
+			inner.remove(source.deref(), id.deref());
+			inner.generation += 1;
+		}
+		let changed = inner.generation != inner.seen;
+		inner.seen = inner.generation;
+		changed
+	}
+
+	// Flags acknowledged items (or events / alerts about them) in `update`.
+	// Acks for items which have recovered are dropped.
+	pub fn apply(&self, update: Arc<Update>) -> Arc<Update> {
+		// problems are flagged by `Problems` itself, since
+		// they're keyed by the source which reported them
+		if update.source.typ == PROBLEMS_TYPE {
+			return update;
+		}
+		let data = {
+			let mut inner = self.inner.lock().unwrap();
+			let source = update.source.id.deref();
+			let flag = |attrs: &Arc<Attributes>, ack: Option<Json>| {
+				let mut attrs = (**attrs).clone();
+				match ack {
+					Some(ack) => { attrs.insert(ACKNOWLEDGED_ATTR.to_string(), ack); },
+					None => { attrs.remove(ACKNOWLEDGED_ATTR); },
+				}
+				Arc::new(attrs)
+			};
+
+			match update.data {
+				Data::State(ref state) => {
+					let mut changed = false;
+					let mut rv = HashMap::with_capacity(state.len());
+					for (id, status) in state.iter() {
+						if recovered(&status.state) {
+							inner.remove(source, id);
+						}
+						let ack = inner.get(source, id).map(Ack::to_json);
+						if status.attrs.get(ACKNOWLEDGED_ATTR) == ack.as_ref() {
+							rv.insert(id.clone(), status.clone());
+							continue;
+						}
+						changed = true;
+						rv.insert(id.clone(), Status {
+							state: status.state.clone(),
+							attrs: flag(&status.attrs, ack),
+						});
+					}
+					if changed { Some(Data::State(rv)) } else { None }
+				},
+				Data::Event(ref event) => {
+					let ack = event.id.as_ref().and_then(|id| inner.get(source, id).map(Ack::to_json));
+					ack.map(|ack| Data::Event(Event {
+						id: event.id.clone(),
+						severity: event.severity.clone(),
+						message: event.message.clone(),
+						attrs: flag(&event.attrs, Some(ack)),
+					}))
+				},
+				Data::Alert(ref alert) if !is_acknowledged(&alert.attrs) => {
+					let ack = alert.item.as_ref().and_then(|id| inner.get(source, id).map(Ack::to_json));
+					ack.map(|ack| Data::Alert(Alert {
+						id: alert.id.clone(),
+						item: alert.item.clone(),
+						state: alert.state.clone(),
+						severity: alert.severity.clone(),
+						message: alert.message.clone(),
+						since: alert.since.clone(),
+						attrs: flag(&alert.attrs, Some(ack)),
+					}))
+				},
+				_ => None,
+			}
+		};
+
+		match data {
+			None => update,
+			Some(data) => Arc::new(Update {
+				source: update.source.clone(),
+				scope: update.scope.clone(),
+				time: update.time.clone(),
+				data: data,
+			}),
+		}
+	}
+}
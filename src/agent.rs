@@ -0,0 +1,203 @@
+// Pushes batches of updates to a central collector (for hosts which
+// the collector can't reach directly). Batches which can't be delivered
+// are buffered on disk until the collector is reachable again.
+
+use std::fs;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::io::{BufRead,BufReader,Read,Write};
+use std::ops::Deref;
+use std::path::{Path,PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc,Mutex};
+use std::thread;
+use std::time;
+use rustc_serialize::json;
+use rustc_serialize::json::{Json};
+use hyper;
+use hyper::header::{Headers,ContentType};
+use monitor::*;
+use config::AgentConfig;
+use system_monitor::SystemMonitor;
+use worker::{Worker,WorkerSelf};
+use super::errors::*;
+
+const BUFFER_FILENAME: &'static str = "agent-buffer.jsonl";
+
+struct Agent {
+	config: AgentConfig,
+	buffer_path: PathBuf,
+}
+
+impl Agent {
+	fn new(config: AgentConfig) -> Agent {
+		let buffer_path = Path::new(config.buffer_dir.deref()).join(BUFFER_FILENAME);
+		Agent {
+			config: config,
+			buffer_path: buffer_path,
+		}
+	}
+
+	fn post(&self, updates: &[String]) -> Result<(), InternalError> {
+		// updates are already encoded, so we build the body by hand
+		let body = format!("{{\"host\":{},\"updates\":[{}]}}",
+			Json::String(self.config.host.clone()),
+			updates.join(","));
+
+		let mut headers = Headers::new();
+		headers.set(ContentType::json());
+		match self.config.token {
+			Some(ref token) => headers.set_raw("Authorization", vec!(format!("Bearer {}", token).into_bytes())),
+			None => (),
+		};
+
+		let mut client = hyper::Client::new();
+		let timeout = time::Duration::from_millis(self.config.interval.num_milliseconds() as u64 * 2);
+		client.set_read_timeout(Some(timeout));
+		client.set_write_timeout(Some(timeout));
+		let mut response = try!(client.post(self.config.url.deref())
+			.headers(headers)
+			.body(body.deref())
+			.send());
+		if response.status != hyper::status::StatusCode::Ok {
+			let mut detail = String::new();
+			ignore_error!(response.read_to_string(&mut detail).map(|_| ()), "reading collector response");
+			return Err(InternalError::new(format!("collector returned {}: {}", response.status, detail)));
+		}
+		Ok(())
+	}
+
+	// Sends updates in batches. On failure, returns the updates
+	// which weren't accepted along with the error.
+	fn send<'a>(&self, updates: &'a [String]) -> Result<(), (&'a [String], InternalError)> {
+		let mut sent = 0;
+		for batch in updates.chunks(self.config.batch_size) {
+			match self.post(batch) {
+				Ok(()) => sent += batch.len(),
+				Err(e) => return Err((&updates[sent..], e)),
+			}
+		}
+		Ok(())
+	}
+
+	fn buffered(&self) -> Result<Vec<String>, InternalError> {
+		match File::open(&self.buffer_path) {
+			Ok(file) => {
+				let mut rv = Vec::new();
+				for line in BufReader::new(file).lines() {
+					let line = try!(line);
+					if !line.is_empty() {
+						rv.push(line);
+					}
+				}
+				Ok(rv)
+			},
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+			Err(e) => Err(InternalError::from(e)),
+		}
+	}
+
+	fn buffer(&self, updates: &[String]) -> Result<(), InternalError> {
+		let size = match fs::metadata(&self.buffer_path) {
+			Ok(meta) => meta.len(),
+			Err(_) => 0,
+		};
+		if size >= self.config.max_buffer_bytes {
+			warn!("agent buffer is full, dropping {} updates", updates.len());
+			return Ok(());
+		}
+		try!(fs::create_dir_all(&self.config.buffer_dir));
+		let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.buffer_path));
+		for update in updates {
+			try!(writeln!(file, "{}", update));
+		}
+		Ok(())
+	}
+
+	fn flush(&self, pending: Vec<String>) -> Result<(), InternalError> {
+		// anything already on disk must be sent first, to preserve ordering
+		let buffered = try!(self.buffered());
+		if !buffered.is_empty() {
+			match self.send(&buffered) {
+				Ok(()) => {
+					debug!("sent {} buffered updates", buffered.len());
+					try!(fs::remove_file(&self.buffer_path));
+				},
+				Err((unsent, e)) => {
+					if unsent.len() < buffered.len() {
+						// keep only what the collector didn't accept
+						try!(fs::remove_file(&self.buffer_path));
+						try!(self.buffer(unsent));
+					}
+					try!(self.buffer(&pending));
+					return Err(e);
+				},
+			}
+		}
+
+		match self.send(&pending) {
+			Ok(()) => Ok(()),
+			Err((unsent, e)) => {
+				try!(self.buffer(unsent));
+				Err(e)
+			},
+		}
+	}
+}
+
+fn run(thread: WorkerSelf<InternalError>, monitor: Arc<Mutex<SystemMonitor>>, config: AgentConfig) -> Result<(), InternalError> {
+	info!("Starting agent for collector {} ...", config.url);
+	let receiver = {
+		try!(try!(monitor.lock()).subscribe())
+	};
+	let sleep_ms = config.interval.num_milliseconds() as u32;
+	let agent = Agent::new(config);
+
+	// The monitor drops updates for listeners which fall behind, so we
+	// move them into an unbounded queue while we're busy sending
+	let (queue_send, queue) = mpsc::channel();
+	let _queue_thread = try!(thread.spawn_anon(move |t| -> Result<(),InternalError> {
+		loop {
+			let (_, update) = try!(receiver.recv());
+			try!(t.tick());
+			try!(queue_send.send(update));
+		}
+	}));
+
+	let mut last_error = None;
+	loop {
+		thread::sleep_ms(sleep_ms);
+		try!(thread.tick());
+
+		let mut pending = Vec::new();
+		loop {
+			match queue.try_recv() {
+				Ok(update) => pending.push(try!(json::encode(&*update))),
+				Err(mpsc::TryRecvError::Empty) => break,
+				Err(mpsc::TryRecvError::Disconnected) => {
+					return Err(InternalError::new("agent queue disconnected".to_string()));
+				},
+			}
+		}
+
+		match agent.flush(pending) {
+			Ok(()) => {
+				if last_error.is_some() {
+					info!("agent reconnected to {}", agent.config.url);
+				}
+				last_error = None;
+			},
+			Err(e) => {
+				let error = format!("{}", e);
+				if last_error.as_ref() != Some(&error) {
+					warn!("agent failed to send updates: {}", error);
+				}
+				last_error = Some(error);
+			},
+		}
+	}
+}
+
+pub fn main(monitor: Arc<Mutex<SystemMonitor>>, config: AgentConfig, parent: &WorkerSelf<InternalError>) -> Result<Worker<InternalError>,io::Error> {
+	parent.spawn("agent".into(), move |t| run(t, monitor, config))
+}
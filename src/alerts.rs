@@ -0,0 +1,272 @@
+// Evaluates the configured alert rules over the stream of updates.
+//
+// Each (rule, source, item) for which a rule's condition holds becomes
+// pending, and fires once the condition has held for the rule's `for`
+// duration. A firing alert is only resolved after the condition has been
+// false for `resolve_after`, so that an item which briefly recovers
+// doesn't raise a new alert each time it fails again. Pending alerts
+// whose condition stops holding are dropped without notice.
+
+use std::collections::{HashMap,VecDeque};
+use std::ops::Deref;
+use std::sync::{Arc};
+use monitor::*;
+use config::{AlertRule,AlertCondition};
+use silences::{Silences,is_silenced};
+
+#[derive(Clone,PartialEq,Eq)]
+enum Phase {
+	Pending,
+	Firing,
+}
+
+struct Instance {
+	source: Arc<Source>,
+	phase: Phase,
+	// when the condition started to hold
+	since: Time,
+	active: bool,
+	// when `active` last changed
+	changed: Time,
+	// describes the most recent observation which satisfied the condition
+	detail: String,
+	attrs: Arc<Attributes>,
+}
+
+// (rule index, source id, item id). The item id is empty for
+// rules which apply to a source as a whole.
+type Key = (usize, String, String);
+
+struct EventWindow {
+	source: Arc<Source>,
+	times: VecDeque<Time>,
+	last_message: Option<String>,
+}
+
+pub struct AlertEngine {
+	rules: Arc<Vec<AlertRule>>,
+	instances: HashMap<Key, Instance>,
+	// recent matching events, for `Events` rules
+	events: HashMap<(usize, String), EventWindow>,
+	silences: Silences,
+}
+
+fn matches(pattern: &Option<::glob::Pattern>, s: &str) -> bool {
+	pattern.as_ref().map(|p| p.matches(s)).unwrap_or(true)
+}
+
+fn metric_value(value: &ComputedMetricValue) -> f64 {
+	match *value {
+		ComputedMetricValue::Int(n) => n as f64,
+		ComputedMetricValue::Float(n) => n,
+		ComputedMetricValue::Duration(Duration(d)) => d.num_milliseconds() as f64,
+	}
+}
+
+fn alert_update(rule: &AlertRule, key: &Key, instance: &Instance, state: AlertState, time: &Time) -> Arc<Update> {
+	let (_, _, ref item) = *key;
+	let message = match state {
+		AlertState::Firing => format!("{}: {}", rule.id, instance.detail),
+		AlertState::Resolved => format!("{}: resolved", rule.id),
+	};
+	Arc::new(Update {
+		source: instance.source.clone(),
+		scope: UpdateScope::Partial,
+		time: time.clone(),
+		data: Data::Alert(Alert {
+			id: rule.id.clone(),
+			item: if item.is_empty() { None } else { Some(item.clone()) },
+			state: state,
+			severity: rule.severity.clone(),
+			message: message,
+			since: instance.since.clone(),
+			attrs: instance.attrs.clone(),
+		}),
+	})
+}
+
+impl AlertEngine {
+	pub fn new(rules: Vec<AlertRule>, silences: Silences) -> AlertEngine {
+		AlertEngine {
+			rules: Arc::new(rules),
+			silences: silences,
+			instances: HashMap::new(),
+			events: HashMap::new(),
+		}
+	}
+
+	fn observe(&mut self, key: Key, source: &Arc<Source>, active: bool, detail: String, attrs: Arc<Attributes>, time: &Time) {
+		if !active && !self.instances.contains_key(&key) {
+			return;
+		}
+		let instance = self.instances.entry(key).or_insert_with(|| Instance {
+			source: source.clone(),
+			phase: Phase::Pending,
+			since: time.clone(),
+			active: active,
+			changed: time.clone(),
+			detail: String::new(),
+			attrs: Arc::new(HashMap::new()),
+		});
+		if instance.active != active {
+			instance.active = active;
+			instance.changed = time.clone();
+		}
+		if active {
+			instance.detail = detail;
+			instance.attrs = attrs;
+		}
+	}
+
+	fn observe_state(&mut self, idx: usize, update: &Update, state: &State, items: &HashMap<String, Status>, time: &Time) {
+		let rules = self.rules.clone();
+		let rule = &rules[idx];
+		for (id, status) in items.iter() {
+			// silenced items are left as they are until the silence ends
+			if !matches(&rule.item, id) || is_silenced(&status.attrs) {
+				continue;
+			}
+			let key = (idx, update.source.id.clone(), id.clone());
+			// a flapping item still satisfies rules for its underlying state
+			let active = status.state == *state || status.actual_state() == *state;
+			let detail = format!("{} is {:?}", id, status.state);
+			self.observe(key, &update.source, active, detail, status.attrs.clone(), time);
+		}
+
+		// items which have disappeared no longer satisfy the condition
+		let gone: Vec<Key> = self.instances.keys()
+			.filter(|&&(i, ref source, ref id)| i == idx && *source == update.source.id && !items.contains_key(id))
+			.cloned()
+			.collect();
+		for key in gone {
+			self.observe(key, &update.source, false, String::new(), Arc::new(HashMap::new()), time);
+		}
+	}
+
+	fn observe_event(&mut self, idx: usize, update: &Update, level: &Severity, event: &Event, time: &Time) {
+		let rules = self.rules.clone();
+		let rule = &rules[idx];
+		let severe = event.severity.as_ref().map(|s| s >= level).unwrap_or(false);
+		let id_matches = match event.id {
+			Some(ref id) => matches(&rule.item, id),
+			None => rule.item.is_none(),
+		};
+		if !(severe && id_matches) || is_silenced(&event.attrs) {
+			return;
+		}
+		let window = self.events.entry((idx, update.source.id.clone())).or_insert_with(|| EventWindow {
+			source: update.source.clone(),
+			times: VecDeque::new(),
+			last_message: None,
+		});
+		window.times.push_back(time.clone());
+		window.last_message = event.message.clone();
+	}
+
+	fn observe_metrics(&mut self, idx: usize, update: &Update, above: Option<f64>, below: Option<f64>, metrics: &Metrics, time: &Time) {
+		let rules = self.rules.clone();
+		let rule = &rules[idx];
+		for metric in metrics.values() {
+			// metrics can't be flagged, so silences are checked here
+			if !matches(&rule.item, &metric.id) || self.silences.is_silenced(update.source.id.deref(), &metric.id) {
+				continue;
+			}
+			let value = metric_value(&metric.value);
+			let active = above.map(|a| value > a).unwrap_or(false)
+				|| below.map(|b| value < b).unwrap_or(false);
+			let key = (idx, update.source.id.clone(), metric.id.clone());
+			let detail = format!("{} is {}", metric.id, value);
+			self.observe(key, &update.source, active, detail, Arc::new(HashMap::new()), time);
+		}
+	}
+
+	// expires events which have left their rule's window, and
+	// updates the corresponding instances
+	fn count_events(&mut self, time: &Time) {
+		let rules = self.rules.clone();
+		let mut observations = Vec::new();
+		for (&(idx, ref source_id), window) in self.events.iter_mut() {
+			let (count, duration) = match rules[idx].condition {
+				AlertCondition::Events { count, window: duration, .. } => (count, duration),
+				_ => continue,
+			};
+			while window.times.front().map(|t| time.duration_since(t) > duration).unwrap_or(false) {
+				let _: Option<Time> = window.times.pop_front();
+			}
+			let mut detail = format!("{} events within {}s", window.times.len(), duration.num_seconds());
+			match window.last_message {
+				Some(ref message) => detail.push_str(&format!(", most recently: {}", message)),
+				None => (),
+			}
+			observations.push(((idx, source_id.clone(), String::new()), window.source.clone(), window.times.len() >= count, detail));
+		}
+		let expired: Vec<(usize, String)> = self.events.iter()
+			.filter(|&(_, window)| window.times.is_empty())
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in expired {
+			self.events.remove(&key);
+		}
+		for (key, source, active, detail) in observations {
+			self.observe(key, &source, active, detail, Arc::new(HashMap::new()), time);
+		}
+	}
+
+	fn evaluate(&mut self, time: &Time) -> Vec<Arc<Update>> {
+		self.count_events(time);
+		let mut rv = Vec::new();
+		let mut finished = Vec::new();
+		for (key, instance) in self.instances.iter_mut() {
+			let rule = &self.rules[key.0];
+			match (instance.phase.clone(), instance.active) {
+				(Phase::Pending, true) => {
+					if time.duration_since(&instance.since) >= rule.pending {
+						instance.phase = Phase::Firing;
+						rv.push(alert_update(rule, key, instance, AlertState::Firing, time));
+					}
+				},
+				(Phase::Pending, false) => finished.push(key.clone()),
+				(Phase::Firing, true) => (),
+				(Phase::Firing, false) => {
+					if time.duration_since(&instance.changed) >= rule.resolve_after {
+						rv.push(alert_update(rule, key, instance, AlertState::Resolved, time));
+						finished.push(key.clone());
+					}
+				},
+			}
+		}
+		for key in finished {
+			self.instances.remove(&key);
+		}
+		rv
+	}
+
+	// Returns any alerts which have fired or been resolved as a result of `update`
+	pub fn process(&mut self, update: &Arc<Update>) -> Vec<Arc<Update>> {
+		// rules are evaluated against the local clock, since
+		// remote sources may not agree with it
+		let now = Time::now();
+		let rules = self.rules.clone();
+		for (idx, rule) in rules.iter().enumerate() {
+			if !matches(&rule.source, &update.source.id) {
+				continue;
+			}
+			match (&rule.condition, &update.data) {
+				(&AlertCondition::State(ref state), &Data::State(ref items)) =>
+					self.observe_state(idx, update, state, items, &now),
+				(&AlertCondition::Events { ref level, .. }, &Data::Event(ref event)) =>
+					self.observe_event(idx, update, level, event, &now),
+				(&AlertCondition::Metric { above, below }, &Data::Metrics(ref metrics)) =>
+					self.observe_metrics(idx, update, above, below, metrics, &now),
+				_ => (),
+			}
+		}
+		self.evaluate(&now)
+	}
+
+	// Pending alerts fire (and firing alerts resolve) after some time
+	// has passed, which may happen without any new updates
+	pub fn tick(&mut self) -> Vec<Arc<Update>> {
+		self.evaluate(&Time::now())
+	}
+}
@@ -0,0 +1,501 @@
+// JSON endpoints, served alongside the event stream
+
+use std::collections::{HashMap,BTreeMap};
+use std::io::{Read};
+use std::ops::Deref;
+use std::sync::mpsc::{SyncSender,TrySendError};
+use std::sync::{Arc,Mutex};
+use rustc_serialize::json;
+use rustc_serialize::json::{Json};
+use hyper::server::{Request,Response};
+use hyper::net::Fresh;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::status::StatusCode;
+use hyper::header::{ContentType,AccessControlAllowOrigin};
+use monitor::*;
+use system_monitor::SystemMonitor;
+use config::{ApiConfig,CollectorConfig,IngestSource,SilenceRequest,AckRequest};
+use decode::{decode_update,decode_event,decode_state,decode_metric};
+use metrics::Aggregator;
+use silences::Silences;
+use acks::Acks;
+use problems::Problems;
+use store::{Store,HistoryQuery,DEFAULT_HISTORY_LIMIT};
+use report;
+use report::ReportQuery;
+use transitions::is_transition;
+use util::JsonMap;
+use super::errors::*;
+
+const INGEST_TYPE: &'static str = "ingest";
+// ingest bodies are read before the token can be checked, so they're limited
+const MAX_INGEST_BYTES: u64 = 1024 * 1024;
+
+pub enum Endpoint {
+	Collect,
+	Ingest,
+	ListSilences,
+	CreateSilence,
+	DeleteSilence(String),
+	ListAcks,
+	CreateAck,
+	DeleteAck,
+	Problems,
+	History,
+	Report,
+}
+
+pub struct ApiError {
+	status: StatusCode,
+	message: String,
+}
+
+impl ApiError {
+	pub fn new(status: StatusCode, message: String) -> ApiError {
+		ApiError {
+			status: status,
+			message: message,
+		}
+	}
+
+	pub fn bad_request(message: String) -> ApiError {
+		ApiError::new(StatusCode::BadRequest, message)
+	}
+
+	pub fn not_found() -> ApiError {
+		ApiError::new(StatusCode::NotFound, "Not found".to_string())
+	}
+}
+
+impl From<InternalError> for ApiError {
+	fn from(err: InternalError) -> ApiError {
+		ApiError::new(StatusCode::InternalServerError, err.reason)
+	}
+}
+
+// the request path, without any query string
+pub fn request_path(request: &Request) -> String {
+	match request.uri {
+		RequestUri::AbsolutePath(ref path) => path.splitn(2, '?').next().unwrap_or("").to_string(),
+		_ => "".to_string(),
+	}
+}
+
+pub fn route(method: &Method, path: &str) -> Option<Endpoint> {
+	match (method, path) {
+		(&Method::Post, "/collect") => Some(Endpoint::Collect),
+		(&Method::Post, "/ingest") => Some(Endpoint::Ingest),
+		(&Method::Get, "/silences") => Some(Endpoint::ListSilences),
+		(&Method::Post, "/silences") => Some(Endpoint::CreateSilence),
+		(&Method::Delete, path) if path.starts_with("/silences/") =>
+			Some(Endpoint::DeleteSilence(path["/silences/".len()..].to_string())),
+		(&Method::Get, "/acks") => Some(Endpoint::ListAcks),
+		(&Method::Post, "/acks") => Some(Endpoint::CreateAck),
+		(&Method::Delete, "/acks") => Some(Endpoint::DeleteAck),
+		(&Method::Get, "/problems") => Some(Endpoint::Problems),
+		(&Method::Get, "/history") => Some(Endpoint::History),
+		(&Method::Get, "/report") => Some(Endpoint::Report),
+		_ => None,
+	}
+}
+
+fn percent_decode(s: &str) -> String {
+	let bytes = s.as_bytes();
+	let mut rv = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'+' => rv.push(b' '),
+			b'%' if i + 2 < bytes.len() => {
+				let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+					.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+				match hex {
+					Some(b) => { rv.push(b); i += 2; },
+					None => rv.push(b'%'),
+				}
+			},
+			b => rv.push(b),
+		}
+		i += 1;
+	}
+	String::from_utf8_lossy(&rv).into_owned()
+}
+
+// the (decoded) query string parameters of a request
+pub fn query_params(request: &Request) -> HashMap<String, String> {
+	let query = match request.uri {
+		RequestUri::AbsolutePath(ref path) => path.splitn(2, '?').nth(1).unwrap_or("").to_string(),
+		_ => "".to_string(),
+	};
+	query.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+		let mut parts = pair.splitn(2, '=');
+		let key = percent_decode(parts.next().unwrap_or(""));
+		let value = percent_decode(parts.next().unwrap_or(""));
+		(key, value)
+	}).collect()
+}
+
+pub fn read_json(request: &mut Request) -> Result<Json, ApiError> {
+	read_json_max(request, ::std::u64::MAX)
+}
+
+fn read_json_max(request: &mut Request, max_bytes: u64) -> Result<Json, ApiError> {
+	let mut body = String::new();
+	try!(request.by_ref().take(max_bytes.saturating_add(1)).read_to_string(&mut body).map_err(|e| ApiError::bad_request(format!("{}", e))));
+	if body.len() as u64 > max_bytes {
+		return Err(ApiError::bad_request(format!("Request body is larger than {} bytes", max_bytes)));
+	}
+	Json::from_str(body.deref()).map_err(|e| ApiError::bad_request(format!("Invalid JSON: {}", e)))
+}
+
+pub fn bearer_token(request: &Request) -> Option<String> {
+	request.headers.get_raw("Authorization")
+		.and_then(|values| values.first())
+		.and_then(|value| String::from_utf8(value.clone()).ok())
+		.and_then(|value| {
+			let value = value.trim();
+			if value.starts_with("Bearer ") {
+				Some(value["Bearer ".len()..].trim().to_string())
+			} else {
+				None
+			}
+		})
+}
+
+pub fn check_token(request: &Request, expected: &Option<String>) -> Result<(), ApiError> {
+	match *expected {
+		None => Ok(()),
+		Some(ref expected) => {
+			let valid = bearer_token(request)
+				.map(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+				.unwrap_or(false);
+			if valid {
+				Ok(())
+			} else {
+				Err(ApiError::new(StatusCode::Unauthorized, "Invalid token".to_string()))
+			}
+		}
+	}
+}
+
+// Compares without returning early, so that the time taken doesn't reveal how
+// much of a token was right (only its length)
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn respond(mut response: Response<Fresh>, status: StatusCode, body: Json) {
+	*response.status_mut() = status;
+	{
+		let headers = response.headers_mut();
+		headers.set(AccessControlAllowOrigin::Any);
+		headers.set(ContentType::json());
+	}
+	let body = format!("{}", body);
+	match response.send(body.as_bytes()) {
+		Ok(()) => (),
+		Err(e) => debug!("Unable to send response: {}", e),
+	}
+}
+
+struct Ingest {
+	token: String,
+	source: Arc<Source>,
+	metrics: Mutex<Aggregator>,
+}
+
+pub struct Api {
+	emitter: SyncSender<Arc<Update>>,
+	token: Option<String>,
+	silences: Silences,
+	acks: Acks,
+	problems: Problems,
+	store: Option<Store>,
+	collector: Option<CollectorConfig>,
+	ingest: HashMap<String, Ingest>,
+	listen: String,
+}
+
+impl Api {
+	pub fn new(
+		monitor: &Arc<Mutex<SystemMonitor>>,
+		config: ApiConfig,
+		collector: Option<CollectorConfig>,
+		ingest: Vec<IngestSource>)
+		-> Result<Api, InternalError>
+	{
+		let (emitter, silences, acks, problems, store) = {
+			let monitor = try!(monitor.lock());
+			(monitor.emitter(), monitor.silences(), monitor.acks(), monitor.problems(), monitor.store())
+		};
+		let ingest = ingest.into_iter().map(|conf| {
+			(conf.id.clone(), Ingest {
+				token: conf.token,
+				source: Arc::new(Source::new(conf.id, INGEST_TYPE)),
+				metrics: Mutex::new(Aggregator::new()),
+			})
+		}).collect();
+		Ok(Api {
+			emitter: emitter,
+			token: config.token,
+			silences: silences,
+			acks: acks,
+			problems: problems,
+			store: store,
+			collector: collector,
+			ingest: ingest,
+			listen: config.listen,
+		})
+	}
+
+	// the address to serve on
+	pub fn listen(&self) -> &str {
+		self.listen.deref()
+	}
+
+	pub fn handle<'a, 'k>(&'a self, endpoint: Endpoint, mut request: Request<'a, 'k>, response: Response<'a, Fresh>) {
+		let result = match endpoint {
+			Endpoint::Collect => self.collect(&mut request),
+			Endpoint::Ingest => self.ingest(&mut request),
+			Endpoint::ListSilences => Ok(self.silences.to_json()),
+			Endpoint::CreateSilence => self.create_silence(&mut request),
+			Endpoint::DeleteSilence(ref id) => self.delete_silence(&request, id),
+			Endpoint::ListAcks => Ok(self.acks.to_json()),
+			Endpoint::CreateAck => self.create_ack(&mut request),
+			Endpoint::DeleteAck => self.delete_ack(&mut request),
+			Endpoint::Problems => Ok(self.problems.to_json()),
+			Endpoint::History => self.history(&request),
+			Endpoint::Report => self.report(&request),
+		};
+		match result {
+			Ok(body) => respond(response, StatusCode::Ok, body),
+			Err(e) => {
+				debug!("API request failed: {}", e.message);
+				let mut body = BTreeMap::new();
+				body.insert("error".to_string(), Json::String(e.message));
+				respond(response, e.status, Json::Object(body))
+			},
+		}
+	}
+
+	pub fn inject(&self, update: Update) -> Result<(), ApiError> {
+		match self.emitter.try_send(Arc::new(update)) {
+			Ok(()) => Ok(()),
+			Err(TrySendError::Full(_)) => Err(ApiError::new(StatusCode::ServiceUnavailable, "Event buffer full".to_string())),
+			Err(TrySendError::Disconnected(_)) => Err(ApiError::new(StatusCode::InternalServerError, "Monitor not running".to_string())),
+		}
+	}
+
+	// Accepts a batch of updates from an agent:
+	// {"host": "web1", "updates": [ ... ]}
+	fn collect(&self, request: &mut Request) -> Result<Json, ApiError> {
+		let config = try!(self.collector.as_ref().ok_or_else(ApiError::not_found));
+		try!(check_token(request, &config.token));
+		let mut body = match try!(read_json(request)) {
+			Json::Object(body) => body,
+			_ => return Err(ApiError::bad_request("Expected an object".to_string())),
+		};
+		let host = match body.remove("host") {
+			Some(Json::String(ref host)) if !host.is_empty() && !host.contains('/') => host.clone(),
+			_ => return Err(ApiError::bad_request("Invalid or missing `host`".to_string())),
+		};
+		let updates = match body.remove("updates") {
+			Some(Json::Array(updates)) => updates,
+			_ => return Err(ApiError::bad_request("Invalid or missing `updates`".to_string())),
+		};
+
+		let count = updates.len();
+		let mut decoded = Vec::with_capacity(count);
+		for update in updates {
+			let update = try!(decode_update(update, Some(host.deref())).map_err(|e| ApiError::bad_request(e.reason)));
+			// we derive our own transition events from the agent's state
+			if is_transition(&update) {
+				continue;
+			}
+			decoded.push(update);
+		}
+		// batches can be bigger than the monitor's buffer, so this blocks rather
+		// than rejecting part of a batch (which the agent would then resend)
+		for update in decoded {
+			try!(self.emitter.send(Arc::new(update)).map_err(|_|
+				ApiError::new(StatusCode::InternalServerError, "Monitor not running".to_string())));
+		}
+
+		let mut rv = BTreeMap::new();
+		rv.insert("accepted".to_string(), Json::U64(count as u64));
+		Ok(Json::Object(rv))
+	}
+
+	// Accepts data for a configured ingest source, authorized by that source's token:
+	// {"source": "deploy", "event": {"severity": "Notice", "message": "deploy started"}}
+	// {"source": "deploy", "state": {"web": {"state": "Active", "attrs": {}}}}
+	// {"source": "deploy", "metrics": [{"id": "duration", "type": "timespan", "value": 1200}]}
+	fn ingest(&self, request: &mut Request) -> Result<Json, ApiError> {
+		let mut body = match try!(read_json_max(request, MAX_INGEST_BYTES)) {
+			Json::Object(body) => body,
+			_ => return Err(ApiError::bad_request("Expected an object".to_string())),
+		};
+		// Unknown sources get the same response as a bad token, so that source
+		// ids can't be discovered without one
+		let ingest = match body.remove("source") {
+			Some(Json::String(ref id)) => match self.ingest.get(id) {
+				Some(ingest) => ingest,
+				None => return Err(ApiError::new(StatusCode::Unauthorized, "Invalid token".to_string())),
+			},
+			_ => return Err(ApiError::bad_request("Invalid or missing `source`".to_string())),
+		};
+		try!(check_token(request, &Some(ingest.token.clone())));
+
+		let (data, scope) = match (body.remove("event"), body.remove("state"), body.remove("metrics")) {
+			(Some(event), None, None) => {
+				(Data::Event(try!(decode_event(event).map_err(|e| ApiError::bad_request(e.reason)))), UpdateScope::Partial)
+			},
+			(None, Some(state), None) => {
+				(Data::State(try!(decode_state(state).map_err(|e| ApiError::bad_request(e.reason)))), UpdateScope::Snapshot)
+			},
+			(None, None, Some(Json::Array(metrics))) => {
+				let mut aggregator = try!(ingest.metrics.lock().map_err(InternalError::from));
+				// validate everything before adding any of it
+				let mut decoded = Vec::with_capacity(metrics.len());
+				for metric in metrics {
+					decoded.push(try!(decode_metric(metric).map_err(|e| ApiError::bad_request(e.reason))));
+				}
+				for metric in decoded {
+					aggregator.add(metric);
+				}
+				(Data::Metrics(aggregator.flush()), UpdateScope::Partial)
+			},
+			_ => return Err(ApiError::bad_request("Expected exactly one of `event`, `state` or `metrics` (array)".to_string())),
+		};
+		if !body.is_empty() {
+			let keys: Vec<String> = body.keys().cloned().collect();
+			return Err(ApiError::bad_request(format!("Unknown key(s): {}", keys.join(", "))));
+		}
+
+		try!(self.inject(Update {
+			source: ingest.source.clone(),
+			scope: scope,
+			time: Time::now(),
+			data: data,
+		}));
+
+		let mut rv = BTreeMap::new();
+		rv.insert("accepted".to_string(), Json::U64(1));
+		Ok(Json::Object(rv))
+	}
+
+	// Silences an item (or a whole source) for a while:
+	// {"source": "systemd.system", "match": ["nginx.service"], "duration": "2h", "comment": "upgrading"}
+	fn create_silence(&self, request: &mut Request) -> Result<Json, ApiError> {
+		try!(check_token(request, &self.token));
+		let body = try!(read_json(request));
+		let silence = try!(SilenceRequest::parse(body).map_err(|e| ApiError::bad_request(format!("{}", e))));
+		let id = try!(self.silences.add(silence).map_err(ApiError::bad_request));
+		let mut rv = BTreeMap::new();
+		rv.insert("id".to_string(), Json::String(id));
+		Ok(Json::Object(rv))
+	}
+
+	fn delete_silence(&self, request: &Request, id: &str) -> Result<Json, ApiError> {
+		try!(check_token(request, &self.token));
+		if !self.silences.remove(id) {
+			return Err(ApiError::not_found());
+		}
+		let mut rv = BTreeMap::new();
+		rv.insert("deleted".to_string(), Json::String(id.to_string()));
+		Ok(Json::Object(rv))
+	}
+
+	// Acknowledges a failed item, until it recovers or the ack expires:
+	// {"source": "systemd.system", "id": "nginx.service", "comment": "on it", "duration": "4h"}
+	fn create_ack(&self, request: &mut Request) -> Result<Json, ApiError> {
+		try!(check_token(request, &self.token));
+		let body = try!(read_json(request));
+		let ack = try!(AckRequest::parse(body).map_err(|e| ApiError::bad_request(format!("{}", e))));
+		try!(self.acks.add(ack).map_err(ApiError::bad_request));
+		let mut rv = BTreeMap::new();
+		rv.insert("accepted".to_string(), Json::U64(1));
+		Ok(Json::Object(rv))
+	}
+
+	// {"source": "systemd.system", "id": "nginx.service"}
+	fn delete_ack(&self, request: &mut Request) -> Result<Json, ApiError> {
+		try!(check_token(request, &self.token));
+		let mut body = match try!(read_json(request)) {
+			Json::Object(body) => body,
+			_ => return Err(ApiError::bad_request("Expected an object".to_string())),
+		};
+		let (source, id) = match (body.remove("source"), body.remove("id")) {
+			(Some(Json::String(source)), Some(Json::String(id))) => (source, id),
+			_ => return Err(ApiError::bad_request("Invalid or missing `source` or `id`".to_string())),
+		};
+		if !self.acks.remove(source.deref(), id.deref()) {
+			return Err(ApiError::not_found());
+		}
+		let mut rv = BTreeMap::new();
+		rv.insert("deleted".to_string(), Json::U64(1));
+		Ok(Json::Object(rv))
+	}
+
+	// The most recent stored updates (oldest first), optionally filtered:
+	// /history?source=systemd.*&unit=nginx.service&severity=warning&since=1500000000&until=1500086400&limit=100
+	fn history(&self, request: &Request) -> Result<Json, ApiError> {
+		let store = try!(self.store.as_ref().ok_or_else(ApiError::not_found));
+		let query = try!(parse_history_query(query_params(request)));
+		let updates = try!(store.query(&query));
+		let mut rv = Vec::with_capacity(updates.len());
+		for update in updates {
+			let encoded = try!(json::encode(&update).map_err(InternalError::from));
+			rv.push(try!(Json::from_str(encoded.deref()).map_err(|e| InternalError::new(format!("{}", e)))));
+		}
+		Ok(Json::Array(rv))
+	}
+
+	// Availability of each unit over a window (the last 30 days by default):
+	// /report?source=systemd.system&unit=nginx.service&since=2016-05-01&until=2016-06-01
+	fn report(&self, request: &Request) -> Result<Json, ApiError> {
+		let store = try!(self.store.as_ref().ok_or_else(ApiError::not_found));
+		let query = try!(ReportQuery::parse(query_params(request)).map_err(ApiError::bad_request));
+		let reports = try!(report::availability(store, &query));
+		Ok(report::to_json(&reports, &query))
+	}
+}
+
+fn parse_history_query(mut params: HashMap<String, String>) -> Result<HistoryQuery, ApiError> {
+	let int = |key: &str, value: Option<String>| -> Result<Option<i64>, ApiError> {
+		match value {
+			Some(value) => value.parse().map(Some).map_err(|_| ApiError::bad_request(format!("Invalid `{}`: {}", key, value))),
+			None => Ok(None),
+		}
+	};
+	let source = match params.remove("source") {
+		Some(source) => Some(try!(::glob::Pattern::new(source.deref())
+			.map_err(|e| ApiError::bad_request(format!("Invalid `source`: {}", e))))),
+		None => None,
+	};
+	let severity = match params.remove("severity") {
+		Some(severity) => Some(try!(Severity::from_name(severity.deref())
+			.ok_or_else(|| ApiError::bad_request(format!("Invalid `severity`: {}", severity))))),
+		None => None,
+	};
+	let since = try!(int("since", params.remove("since")));
+	let until = try!(int("until", params.remove("until")));
+	let limit = try!(int("limit", params.remove("limit")));
+	let unit = params.remove("unit");
+	if !params.is_empty() {
+		let keys: Vec<String> = params.keys().cloned().collect();
+		return Err(ApiError::bad_request(format!("Unknown parameter(s): {}", keys.join(", "))));
+	}
+	Ok(HistoryQuery {
+		source: source,
+		unit: unit,
+		severity: severity,
+		since: since,
+		until: until,
+		limit: limit.map(|n| ::std::cmp::max(n, 0) as usize).unwrap_or(DEFAULT_HISTORY_LIMIT),
+	})
+}
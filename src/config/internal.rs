@@ -83,6 +83,45 @@ pub fn as_i32(v: Json) -> Result<i32, ConfigError> {
 	}
 }
 
+pub fn as_i64(v: Json) -> Result<i64, ConfigError> {
+	match v {
+		Json::I64(n) => Ok(n),
+		Json::U64(n) => Ok(n as i64),
+		v => Err(type_mismatch(&v, "Integer")),
+	}
+}
+
+pub fn as_f64(v: Json) -> Result<f64, ConfigError> {
+	match v {
+		Json::I64(n) => Ok(n as f64),
+		Json::U64(n) => Ok(n as f64),
+		Json::F64(n) => Ok(n),
+		v => Err(type_mismatch(&v, "Number")),
+	}
+}
+
+pub fn as_duration(v: Json) -> Result<Duration, ConfigError> {
+	let s = try!(as_string(v));
+	let invalid_duration = || ConfigError::new(format!("Invalid duration: {}", s));
+	let suffix_loc = try!(
+		s.find(|c:char| !c.is_numeric())
+		.ok_or_else(&invalid_duration)
+	);
+	let digits = s.index(0..suffix_loc);
+	let suffix = s.index(suffix_loc..);
+	let val = try!(i64::from_str(digits));
+	Ok(match suffix {
+		"ms" => Duration::milliseconds(val),
+		"s" => Duration::seconds(val),
+		"m" => Duration::minutes(val),
+		"h" => Duration::hours(val),
+		"d" => Duration::days(val),
+		_ => {
+			return Err(invalid_duration());
+		}
+	})
+}
+
 pub fn as_object(j:Json) -> Result<JsonMap, ConfigError> {
 	match j {
 		Json::Object(attrs) => Ok(attrs),
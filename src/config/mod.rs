@@ -12,9 +12,10 @@ use std::num::ParseIntError;
 use rustc_serialize::json::Json;
 use rustc_serialize::json;
 use chrono::{Duration};
-use monitor::Severity;
+use monitor::{Severity,State};
 use util::*;
 use regex::Regex;
+use cron::Schedule;
 
 
 #[macro_use]
@@ -57,7 +58,7 @@ pub struct FilterCommon {
 }
 
 impl FilterCommon {
-	fn parse_matcher(matcher: Json) -> Result<Match, ConfigError> {
+	pub fn parse_matcher(matcher: Json) -> Result<Match, ConfigError> {
 		match matcher {
 			Json::String(lit) => {
 				Ok(Match {
@@ -116,9 +117,128 @@ pub struct JournalFilter {
 	pub attr_extend: Option<JsonMap>,
 }
 
+#[derive(Clone,Debug)]
+pub enum UnitExpectation {
+	// anything but active is an outage
+	MustBeActive,
+	// only the result of the last run matters
+	Oneshot,
+	// inactive is fine, but failed isn't
+	IgnoreInactive,
+}
+
+impl UnitExpectation {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			UnitExpectation::MustBeActive => "must_be_active",
+			UnitExpectation::Oneshot => "oneshot",
+			UnitExpectation::IgnoreInactive => "ignore_inactive",
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct UnitRule {
+	pub pattern: ::glob::Pattern,
+	pub expect: UnitExpectation,
+}
+
 pub struct SystemdConfig {
 	pub common: CommonConfig<()>,
 	pub user: Option<bool>,
+	// how long past its next scheduled run a timer may be before it's considered overdue.
+	// Only calendar (`OnCalendar=`) timers are checked: monotonic timers such as
+	// `OnBootSec=` have no realtime schedule, so they're never considered overdue.
+	pub timer_grace: Option<Duration>,
+	// the first rule matching a unit's name applies
+	pub expect: Vec<UnitRule>,
+	pub journal_lines: Option<i32>,
+}
+
+#[derive(Clone)]
+pub enum SocketAddress {
+	Tcp(String),
+	Unix(String),
+}
+
+#[derive(Clone)]
+pub struct SocketTarget {
+	pub id: String,
+	pub address: SocketAddress,
+	pub send: Option<String>,
+	pub expect: Option<Regex>,
+}
+
+pub struct SocketConfig {
+	pub common: CommonConfig<()>,
+	pub targets: Vec<SocketTarget>,
+	pub timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct FileTarget {
+	pub pattern: String,
+	pub max_age: Option<Duration>,
+}
+
+pub struct FileConfig {
+	pub common: CommonConfig<()>,
+	pub paths: Vec<FileTarget>,
+}
+
+#[derive(Clone)]
+pub struct LogfileConfig {
+	pub common: CommonConfig<JournalFilter>,
+	pub paths: Vec<String>,
+	pub pattern: Option<Regex>,
+	pub interval: Duration,
+}
+
+#[derive(Clone)]
+pub struct SyslogConfig {
+	pub common: CommonConfig<JournalFilter>,
+	pub udp: Option<String>,
+	pub tcp: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct PrometheusEndpoint {
+	pub id: String,
+	pub url: String,
+}
+
+#[derive(Clone)]
+pub struct PrometheusRule {
+	// all matchers must match. Matchers with an `attr` are tested against
+	// the sample's label of that name, otherwise they test the metric name.
+	pub matchers: Vec<Match>,
+	pub equals: Option<f64>,
+	pub above: Option<f64>,
+	pub below: Option<f64>,
+	pub state: State,
+}
+
+pub struct PrometheusConfig {
+	pub common: CommonConfig<()>,
+	pub endpoints: Vec<PrometheusEndpoint>,
+	pub quantiles: Vec<f64>,
+	pub rules: Vec<PrometheusRule>,
+	pub timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct RemoteConfig {
+	pub common: CommonConfig<()>,
+	pub url: String,
+	pub host: String,
+	pub timeout: Duration,
+	pub max_backoff: Duration,
+}
+
+pub struct StatsdConfig {
+	pub common: CommonConfig<()>,
+	pub address: String,
+	pub interval: Duration,
 }
 
 trait ModuleConfig {
@@ -149,6 +269,13 @@ impl ModuleConfig for JournalConfig {
 		config: &mut ConfigMap)
 		-> Result<Self::Filter, ConfigError>
 	{
+		JournalFilter::parse(common, config)
+	}
+}
+
+impl JournalFilter {
+	// shared by all modules which produce journal-like events
+	fn parse(common: FilterCommon, config: &mut ConfigMap) -> Result<JournalFilter, ConfigError> {
 		let level = try!(config.descend_json("level", |l|
 			l.map_m(|l| as_string(l).and_then(as_severity))));
 
@@ -163,6 +290,34 @@ impl ModuleConfig for JournalConfig {
 	}
 }
 
+// For sizes and counts, which must be greater than zero
+fn positive(n: i64) -> Result<u64, ConfigError> {
+	if n > 0 {
+		Ok(n as u64)
+	} else {
+		Err(ConfigError::new(format!("Expected a positive number, got {}", n)))
+	}
+}
+
+// For timeouts, where zero isn't meaningful
+fn positive_duration(d: Duration) -> Result<Duration, ConfigError> {
+	if d > Duration::zero() {
+		Ok(d)
+	} else {
+		Err(ConfigError::new("Expected a positive duration".to_string()))
+	}
+}
+
+// The values of the freedesktop notification `urgency` hint
+fn as_urgency(s: String) -> Result<u8, ConfigError> {
+	match s.deref() {
+		"low"      => Ok(0),
+		"normal"   => Ok(1),
+		"critical" => Ok(2),
+		_ => Err(ConfigError::new(format!("Unknown urgency: {}", s))),
+	}
+}
+
 fn as_severity(s:String) -> Result<Severity, ConfigError> {
 	match s.deref() {
 		"Emergency" => Ok(Severity::Emergency),
@@ -177,6 +332,37 @@ fn as_severity(s:String) -> Result<Severity, ConfigError> {
 	}
 }
 
+fn as_glob(s:String) -> Result<::glob::Pattern, ConfigError> {
+	::glob::Pattern::new(s.deref()).map_err(ConfigError::from)
+}
+
+fn as_state(s:String) -> Result<State, ConfigError> {
+	State::from_name(s.deref()).ok_or_else(||
+		ConfigError::new(format!("Unknown state: {}", s))
+	)
+}
+
+impl SystemdConfig {
+	fn parse_rule(rule: Json) -> Result<UnitRule, ConfigError> {
+		let rule = try!(as_object(rule));
+		ConfigCheck::consume_new(rule, |rule| {
+			let pattern = try!(rule.descend_json("unit", |u| mandatory(u).and_then(as_string)));
+			let expect = try!(rule.descend_json("expect", |e| mandatory(e).and_then(as_string)));
+			let expect = try!([
+				UnitExpectation::MustBeActive,
+				UnitExpectation::Oneshot,
+				UnitExpectation::IgnoreInactive,
+			].iter().find(|e| e.name() == expect).cloned().ok_or_else(||
+				ConfigError::new(format!("Unknown expectation: {}", expect))
+			));
+			Ok(UnitRule {
+				pattern: try!(::glob::Pattern::new(pattern.deref())),
+				expect: expect,
+			})
+		})
+	}
+}
+
 impl ModuleConfig for SystemdConfig {
 	type Filter = ();
 	fn parse(
@@ -187,9 +373,325 @@ impl ModuleConfig for SystemdConfig {
 		let user = try!(config.descend_json("user",
 			|u| u.map_m(as_boolean)
 		));
+		let timer_grace = try!(config.descend_json("timer_grace",
+			|g| g.map_m(as_duration)
+		));
+		let expect = try!(config.descend_json("expect", |e| match e {
+			Some(e) => e.descend_map_json(Self::parse_rule),
+			None => Ok(Vec::new()),
+		}));
+		let journal_lines = try!(config.descend_json("journal_lines",
+			|l| l.map_m(as_i32)
+		));
 		Ok(SystemdConfig {
 			common: common,
 			user: user,
+			timer_grace: timer_grace,
+			expect: expect,
+			journal_lines: journal_lines,
+		})
+	}
+
+	fn parse_filter(
+		_common: FilterCommon,
+		_config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		Ok(())
+	}
+}
+
+impl SocketConfig {
+	fn default_timeout() -> Duration { Duration::seconds(5) }
+
+	fn parse_target(id: String, conf: Json) -> Result<SocketTarget, ConfigError> {
+		let conf = try!(as_object(conf));
+		ConfigCheck::consume_new(conf, |conf| {
+			let tcp = try!(conf.descend_json("tcp", as_string_opt));
+			let unix = try!(conf.descend_json("unix", as_string_opt));
+			let address = match (tcp, unix) {
+				(Some(addr), None) => SocketAddress::Tcp(addr),
+				(None, Some(path)) => SocketAddress::Unix(path),
+				(None, None) => return Err(ConfigError::new("one of `tcp` or `unix` is required".to_string())),
+				(Some(_), Some(_)) => return Err(ConfigError::new("only one of `tcp` or `unix` may be given".to_string())),
+			};
+			let send = try!(conf.descend_json("send", as_string_opt));
+			let expect = try!(conf.descend_json("expect", |e|
+				e.map_m(|e| as_string(e).and_then(|e| Regex::new(e.deref()).map_err(ConfigError::from)))
+			));
+			Ok(SocketTarget {
+				id: id,
+				address: address,
+				send: send,
+				expect: expect,
+			})
+		})
+	}
+}
+
+impl ModuleConfig for SocketConfig {
+	type Filter = ();
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let timeout = try!(config.descend_json("timeout",
+			|t| t.map_m(|t| as_duration(t).and_then(positive_duration))
+		));
+		let targets = try!(config.descend_json("targets", |targets| {
+			let targets = try!(mandatory(targets).and_then(as_object));
+			let mut rv = Vec::new();
+			for (id, target) in targets {
+				rv.push(try!(annotate_error!(id, Self::parse_target(id.clone(), target))));
+			}
+			Ok(rv)
+		}));
+		Ok(SocketConfig {
+			common: common,
+			targets: targets,
+			timeout: timeout.unwrap_or_else(Self::default_timeout),
+		})
+	}
+
+	fn parse_filter(
+		_common: FilterCommon,
+		_config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		Ok(())
+	}
+}
+
+impl FileConfig {
+	fn parse_target(conf: Json, default_max_age: Option<Duration>) -> Result<FileTarget, ConfigError> {
+		let (pattern, max_age) = match conf {
+			Json::String(pattern) => (pattern, None),
+			Json::Object(conf) => {
+				try!(ConfigCheck::consume_new(conf, |conf| {
+					let pattern = try!(conf.descend_json("path", |p| mandatory(p).and_then(as_string)));
+					let max_age = try!(conf.descend_json("max_age", |a| a.map_m(as_duration)));
+					Ok((pattern, max_age))
+				}))
+			},
+			ref other => return Err(type_mismatch(other, "String or Object")),
+		};
+
+		// validate the pattern upfront, so we don't find out at poll time
+		let _: ::glob::Pattern = try!(::glob::Pattern::new(pattern.deref()));
+		Ok(FileTarget {
+			pattern: pattern,
+			max_age: max_age.or(default_max_age),
+		})
+	}
+}
+
+impl ModuleConfig for FileConfig {
+	type Filter = ();
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let max_age = try!(config.descend_json("max_age",
+			|a| a.map_m(as_duration)
+		));
+		let paths = try!(config.descend_json("paths", |paths|
+			mandatory(paths).and_then(|paths|
+				paths.descend_map_json(|p| Self::parse_target(p, max_age))
+			)
+		));
+		Ok(FileConfig {
+			common: common,
+			paths: paths,
+		})
+	}
+
+	fn parse_filter(
+		_common: FilterCommon,
+		_config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		Ok(())
+	}
+}
+
+impl ModuleConfig for LogfileConfig {
+	type Filter = JournalFilter;
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let paths = try!(config.descend_json("paths", |paths|
+			mandatory(paths).and_then(|paths| paths.descend_map_json(as_string))
+		));
+		let pattern = try!(config.descend_json("pattern", |p|
+			p.map_m(|p| as_string(p).and_then(|p| Regex::new(p.deref()).map_err(ConfigError::from)))
+		));
+		let interval = try!(config.descend_json("interval",
+			|i| i.map_m(as_duration)
+		));
+		Ok(LogfileConfig {
+			common: common,
+			paths: paths,
+			pattern: pattern,
+			interval: interval.unwrap_or_else(|| Duration::seconds(1)),
+		})
+	}
+
+	fn parse_filter(
+		common: FilterCommon,
+		config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		JournalFilter::parse(common, config)
+	}
+}
+
+impl ModuleConfig for SyslogConfig {
+	type Filter = JournalFilter;
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let udp = try!(config.descend_json("udp", as_string_opt));
+		let tcp = try!(config.descend_json("tcp", as_string_opt));
+		let udp = match (&udp, &tcp) {
+			(&None, &None) => Some("0.0.0.0:514".to_string()),
+			_ => udp,
+		};
+		Ok(SyslogConfig {
+			common: common,
+			udp: udp,
+			tcp: tcp,
+		})
+	}
+
+	fn parse_filter(
+		common: FilterCommon,
+		config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		JournalFilter::parse(common, config)
+	}
+}
+
+impl PrometheusConfig {
+	fn default_quantiles() -> Vec<f64> { vec!(0.5, 0.9, 0.99) }
+
+	fn parse_rule(rule: Json) -> Result<PrometheusRule, ConfigError> {
+		let rule = try!(as_object(rule));
+		ConfigCheck::consume_new(rule, |rule| {
+			let matchers = try!(rule.descend_json("match", |m|
+				mandatory(m).and_then(|m| m.descend_map_json(FilterCommon::parse_matcher))
+			));
+			let equals = try!(rule.descend_json("equals", |v| v.map_m(as_f64)));
+			let above = try!(rule.descend_json("above", |v| v.map_m(as_f64)));
+			let below = try!(rule.descend_json("below", |v| v.map_m(as_f64)));
+			if equals.is_none() && above.is_none() && below.is_none() {
+				return Err(ConfigError::new("one of `equals`, `above` or `below` is required".to_string()));
+			}
+			let state = try!(rule.descend_json("state", |s|
+				s.map_m(|s| as_string(s).and_then(as_state))
+			));
+			Ok(PrometheusRule {
+				matchers: matchers,
+				equals: equals,
+				above: above,
+				below: below,
+				state: state.unwrap_or(State::Error),
+			})
+		})
+	}
+}
+
+impl ModuleConfig for PrometheusConfig {
+	type Filter = ();
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let endpoints = try!(config.descend_json("endpoints", |endpoints| {
+			let endpoints = try!(mandatory(endpoints).and_then(as_object));
+			let mut rv = Vec::new();
+			for (id, url) in endpoints {
+				let url = try!(annotate_error!(id, as_string(url)));
+				rv.push(PrometheusEndpoint { id: id, url: url });
+			}
+			Ok(rv)
+		}));
+		let quantiles = try!(config.descend_json("quantiles", |q|
+			q.map_m(|q| q.descend_map_json(as_f64))
+		));
+		let rules = try!(config.descend_json("rules", |r|
+			r.map_m(|r| r.descend_map_json(Self::parse_rule))
+		));
+		let timeout = try!(config.descend_json("timeout", |t| t.map_m(as_duration)));
+		Ok(PrometheusConfig {
+			common: common,
+			endpoints: endpoints,
+			quantiles: quantiles.unwrap_or_else(Self::default_quantiles),
+			rules: rules.unwrap_or_else(Vec::new),
+			timeout: timeout.unwrap_or_else(|| Duration::seconds(10)),
+		})
+	}
+
+	fn parse_filter(
+		_common: FilterCommon,
+		_config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		Ok(())
+	}
+}
+
+impl ModuleConfig for RemoteConfig {
+	type Filter = ();
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let url = try!(config.descend_json("url", |u| mandatory(u).and_then(as_string)));
+		let host = try!(config.descend_json("host", as_string_opt));
+		let timeout = try!(config.descend_json("timeout", |t| t.map_m(as_duration)));
+		let max_backoff = try!(config.descend_json("max_backoff", |t| t.map_m(as_duration)));
+		Ok(RemoteConfig {
+			host: host.unwrap_or_else(|| common.id.clone()),
+			common: common,
+			url: url,
+			// the remote server sends keepalives every 10s
+			timeout: timeout.unwrap_or_else(|| Duration::seconds(30)),
+			max_backoff: max_backoff.unwrap_or_else(|| Duration::minutes(5)),
+		})
+	}
+
+	fn parse_filter(
+		_common: FilterCommon,
+		_config: &mut ConfigMap)
+		-> Result<Self::Filter, ConfigError>
+	{
+		Ok(())
+	}
+}
+
+impl ModuleConfig for StatsdConfig {
+	type Filter = ();
+	fn parse(
+		common: CommonConfig<Self::Filter>,
+		mut config: Option<&mut ConfigMap>)
+		-> Result<Self, ConfigError>
+	{
+		let address = try!(config.descend_json("address", as_string_opt));
+		let interval = try!(config.descend_json("interval", |i| i.map_m(as_duration)));
+		Ok(StatsdConfig {
+			common: common,
+			address: address.unwrap_or_else(|| "0.0.0.0:8125".to_string()),
+			// statsd's own default flush interval
+			interval: interval.unwrap_or_else(|| Duration::seconds(10)),
 		})
 	}
 
@@ -222,6 +724,27 @@ fn parse_source_config(id: &String, conf: Json) -> Result<SourceConfig, ConfigEr
 			"journal" => {
 				SourceConfig::Journal(try!(parse_module(id, conf)))
 			},
+			"socket" => {
+				SourceConfig::Socket(try!(parse_module(id, conf)))
+			},
+			"file" => {
+				SourceConfig::File(try!(parse_module(id, conf)))
+			},
+			"logfile" => {
+				SourceConfig::Logfile(try!(parse_module(id, conf)))
+			},
+			"syslog" => {
+				SourceConfig::Syslog(try!(parse_module(id, conf)))
+			},
+			"prometheus" => {
+				SourceConfig::Prometheus(try!(parse_module(id, conf)))
+			},
+			"remote" => {
+				SourceConfig::Remote(try!(parse_module(id, conf)))
+			},
+			"statsd" => {
+				SourceConfig::Statsd(try!(parse_module(id, conf)))
+			},
 			other => {
 				return Err(ConfigError::new(format!("Unknown module: {}", other)));
 			}
@@ -271,6 +794,13 @@ fn parse_module<T:ModuleConfig>(id: String, attrs: Option<&mut ConfigMap>) -> Re
 pub enum SourceConfig {
 	Systemd(SystemdConfig),
 	Journal(JournalConfig),
+	Socket(SocketConfig),
+	File(FileConfig),
+	Logfile(LogfileConfig),
+	Syslog(SyslogConfig),
+	Prometheus(PrometheusConfig),
+	Remote(RemoteConfig),
+	Statsd(StatsdConfig),
 }
 
 pub struct PollConfig {
@@ -285,41 +815,616 @@ impl PollConfig {
 	fn default_interval() -> Duration { Duration::seconds(15) }
 
 	fn parse(c:&mut ConfigMap) -> Result<PollConfig, ConfigError> {
-		let invalid_duration = |d:&String|
-			ConfigError::new(format!("Invalid duration: {}", d));
-
-		let duration = try!(c.descend_json("interval", |s | match s {
-			Some(s) => {
-				let s = try!(as_string(s));
-				let suffix_loc = try!(
-					s.find(|c:char| !c.is_numeric())
-					.ok_or_else(||invalid_duration(&s))
-				);
-				let digits = s.index(0..suffix_loc);
-				let suffix = s.index(suffix_loc..);
-				let val = try!(i64::from_str(digits));
-				Ok(match suffix {
-					"ms" => Duration::milliseconds(val),
-					"s" => Duration::seconds(val),
-					"m" => Duration::minutes(val),
-					"h" => Duration::hours(val),
-					"d" => Duration::days(val),
-					_ => {
-						return Err(invalid_duration(&s));
-					}
-				})
-			},
-			None => Ok(Self::default_interval()),
-		}));
+		let duration = try!(c.descend_json("interval", |s|
+			s.map_m(as_duration).map(|d| d.unwrap_or_else(Self::default_interval))
+		));
 		Ok(PollConfig {
 			interval: duration,
 		})
 	}
 }
 
+#[derive(Clone)]
+pub struct AgentConfig {
+	pub url: String,
+	pub host: String,
+	pub token: Option<String>,
+	pub interval: Duration,
+	pub batch_size: usize,
+	pub buffer_dir: String,
+	pub max_buffer_bytes: u64,
+}
+
+impl AgentConfig {
+	fn parse(c: &mut ConfigMap) -> Result<AgentConfig, ConfigError> {
+		let url = try!(c.descend_json("url", |u| mandatory(u).and_then(as_string)));
+		let host = try!(c.descend_json("host", |h| mandatory(h).and_then(as_string)));
+		let token = try!(c.descend_json("token", as_string_opt));
+		let interval = try!(c.descend_json("interval", |i| i.map_m(as_duration)));
+		let batch_size = try!(c.descend_json("batch_size", |b| b.map_m(|b| as_i64(b).and_then(positive))));
+		let buffer_dir = try!(c.descend_json("buffer_dir", as_string_opt));
+		let max_buffer_bytes = try!(c.descend_json("max_buffer_bytes", |b| b.map_m(|b| as_i64(b).and_then(positive))));
+		Ok(AgentConfig {
+			url: url,
+			host: host,
+			token: token,
+			interval: interval.unwrap_or_else(|| Duration::seconds(5)),
+			batch_size: batch_size.unwrap_or(500) as usize,
+			buffer_dir: buffer_dir.unwrap_or_else(|| "/var/lib/iysr".to_string()),
+			max_buffer_bytes: max_buffer_bytes.unwrap_or(10 * 1024 * 1024),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct CollectorConfig {
+	pub token: Option<String>,
+}
+
+impl CollectorConfig {
+	fn parse(c: &mut ConfigMap) -> Result<CollectorConfig, ConfigError> {
+		let token = try!(c.descend_json("token", as_string_opt));
+		Ok(CollectorConfig {
+			token: token,
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct IngestSource {
+	pub id: String,
+	pub token: String,
+}
+
+impl IngestSource {
+	fn parse(id: String, conf: Json) -> Result<IngestSource, ConfigError> {
+		let conf = try!(as_object(conf));
+		ConfigCheck::consume_new(conf, |conf| {
+			let token = try!(conf.descend_json("token", |t| mandatory(t).and_then(as_string)));
+			Ok(IngestSource {
+				id: id,
+				token: token,
+			})
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct FlappingConfig {
+	// the number of transitions within `window` which is considered flapping
+	// (0 disables flapping detection)
+	pub transitions: usize,
+	pub window: Duration,
+}
+
+impl FlappingConfig {
+	fn default() -> FlappingConfig {
+		FlappingConfig {
+			transitions: 5,
+			window: Duration::minutes(5),
+		}
+	}
+
+	fn parse(c: &mut ConfigMap) -> Result<FlappingConfig, ConfigError> {
+		let default = Self::default();
+		let transitions = try!(c.descend_json("transitions", |t| t.map_m(as_i32)));
+		let window = try!(c.descend_json("window", |w| w.map_m(as_duration)));
+		Ok(FlappingConfig {
+			transitions: ::std::cmp::max(transitions.unwrap_or(default.transitions as i32), 0) as usize,
+			window: window.unwrap_or(default.window),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct StoreConfig {
+	pub dir: String,
+	// retention: the oldest segments are removed once they're older than
+	// `max_age`, or the store is larger than `max_size` bytes
+	pub max_age: Option<Duration>,
+	pub max_size: Option<u64>,
+	pub segment_size: u64,
+}
+
+impl StoreConfig {
+	fn parse(c: &mut ConfigMap) -> Result<StoreConfig, ConfigError> {
+		let dir = try!(c.descend_json("dir", |d| mandatory(d).and_then(as_string)));
+		let max_age = try!(c.descend_json("max_age", |d| d.map_m(as_duration)));
+		let max_size = try!(c.descend_json("max_size", |n| n.map_m(as_i64)));
+		let segment_size = try!(c.descend_json("segment_size", |n| n.map_m(as_i64)));
+		Ok(StoreConfig {
+			dir: dir,
+			max_age: Some(max_age.unwrap_or_else(|| Duration::days(7))),
+			max_size: Some(try!(positive(max_size.unwrap_or(100 * 1024 * 1024)))),
+			segment_size: try!(positive(segment_size.unwrap_or(4 * 1024 * 1024))),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct RestartConfig {
+	// consecutive failed restarts before a source is marked down (0 to retry forever)
+	pub max_restarts: u32,
+	pub initial_backoff: Duration,
+	pub max_backoff: Duration,
+	// a restarted source which stays up this long is considered healthy again
+	pub reset_after: Duration,
+}
+
+impl RestartConfig {
+	fn default() -> RestartConfig {
+		RestartConfig {
+			max_restarts: 10,
+			initial_backoff: Duration::seconds(1),
+			max_backoff: Duration::minutes(5),
+			reset_after: Duration::minutes(10),
+		}
+	}
+
+	fn parse(c: &mut ConfigMap) -> Result<RestartConfig, ConfigError> {
+		let default = Self::default();
+		// rejected rather than clamped when negative, since 0 means retry forever
+		let max_restarts = try!(c.descend_json("max_restarts", |n| n.map_m(|n| as_i32(n).and_then(|n| {
+			if n >= 0 {
+				Ok(n as u32)
+			} else {
+				Err(ConfigError::new(format!("Expected a number of restarts, got {}", n)))
+			}
+		}))));
+		let initial_backoff = try!(c.descend_json("initial_backoff", |d| d.map_m(as_duration)));
+		let max_backoff = try!(c.descend_json("max_backoff", |d| d.map_m(as_duration)));
+		let reset_after = try!(c.descend_json("reset_after", |d| d.map_m(as_duration)));
+		Ok(RestartConfig {
+			max_restarts: max_restarts.unwrap_or(default.max_restarts),
+			initial_backoff: initial_backoff.unwrap_or(default.initial_backoff),
+			max_backoff: max_backoff.unwrap_or(default.max_backoff),
+			reset_after: reset_after.unwrap_or(default.reset_after),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct HealthConfig {
+	// sources which haven't sent an update within this window are marked stale
+	pub stale_after: Option<Duration>,
+	// per-source overrides, by source id
+	pub sources: HashMap<String, Duration>,
+}
+
+impl HealthConfig {
+	fn default() -> HealthConfig {
+		HealthConfig {
+			stale_after: None,
+			sources: HashMap::new(),
+		}
+	}
+
+	fn parse(c: &mut ConfigMap) -> Result<HealthConfig, ConfigError> {
+		let stale_after = try!(c.descend_json("stale_after", |d| d.map_m(as_duration)));
+		let sources = try!(c.descend_json("sources", |sources| match sources {
+			Some(json) => {
+				let conf = try!(as_object(json));
+				let mut rv = HashMap::new();
+				for (id, duration) in conf {
+					let duration = try!(annotate_error!(id, as_duration(duration)));
+					rv.insert(id, duration);
+				}
+				Ok(rv)
+			},
+			None => Ok(HashMap::new()),
+		}));
+		Ok(HealthConfig {
+			stale_after: stale_after,
+			sources: sources,
+		})
+	}
+
+	pub fn stale_after(&self, source: &str) -> Option<Duration> {
+		self.sources.get(source).cloned().or(self.stale_after)
+	}
+}
+
+#[derive(Clone)]
+pub enum AlertCondition {
+	// a state item (e.g. a unit) is in the given state
+	State(State),
+	// at least `count` events of `level` or above within `window`
+	Events {
+		level: Severity,
+		count: usize,
+		window: Duration,
+	},
+	// a metric is above or below a threshold
+	Metric {
+		above: Option<f64>,
+		below: Option<f64>,
+	},
+}
+
+#[derive(Clone)]
+pub struct AlertRule {
+	pub id: String,
+	pub source: Option<::glob::Pattern>,
+	// matched against the state item, event or metric id
+	pub item: Option<::glob::Pattern>,
+	pub condition: AlertCondition,
+	pub severity: Severity,
+	// how long the condition must hold before the alert fires
+	pub pending: Duration,
+	// how long the condition must be false before a firing alert is resolved
+	pub resolve_after: Duration,
+}
+
+impl AlertRule {
+	fn parse_events(c: &mut ConfigMap) -> Result<AlertCondition, ConfigError> {
+		let level = try!(c.descend_json("level", |l|
+			l.map_m(|l| as_string(l).and_then(as_severity))));
+		let count = try!(c.descend_json("count", |n| mandatory(n).and_then(as_i32)));
+		let window = try!(c.descend_json("window", |w| mandatory(w).and_then(as_duration)));
+		if count < 1 {
+			return Err(ConfigError::new(format!("Invalid count: {}", count)));
+		}
+		Ok(AlertCondition::Events {
+			level: level.unwrap_or(Severity::Error),
+			count: count as usize,
+			window: window,
+		})
+	}
+
+	fn parse_metric(c: &mut ConfigMap) -> Result<AlertCondition, ConfigError> {
+		let above = try!(c.descend_json("above", |v| v.map_m(as_f64)));
+		let below = try!(c.descend_json("below", |v| v.map_m(as_f64)));
+		if above.is_none() && below.is_none() {
+			return Err(ConfigError::new("one of `above` or `below` is required".to_string()));
+		}
+		Ok(AlertCondition::Metric {
+			above: above,
+			below: below,
+		})
+	}
+
+	fn parse(id: String, conf: Json) -> Result<AlertRule, ConfigError> {
+		let conf = try!(as_object(conf));
+		ConfigCheck::consume_new(conf, |conf| {
+			let source = try!(conf.descend_json("source", |s|
+				s.map_m(|s| as_string(s).and_then(as_glob))));
+			let item = try!(conf.descend_json("item", |i|
+				i.map_m(|i| as_string(i).and_then(as_glob))));
+			let state = try!(conf.descend_json("state", |s|
+				s.map_m(|s| as_string(s).and_then(as_state))));
+			let events = try!(conf.consume("events", |e| e.map_m(Self::parse_events)));
+			let metric = try!(conf.consume("metric", |m| m.map_m(Self::parse_metric)));
+			let condition = match (state, events, metric) {
+				(Some(state), None, None) => AlertCondition::State(state),
+				(None, Some(events), None) => events,
+				(None, None, Some(metric)) => metric,
+				_ => return Err(ConfigError::new("exactly one of `state`, `events` or `metric` is required".to_string())),
+			};
+			let severity = try!(conf.descend_json("severity", |s|
+				s.map_m(|s| as_string(s).and_then(as_severity))));
+			let pending = try!(conf.descend_json("for", |d| d.map_m(as_duration)));
+			let resolve_after = try!(conf.descend_json("resolve_after", |d| d.map_m(as_duration)));
+			Ok(AlertRule {
+				id: id,
+				source: source,
+				item: item,
+				condition: condition,
+				severity: severity.unwrap_or(Severity::Error),
+				pending: pending.unwrap_or_else(Duration::zero),
+				resolve_after: resolve_after.unwrap_or_else(Duration::zero),
+			})
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct ApiConfig {
+	// required for requests which modify the monitor (e.g. creating silences)
+	pub token: Option<String>,
+	// the address the HTTP server binds to, which also serves `/collect` and
+	// `/ingest`. Use e.g. `0.0.0.0:3000` to accept agents on other hosts.
+	pub listen: String,
+}
+
+impl ApiConfig {
+	pub fn default() -> ApiConfig {
+		ApiConfig {
+			token: None,
+			listen: "127.0.0.1:3000".to_string(),
+		}
+	}
+
+	fn parse(c: &mut ConfigMap) -> Result<ApiConfig, ConfigError> {
+		let token = try!(c.descend_json("token", as_string_opt));
+		let listen = try!(c.descend_json("listen", as_string_opt));
+		Ok(ApiConfig {
+			token: token,
+			listen: listen.unwrap_or(Self::default().listen),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct NotifyStyle {
+	// the `urgency` hint: 0 (low), 1 (normal) or 2 (critical)
+	pub urgency: u8,
+	pub category: Option<String>,
+	pub icon: Option<String>,
+}
+
+impl NotifyStyle {
+	fn default(severity: &Severity) -> NotifyStyle {
+		let (urgency, name, icon) = match *severity {
+			Severity::Emergency | Severity::Alert | Severity::Critical | Severity::Error => (2, "error", "dialog-error"),
+			Severity::Warning => (1, "warning", "dialog-warning"),
+			Severity::Notice | Severity::Info | Severity::Debug => (0, "info", "dialog-information"),
+		};
+		NotifyStyle {
+			urgency: urgency,
+			category: Some(format!("x-iysr.{}", name)),
+			icon: Some(icon.to_string()),
+		}
+	}
+
+	fn parse(severity: &Severity, c: &mut ConfigMap) -> Result<NotifyStyle, ConfigError> {
+		let default = Self::default(severity);
+		let urgency = try!(c.descend_json("urgency", |u| u.map_m(|u| as_string(u).and_then(as_urgency))));
+		let category = try!(c.descend_json("category", as_string_opt));
+		let icon = try!(c.descend_json("icon", as_string_opt));
+		Ok(NotifyStyle {
+			urgency: urgency.unwrap_or(default.urgency),
+			category: category.or(default.category),
+			icon: icon.or(default.icon),
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct NotifyRule {
+	// matched against event / unit ids and their attributes
+	pub filter: FilterCommon,
+	pub level: Option<Severity>,
+	pub timeout: Option<Duration>,
+}
+
+impl NotifyRule {
+	fn parse(conf: Json) -> Result<NotifyRule, ConfigError> {
+		let conf = try!(as_object(conf));
+		ConfigCheck::consume_new(conf, |conf| {
+			let filter = try!(FilterCommon::parse(conf));
+			let level = try!(conf.descend_json("level", |l|
+				l.map_m(|l| as_string(l).and_then(as_severity))));
+			let timeout = try!(conf.descend_json("timeout", |t| t.map_m(|t| as_duration(t).and_then(positive_duration))));
+			Ok(NotifyRule {
+				filter: filter,
+				level: level,
+				timeout: timeout,
+			})
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct NotifyConfig {
+	// events below this severity aren't shown
+	pub level: Severity,
+	// how long event notifications are shown for
+	pub timeout: Duration,
+	// by source id
+	pub sources: HashMap<String, NotifyRule>,
+	// overrides of the default style for each severity
+	pub styles: Vec<(Severity, NotifyStyle)>,
+	// whether notifications about failed units offer restart / acknowledge actions
+	pub actions: bool,
+	// run to show a unit's logs, with `{unit}` replaced by the unit name and `{unit_flag}`
+	// by journalctl's `--unit` or `--user-unit` (there's no "Show logs" action without it)
+	pub log_command: Option<Vec<String>>,
+}
+
+impl NotifyConfig {
+	pub fn default() -> NotifyConfig {
+		NotifyConfig {
+			level: Severity::Warning,
+			timeout: Duration::seconds(10),
+			sources: HashMap::new(),
+			styles: Vec::new(),
+			actions: true,
+			log_command: None,
+		}
+	}
+
+	// The minimum severity notified about for a source
+	pub fn level(&self, source: &str) -> Severity {
+		self.sources.get(source).and_then(|r| r.level.clone()).unwrap_or(self.level.clone())
+	}
+
+	pub fn timeout(&self, source: &str) -> Duration {
+		self.sources.get(source).and_then(|r| r.timeout).unwrap_or(self.timeout)
+	}
+
+	pub fn style(&self, severity: &Severity) -> NotifyStyle {
+		self.styles.iter()
+			.find(|&&(ref s, _)| s == severity)
+			.map(|&(_, ref style)| style.clone())
+			.unwrap_or_else(|| NotifyStyle::default(severity))
+	}
+
+	fn parse(c: &mut ConfigMap) -> Result<NotifyConfig, ConfigError> {
+		let default = Self::default();
+		let level = try!(c.descend_json("level", |l|
+			l.map_m(|l| as_string(l).and_then(as_severity))));
+		let timeout = try!(c.descend_json("timeout", |t| t.map_m(|t| as_duration(t).and_then(positive_duration))));
+		let sources = try!(c.descend_json("sources", |sources| match sources {
+			Some(json) => {
+				let conf = try!(as_object(json));
+				let mut rv = HashMap::new();
+				for (id, rule_conf) in conf {
+					let rule = try!(annotate_error!(id, NotifyRule::parse(rule_conf)));
+					rv.insert(id, rule);
+				}
+				Ok(rv)
+			},
+			None => Ok(HashMap::new()),
+		}));
+		let styles = try!(c.descend_json("severities", |styles| match styles {
+			Some(json) => {
+				let conf = try!(as_object(json));
+				let mut rv = Vec::new();
+				for (name, style_conf) in conf {
+					let severity = try!(annotate_error!(name, as_severity(name.clone())));
+					let style = try!(annotate_error!(name, as_object(style_conf).and_then(|style_conf|
+						ConfigCheck::consume_new(style_conf, |c| NotifyStyle::parse(&severity, c)))));
+					rv.push((severity, style));
+				}
+				Ok(rv)
+			},
+			None => Ok(Vec::new()),
+		}));
+		let actions = try!(c.descend_json("actions", |a| a.map_m(as_boolean)));
+		let log_command = try!(c.descend_json("log_command", |cmd| cmd.map_m(|cmd| {
+			let cmd = try!(cmd.descend_map_json(as_string));
+			if cmd.is_empty() {
+				return Err(ConfigError::new("Empty command".to_string()));
+			}
+			Ok(cmd)
+		})));
+		Ok(NotifyConfig {
+			level: level.unwrap_or(default.level),
+			timeout: timeout.unwrap_or(default.timeout),
+			sources: sources,
+			styles: styles,
+			actions: actions.unwrap_or(default.actions),
+			log_command: log_command,
+		})
+	}
+}
+
+#[derive(Clone)]
+pub struct SilenceMatch {
+	pub source: Option<::glob::Pattern>,
+	// all of these must match the item (e.g. a unit name) or its attributes
+	pub matchers: Vec<Match>,
+	pub comment: Option<String>,
+}
+
+impl SilenceMatch {
+	fn parse(c: &mut ConfigMap) -> Result<SilenceMatch, ConfigError> {
+		let source = try!(c.descend_json("source", |s|
+			s.map_m(|s| as_string(s).and_then(as_glob))));
+		let matchers = try!(c.descend_json("match", FilterCommon::parse_matchers));
+		let comment = try!(c.descend_json("comment", as_string_opt));
+		Ok(SilenceMatch {
+			source: source,
+			matchers: matchers,
+			comment: comment,
+		})
+	}
+}
+
+// a recurring maintenance window
+#[derive(Clone)]
+pub struct SilenceWindow {
+	pub id: String,
+	pub target: SilenceMatch,
+	pub schedule: Schedule,
+	pub duration: Duration,
+}
+
+impl SilenceWindow {
+	fn parse(id: String, conf: Json) -> Result<SilenceWindow, ConfigError> {
+		let conf = try!(as_object(conf));
+		ConfigCheck::consume_new(conf, |conf| {
+			let target = try!(SilenceMatch::parse(conf));
+			let schedule = try!(conf.descend_json("schedule", |s| mandatory(s).and_then(as_string)));
+			let schedule = try!(Schedule::parse(schedule.deref()).map_err(ConfigError::new));
+			let duration = try!(conf.descend_json("duration", |d| mandatory(d).and_then(as_duration)));
+			Ok(SilenceWindow {
+				id: id,
+				target: target,
+				schedule: schedule,
+				duration: duration,
+			})
+		})
+	}
+}
+
+// a one-off silence, created at runtime. Times are in seconds since the epoch.
+pub struct SilenceRequest {
+	pub target: SilenceMatch,
+	pub start: Option<i64>,
+	pub end: Option<i64>,
+	pub duration: Option<Duration>,
+}
+
+impl SilenceRequest {
+	pub fn parse(json: Json) -> Result<SilenceRequest, ConfigError> {
+		let conf = try!(as_object(json));
+		ConfigCheck::consume_new(conf, |conf| {
+			let target = try!(SilenceMatch::parse(conf));
+			let start = try!(conf.descend_json("start", |s| s.map_m(as_i64)));
+			let end = try!(conf.descend_json("end", |e| e.map_m(as_i64)));
+			let duration = try!(conf.descend_json("duration", |d| d.map_m(as_duration)));
+			if end.is_some() == duration.is_some() {
+				return Err(ConfigError::new("exactly one of `end` or `duration` is required".to_string()));
+			}
+			Ok(SilenceRequest {
+				target: target,
+				start: start,
+				end: end,
+				duration: duration,
+			})
+		})
+	}
+}
+
+// an acknowledgement of a failed item, created at runtime
+pub struct AckRequest {
+	pub source: String,
+	// the state item (e.g. a unit name) or `Failure` id
+	pub id: String,
+	pub comment: Option<String>,
+	// in seconds since the epoch
+	pub expires: Option<i64>,
+	pub duration: Option<Duration>,
+}
+
+impl AckRequest {
+	pub fn parse(json: Json) -> Result<AckRequest, ConfigError> {
+		let conf = try!(as_object(json));
+		ConfigCheck::consume_new(conf, |conf| {
+			let source = try!(conf.descend_json("source", |s| mandatory(s).and_then(as_string)));
+			let id = try!(conf.descend_json("id", |i| mandatory(i).and_then(as_string)));
+			let comment = try!(conf.descend_json("comment", as_string_opt));
+			let expires = try!(conf.descend_json("expires", |e| e.map_m(as_i64)));
+			let duration = try!(conf.descend_json("duration", |d| d.map_m(as_duration)));
+			if expires.is_some() && duration.is_some() {
+				return Err(ConfigError::new("only one of `expires` or `duration` may be given".to_string()));
+			}
+			Ok(AckRequest {
+				source: source,
+				id: id,
+				comment: comment,
+				expires: expires,
+				duration: duration,
+			})
+		})
+	}
+}
+
 pub struct Config {
 	pub sources: Vec<SourceConfig>,
 	pub poll: PollConfig,
+	pub agent: Option<AgentConfig>,
+	pub collector: Option<CollectorConfig>,
+	pub ingest: Vec<IngestSource>,
+	pub flapping: FlappingConfig,
+	pub health: HealthConfig,
+	pub restart: RestartConfig,
+	pub store: Option<StoreConfig>,
+	pub notify: NotifyConfig,
+	pub alerts: Vec<AlertRule>,
+	pub silences: Vec<SilenceWindow>,
+	pub api: ApiConfig,
 }
 
 impl Config {
@@ -356,6 +1461,9 @@ impl Config {
 								id: "systemd.system".to_string(),
 							},
 							user: None,
+							timer_grace: None,
+							expect: Vec::new(),
+							journal_lines: None,
 						}),
 						SourceConfig::Journal(JournalConfig {
 							common: CommonConfig {
@@ -373,9 +1481,76 @@ impl Config {
 					))
 				},
 			}));
+			let flapping = try!(config.consume("flapping", |f| match f {
+				None => Ok(FlappingConfig::default()),
+				Some(c) => FlappingConfig::parse(c),
+			}));
+			let health = try!(config.consume("health", |h| match h {
+				None => Ok(HealthConfig::default()),
+				Some(c) => HealthConfig::parse(c),
+			}));
+			let restart = try!(config.consume("restart", |r| match r {
+				None => Ok(RestartConfig::default()),
+				Some(c) => RestartConfig::parse(c),
+			}));
+			let store = try!(config.consume("store", |s| s.map_m(StoreConfig::parse)));
+			let notify = try!(config.consume("notify", |n| match n {
+				None => Ok(NotifyConfig::default()),
+				Some(c) => NotifyConfig::parse(c),
+			}));
+			let alerts = try!(config.descend_json("alerts", |alerts| match alerts {
+				Some(json) => {
+					let conf = try!(as_object(json));
+					let mut rv = Vec::new();
+					for (id, rule_conf) in conf {
+						rv.push(try!(annotate_error!(id, AlertRule::parse(id.clone(), rule_conf))));
+					}
+					Ok(rv)
+				},
+				None => Ok(Vec::new()),
+			}));
+			let silences = try!(config.descend_json("silences", |silences| match silences {
+				Some(json) => {
+					let conf = try!(as_object(json));
+					let mut rv = Vec::new();
+					for (id, window_conf) in conf {
+						rv.push(try!(annotate_error!(id, SilenceWindow::parse(id.clone(), window_conf))));
+					}
+					Ok(rv)
+				},
+				None => Ok(Vec::new()),
+			}));
+			let api = try!(config.consume("api", |a| match a {
+				None => Ok(ApiConfig::default()),
+				Some(c) => ApiConfig::parse(c),
+			}));
+			let agent = try!(config.consume("agent", |a| a.map_m(AgentConfig::parse)));
+			let collector = try!(config.consume("collector", |c| c.map_m(CollectorConfig::parse)));
+			let ingest = try!(config.descend_json("ingest", |ingest| match ingest {
+				Some(json) => {
+					let conf = try!(as_object(json));
+					let mut rv = Vec::new();
+					for (id, source_conf) in conf {
+						rv.push(try!(annotate_error!(id, IngestSource::parse(id.clone(), source_conf))));
+					}
+					Ok(rv)
+				},
+				None => Ok(Vec::new()),
+			}));
 			Ok(Config {
 				poll: poll,
 				sources: sources,
+				agent: agent,
+				collector: collector,
+				ingest: ingest,
+				flapping: flapping,
+				health: health,
+				restart: restart,
+				store: store,
+				notify: notify,
+				alerts: alerts,
+				silences: silences,
+				api: api,
 			})
 		})
 	}
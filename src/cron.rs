@@ -0,0 +1,202 @@
+// Cron-like schedules, written as `minute hour day-of-month month day-of-week`.
+// Each field is `*`, a number, a range (`1-5`) or a list of those (`1,3-4`),
+// optionally with a step (`*/15`, `0-30/10`). Day-of-week 0 (or 7) is Sunday.
+// As in cron, when both day fields are restricted either one may match.
+
+use std::fmt;
+use std::str::FromStr;
+use chrono::{DateTime,Local,Datelike,Timelike,Duration};
+
+#[derive(Clone,Debug)]
+struct Field {
+	bits: u64,
+	any: bool,
+}
+
+impl Field {
+	fn parse(s: &str, min: u32, max: u32) -> Result<Field, String> {
+		let number = |s: &str| u32::from_str(s).map_err(|_| format!("invalid number: {}", s));
+		let mut bits = 0u64;
+		for part in s.split(',') {
+			let (range, step) = match part.find('/') {
+				Some(idx) => (&part[0..idx], try!(number(&part[idx+1..]))),
+				None => (part, 1),
+			};
+			if step == 0 {
+				return Err(format!("invalid step: {}", part));
+			}
+			let (lo, hi) = if range == "*" {
+				(min, max)
+			} else {
+				match range.find('-') {
+					Some(idx) => (try!(number(&range[0..idx])), try!(number(&range[idx+1..]))),
+					// `n/step` means from n until the end of the range
+					None => {
+						let n = try!(number(range));
+						(n, if step > 1 { max } else { n })
+					},
+				}
+			};
+			if lo < min || hi > max || lo > hi {
+				return Err(format!("out of range: {}", part));
+			}
+			let mut n = lo;
+			while n <= hi {
+				bits |= 1 << n;
+				n += step;
+			}
+		}
+		Ok(Field {
+			bits: bits,
+			any: s == "*",
+		})
+	}
+
+	fn contains(&self, n: u32) -> bool {
+		self.bits & (1 << n) != 0
+	}
+}
+
+#[derive(Clone,Debug)]
+pub struct Schedule {
+	spec: String,
+	minute: Field,
+	hour: Field,
+	day: Field,
+	month: Field,
+	weekday: Field,
+}
+
+impl Schedule {
+	pub fn parse(spec: &str) -> Result<Schedule, String> {
+		let fields: Vec<&str> = spec.split_whitespace().collect();
+		if fields.len() != 5 {
+			return Err(format!("expected 5 fields, got {}: {}", fields.len(), spec));
+		}
+		let mut weekday = try!(Field::parse(fields[4], 0, 7));
+		if weekday.contains(7) {
+			weekday.bits |= 1;
+		}
+		Ok(Schedule {
+			spec: spec.to_string(),
+			minute: try!(Field::parse(fields[0], 0, 59)),
+			hour: try!(Field::parse(fields[1], 0, 23)),
+			day: try!(Field::parse(fields[2], 1, 31)),
+			month: try!(Field::parse(fields[3], 1, 12)),
+			weekday: weekday,
+		})
+	}
+
+	pub fn matches(&self, time: &DateTime<Local>) -> bool {
+		let day = self.day.contains(time.day());
+		let weekday = self.weekday.contains(time.weekday().num_days_from_sunday());
+		let day_matches = if self.day.any || self.weekday.any {
+			day && weekday
+		} else {
+			day || weekday
+		};
+		day_matches
+			&& self.minute.contains(time.minute())
+			&& self.hour.contains(time.hour())
+			&& self.month.contains(time.month())
+	}
+
+	// Whether `time` falls within `duration` of a scheduled time
+	pub fn within(&self, time: &DateTime<Local>, duration: Duration) -> bool {
+		let minutes = ::std::cmp::max(duration.num_minutes(), 1);
+		let mut i = 0;
+		while i < minutes {
+			if self.matches(&(time.clone() - Duration::minutes(i))) {
+				return true;
+			}
+			i += 1;
+		}
+		false
+	}
+}
+
+impl fmt::Display for Schedule {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		self.spec.fmt(f)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::{DateTime,Local,TimeZone,Duration};
+	use super::{Field,Schedule};
+
+	fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+		// May 2016; the 1st is a Sunday
+		Local.ymd(2016, 5, day).and_hms(hour, minute, 0)
+	}
+
+	fn values(field: &Field, max: u32) -> Vec<u32> {
+		(0..max + 1).filter(|n| field.contains(*n)).collect()
+	}
+
+	#[test]
+	fn parses_fields() {
+		assert_eq!(values(&Field::parse("*", 0, 5).unwrap(), 5), vec!(0, 1, 2, 3, 4, 5));
+		assert_eq!(values(&Field::parse("3", 0, 5).unwrap(), 5), vec!(3));
+		assert_eq!(values(&Field::parse("1-3,5", 0, 5).unwrap(), 5), vec!(1, 2, 3, 5));
+		assert_eq!(values(&Field::parse("*/15", 0, 59).unwrap(), 59), vec!(0, 15, 30, 45));
+		assert_eq!(values(&Field::parse("0-30/10", 0, 59).unwrap(), 59), vec!(0, 10, 20, 30));
+		assert_eq!(values(&Field::parse("50/5", 0, 59).unwrap(), 59), vec!(50, 55));
+		assert!(Field::parse("*", 0, 5).unwrap().any);
+		assert!(!Field::parse("0-5", 0, 5).unwrap().any);
+	}
+
+	#[test]
+	fn rejects_invalid_fields() {
+		assert!(Field::parse("6", 0, 5).is_err());
+		assert!(Field::parse("0", 1, 5).is_err());
+		assert!(Field::parse("3-1", 0, 5).is_err());
+		assert!(Field::parse("*/0", 0, 5).is_err());
+		assert!(Field::parse("x", 0, 5).is_err());
+		assert!(Field::parse("", 0, 5).is_err());
+		assert!(Schedule::parse("* * * *").is_err());
+	}
+
+	#[test]
+	fn matches_times() {
+		let schedule = Schedule::parse("30 2 * * *").unwrap();
+		assert!(schedule.matches(&at(3, 2, 30)));
+		assert!(!schedule.matches(&at(3, 2, 31)));
+		assert!(!schedule.matches(&at(3, 3, 30)));
+	}
+
+	#[test]
+	fn sunday_is_0_or_7() {
+		assert!(Schedule::parse("0 0 * * 0").unwrap().matches(&at(1, 0, 0)));
+		assert!(Schedule::parse("0 0 * * 7").unwrap().matches(&at(1, 0, 0)));
+		assert!(!Schedule::parse("0 0 * * 7").unwrap().matches(&at(2, 0, 0)));
+	}
+
+	#[test]
+	fn day_or_weekday() {
+		// both restricted: the 15th, or any Monday
+		let schedule = Schedule::parse("0 0 15 * 1").unwrap();
+		assert!(schedule.matches(&at(15, 0, 0)));
+		assert!(schedule.matches(&at(2, 0, 0)));
+		assert!(!schedule.matches(&at(3, 0, 0)));
+
+		// only one restricted: that one has to match
+		let schedule = Schedule::parse("0 0 15 * *").unwrap();
+		assert!(schedule.matches(&at(15, 0, 0)));
+		assert!(!schedule.matches(&at(2, 0, 0)));
+		let schedule = Schedule::parse("0 0 * * 1").unwrap();
+		assert!(schedule.matches(&at(2, 0, 0)));
+		assert!(!schedule.matches(&at(15, 0, 0)));
+	}
+
+	#[test]
+	fn within_duration() {
+		let schedule = Schedule::parse("0 2 * * *").unwrap();
+		let hour = Duration::hours(1);
+		assert!(schedule.within(&at(3, 2, 0), hour));
+		assert!(schedule.within(&at(3, 2, 59), hour));
+		assert!(!schedule.within(&at(3, 3, 0), hour));
+		assert!(!schedule.within(&at(3, 1, 59), hour));
+	}
+}
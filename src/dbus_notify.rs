@@ -1,5 +1,6 @@
 // show persistent notifications for system state, plus
-// transient notifications for events
+// transient notifications for events. Notifications about failed units
+// offer actions to restart the unit, acknowledge it or show its logs.
 
 use std::collections::{HashMap, HashSet};
 use std::process::{Command,Stdio,Child};
@@ -19,8 +20,9 @@ use rustc_serialize::json::{Json};
 use std::time;
 use chrono::{DateTime,Local};
 use monitor::*;
-use config::SystemdConfig;
-use util::read_all;
+use config::{SystemdConfig,NotifyConfig,NotifyStyle,AckRequest};
+use filter::matches_common;
+use util::{read_all,JsonMap};
 use dbus::{Connection,BusType,Message,MessageItem,MessageType,Props,ConnectionItem,Path};
 use system_monitor::{SystemMonitor,Receiver};
 use worker::{Worker,WorkerSelf};
@@ -28,12 +30,29 @@ use super::dbus_common::*;
 use super::errors::*;
 use super::systemd_common::*;
 use super::system_monitor::StateSnapshot;
+use super::silences::{is_silenced,is_silenced_update};
+use super::acks::{Acks,is_acknowledged,is_acknowledged_update};
+use super::systemd_dbus::restart_unit;
+use super::transitions::is_transition;
+use super::problems::PROBLEMS_TYPE;
 extern crate dbus;
 
 const NOTIFY_IFACE: &'static str = "org.freedesktop.Notifications";
 const NOTIFY_PATH: &'static str = "/org/freedesktop/Notifications";
 const NOTIFY_SHOW_METHOD: &'static str = "Notify";
 const NOTIFY_HIDE_METHOD: &'static str = "CloseNotification";
+const ACTION_INVOKED_SIGNAL: &'static str = "ActionInvoked";
+const NOTIFICATION_CLOSED_SIGNAL: &'static str = "NotificationClosed";
+
+const ACTION_RESTART: &'static str = "restart";
+const ACTION_ACKNOWLEDGE: &'static str = "acknowledge";
+const ACTION_LOGS: &'static str = "logs";
+
+// how often invoked actions are checked for while no updates arrive
+const SIGNAL_POLL_MS: u64 = 500;
+
+// the most failing units named per source in the summary
+const MAX_UNITS_LISTED: usize = 5;
 
 struct DbusNotify<'a> {
 	conn: &'a Connection,
@@ -41,6 +60,9 @@ struct DbusNotify<'a> {
 	title: String,
 	body: String,
 	timeout: i32,
+	style: Option<NotifyStyle>,
+	// (key, label) pairs
+	actions: Vec<(String, String)>,
 }
 
 fn method_call(name: &str) -> Result<Message,InternalError> {
@@ -64,6 +86,8 @@ impl<'a> DbusNotify<'a> {
 			title: title,
 			body: body,
 			timeout: DbusNotify::convert_timeout(timeout),
+			style: None,
+			actions: Vec::new(),
 		}
 	}
 
@@ -74,6 +98,8 @@ impl<'a> DbusNotify<'a> {
 			title: "".into(),
 			body: "".into(),
 			timeout: DbusNotify::convert_timeout(timeout),
+			style: None,
+			actions: Vec::new(),
 		}
 	}
 
@@ -89,13 +115,43 @@ impl<'a> DbusNotify<'a> {
 		self.timeout = DbusNotify::convert_timeout(t);
 	}
 
+	fn set_style(&mut self, style: NotifyStyle) {
+		self.style = Some(style);
+	}
+
+	fn set_actions(&mut self, actions: Vec<(String, String)>) {
+		self.actions = actions;
+	}
+
+	fn hints(&self) -> Vec<MessageItem> {
+		let hint = |key: &str, value: MessageItem| MessageItem::DictEntry(
+			Box::new(MessageItem::Str(key.into())),
+			Box::new(MessageItem::Variant(Box::new(value))));
+		let mut rv = Vec::new();
+		match self.style {
+			Some(ref style) => {
+				rv.push(hint("urgency", MessageItem::Byte(style.urgency)));
+				match style.category {
+					Some(ref category) => rv.push(hint("category", MessageItem::Str(category.clone()))),
+					None => (),
+				}
+			},
+			None => (),
+		}
+		rv
+	}
+
 	fn show(&mut self) -> Result<(), InternalError> {
 		let id = match self.id {
 			None => 0,
 			Some(i) => i,
 		};
+		let icon = self.style.as_ref().and_then(|s| s.icon.clone()).unwrap_or("".into());
 		let mut method = try!(method_call(NOTIFY_SHOW_METHOD));
-		let actions: &[&str] = &[];
+		// actions are sent as a flat list of keys and labels
+		let actions: Vec<&str> = self.actions.iter()
+			.flat_map(|&(ref key, ref label)| vec!(key.deref(), label.deref()))
+			.collect();
 		debug!("DbusNotify showing notification");
 		method.append_items(&[
 			// STRING app_name;
@@ -105,7 +161,7 @@ impl<'a> DbusNotify<'a> {
 			MessageItem::UInt32(id),
 
 			// STRING app_icon;
-			MessageItem::Str("".into()),
+			MessageItem::Str(icon),
 
 			// STRING summary;
 			MessageItem::Str(self.title.clone()),
@@ -115,16 +171,16 @@ impl<'a> DbusNotify<'a> {
 
 			// ARRAY actions;
 			// MessageItem::Array(vec!(), MessageItem::Str("".into()).type_sig()),
-			actions.into(),
+			(&actions[..]).into(),
 
 			// DICT hints;
-			MessageItem::Array(vec!(), MessageItem::DictEntry(
+			MessageItem::Array(self.hints(), MessageItem::DictEntry(
 					Box::new(MessageItem::Str("".into())),
 					Box::new(MessageItem::Variant(Box::new(MessageItem::Bool(true)))) // actual variant type doesn't matter, but we need something...
 				).type_sig()),
 
 			// INT32 expire_timeout;
-			MessageItem::Int32(0i32)
+			MessageItem::Int32(self.timeout)
 		]);
 
 		let response = try!(call_method(self.conn, method));
@@ -165,46 +221,337 @@ impl<'a> Drop for DbusNotify<'a> {
 	}
 }
 
-fn notification_contents(_state: &StateSnapshot) -> Option<String> {
-	Some("TODO: summarize state".into())
+fn to_json_map(attrs: &Attributes) -> JsonMap {
+	attrs.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+// Whether an item (unit or event) from `source` passes the notify config
+fn wanted(config: &NotifyConfig, source: &str, severity: &Severity, id: &str, attrs: &Attributes) -> bool {
+	if *severity < config.level(source) {
+		return false;
+	}
+	match config.sources.get(source) {
+		Some(rule) => matches_common(&rule.filter, id, &to_json_map(attrs)),
+		None => true,
+	}
+}
+
+// A title and body summarizing failed units, or None if everything is healthy
+fn notification_contents(config: &NotifyConfig, state: &StateSnapshot) -> Option<(String, String)> {
+	let mut failed: Vec<(String, Vec<String>)> = Vec::new();
+	let mut errors: Vec<(String, String)> = Vec::new();
+	for update in state.values() {
+		// problems are failures rather than units, and are reported as events
+		if update.source.typ == PROBLEMS_TYPE {
+			continue;
+		}
+		match update.data {
+			Data::State(ref state) => {
+				let mut units: Vec<String> = state.iter()
+					.filter(|&(_, status)| status.state == State::Error)
+					.filter(|&(_, status)| !is_silenced(&status.attrs) && !is_acknowledged(&status.attrs))
+					.filter(|&(id, status)| wanted(config, &update.source.id, &Severity::Error, id, &status.attrs))
+					.map(|(id, _)| id.clone())
+					.collect();
+				if !units.is_empty() {
+					units.sort();
+					failed.push((update.source.id.clone(), units));
+				}
+			},
+			// the source couldn't be polled
+			Data::Error(ref failure) => {
+				let id = failure.id.clone().unwrap_or("".to_string());
+				if wanted(config, &update.source.id, &Severity::Error, &id, &HashMap::new()) {
+					errors.push((update.source.id.clone(), failure.error.clone()));
+				}
+			},
+			_ => (),
+		}
+	}
+	if failed.is_empty() && errors.is_empty() {
+		return None;
+	}
+	failed.sort();
+	errors.sort();
+
+	let count = failed.iter().fold(0, |total, &(_, ref units)| total + units.len());
+	let title = match (count, errors.len()) {
+		(1, 0) => "iysr: 1 failed unit".to_string(),
+		(n, 0) => format!("iysr: {} failed units", n),
+		(0, _) => "iysr: source errors".to_string(),
+		(n, _) => format!("iysr: {} failed units, source errors", n),
+	};
+	let mut lines = Vec::new();
+	for (source, units) in failed {
+		lines.push(format!("{}: {} failed", source, units.len()));
+		let mut names = units.iter().take(MAX_UNITS_LISTED).cloned().collect::<Vec<String>>().join(", ");
+		if units.len() > MAX_UNITS_LISTED {
+			names.push_str(&format!(" and {} more", units.len() - MAX_UNITS_LISTED));
+		}
+		lines.push(format!("  {}", names));
+	}
+	for (source, error) in errors {
+		lines.push(format!("{}: {}", source, error));
+	}
+	Some((title, lines.join("\n")))
+}
+
+// The severity of a partial update, and the item id and attributes it's filtered on
+fn event_subject(data: &Data) -> Option<(Severity, String, Arc<Attributes>)> {
+	match *data {
+		// events without a severity are treated as informational
+		Data::Event(ref event) => Some((event.severity.clone().unwrap_or(Severity::Info),
+			event.id.clone().unwrap_or("".to_string()), event.attrs.clone())),
+		Data::Alert(ref alert) => Some((alert.severity.clone(),
+			alert.item.clone().unwrap_or(alert.id.clone()), alert.attrs.clone())),
+		Data::Error(ref failure) => Some((Severity::Error,
+			failure.id.clone().unwrap_or("".to_string()), Arc::new(HashMap::new()))),
+		_ => None,
+	}
 }
 
-fn run<'a>(thread: WorkerSelf<InternalError>, monitor: Arc<Mutex<SystemMonitor>>) -> Result<(), InternalError> {
+// The title and body for a transient notification about a partial update
+fn event_contents(update: &Update) -> Option<(String, String)> {
+	let source = &update.source.id;
+	match update.data {
+		Data::Event(ref event) => {
+			let severity = event.severity.as_ref().map(|s| s.to_string()).unwrap_or("Event".to_string());
+			let message = event.message.clone().unwrap_or_else(||
+				event.id.clone().unwrap_or("(no message)".to_string()));
+			Some((format!("{} from {}", severity, source), message))
+		},
+		Data::Alert(ref alert) => {
+			Some((format!("{} alert {} {:?} ({})", alert.severity.to_string(), alert.id, alert.state, source),
+				alert.message.clone()))
+		},
+		Data::Error(ref failure) => Some((format!("Error from {}", source), failure.error.clone())),
+		_ => None,
+	}
+}
+
+// The failed unit which a notification's actions apply to
+#[derive(Clone)]
+struct ActionTarget {
+	source: String,
+	unit: String,
+	// for units of systemd sources, whether it's a user unit
+	systemd_user: Option<bool>,
+}
+
+struct Actions {
+	enabled: bool,
+	acks: Acks,
+	log_command: Option<Vec<String>>,
+	// systemd source id -> whether it watches the user instance
+	systemd_sources: HashMap<String, bool>,
+	// notification id -> the unit its actions apply to, until it's closed
+	pending: HashMap<u32, ActionTarget>,
+}
+
+fn signal_match_rule(member: &str) -> String {
+	format!("type='signal',interface='{}',path='{}',member='{}'", NOTIFY_IFACE, NOTIFY_PATH, member)
+}
+
+impl Actions {
+	fn listen(&self, conn: &Connection) -> Result<(), InternalError> {
+		if self.enabled {
+			try!(conn.add_match(signal_match_rule(ACTION_INVOKED_SIGNAL).deref()));
+			try!(conn.add_match(signal_match_rule(NOTIFICATION_CLOSED_SIGNAL).deref()));
+		}
+		Ok(())
+	}
+
+	// The unit which a transition event reports as having failed
+	fn target(&self, update: &Update) -> Option<ActionTarget> {
+		if !self.enabled || !is_transition(update) {
+			return None;
+		}
+		let event = match update.data {
+			Data::Event(ref event) => event,
+			_ => return None,
+		};
+		match event.attrs.get("new_state") {
+			Some(&Json::String(ref state)) if *state == format!("{:?}", State::Error) => (),
+			_ => return None,
+		}
+		event.id.as_ref().map(|unit| ActionTarget {
+			source: update.source.id.clone(),
+			unit: unit.clone(),
+			systemd_user: self.systemd_sources.get(&update.source.id).cloned(),
+		})
+	}
+
+	fn offered(&self, target: &ActionTarget) -> Vec<(String, String)> {
+		let mut rv = Vec::new();
+		let systemd = target.systemd_user.is_some();
+		if systemd {
+			rv.push((ACTION_RESTART.to_string(), "Restart".to_string()));
+		}
+		rv.push((ACTION_ACKNOWLEDGE.to_string(), "Acknowledge".to_string()));
+		if systemd && self.log_command.is_some() {
+			rv.push((ACTION_LOGS.to_string(), "Show logs".to_string()));
+		}
+		rv
+	}
+
+	// Handles any actions invoked (or notifications closed) since the last call
+	fn process_signals(&mut self, conn: &Connection) {
+		if !self.enabled {
+			return;
+		}
+		for item in conn.iter(0) {
+			let msg = match item {
+				ConnectionItem::Signal(msg) => msg,
+				// no more pending messages
+				ConnectionItem::Nothing => break,
+				_ => continue,
+			};
+			let (_, _, iface, member) = msg.headers();
+			if iface.as_ref().map(|i| i.deref()) != Some(NOTIFY_IFACE) {
+				continue;
+			}
+			let items = msg.get_items();
+			// (notifications from other applications aren't in `pending`)
+			match (member.as_ref().map(|m| m.deref()), items.get(0), items.get(1)) {
+				(Some(ACTION_INVOKED_SIGNAL), Some(&MessageItem::UInt32(id)), Some(&MessageItem::Str(ref action))) => {
+					match self.pending.get(&id).cloned() {
+						Some(target) => ignore_error!(self.invoke(&target, action.deref()), "running notification action"),
+						None => (),
+					}
+				},
+				(Some(NOTIFICATION_CLOSED_SIGNAL), Some(&MessageItem::UInt32(id)), _) => {
+					self.pending.remove(&id);
+				},
+				_ => (),
+			}
+		}
+	}
+
+	fn invoke(&self, target: &ActionTarget, action: &str) -> Result<(), InternalError> {
+		info!("running action {} for {} ({})", action, target.unit, target.source);
+		match (action, target.systemd_user) {
+			(ACTION_RESTART, Some(user)) => {
+				let bus = if user { BusType::Session } else { BusType::System };
+				restart_unit(bus, target.unit.deref())
+			},
+			(ACTION_ACKNOWLEDGE, _) => {
+				self.acks.add(AckRequest {
+					source: target.source.clone(),
+					id: target.unit.clone(),
+					comment: Some("acknowledged from a desktop notification".to_string()),
+					expires: None,
+					duration: None,
+				}).map_err(InternalError::new)
+			},
+			(ACTION_LOGS, Some(user)) => self.show_logs(target.unit.deref(), user),
+			_ => Err(InternalError::new(format!("Unknown action: {}", action))),
+		}
+	}
+
+	fn show_logs(&self, unit: &str, user: bool) -> Result<(), InternalError> {
+		let command = match self.log_command {
+			Some(ref command) => command,
+			None => return Err(InternalError::new("no log_command configured".to_string())),
+		};
+		let unit_flag = if user { "--user-unit" } else { "--unit" };
+		let args: Vec<String> = command.iter()
+			.map(|arg| arg.replace("{unit}", unit).replace("{unit_flag}", unit_flag))
+			.collect();
+		let mut child = try!(Command::new(&args[0]).args(&args[1..]).stdin(Stdio::null()).spawn());
+		// reap the command once it exits
+		try!(thread::Builder::new().spawn(move || ignore_error!(child.wait().map(|_| ()), "waiting for log command")));
+		Ok(())
+	}
+}
+
+fn run<'a>(thread: WorkerSelf<InternalError>, monitor: Arc<Mutex<SystemMonitor>>, config: NotifyConfig, systemd_sources: HashMap<String, bool>) -> Result<(), InternalError> {
 	info!("Starting DBus notification service ...");
-	let mut monitor = try!(monitor.lock());
-	let receiver = try!(monitor.subscribe());
+	// the monitor is only locked while subscribing, so other listeners can too
+	let (receiver, acks) = {
+		let mut monitor = try!(monitor.lock());
+		(try!(monitor.subscribe()), monitor.acks())
+	};
 	let conn = try!(Connection::get_private(BusType::Session));
+	let mut actions = Actions {
+		enabled: config.actions,
+		acks: acks,
+		log_command: config.log_command.clone(),
+		systemd_sources: systemd_sources,
+		pending: HashMap::new(),
+	};
+	try!(actions.listen(&conn));
 	let mut persistent_notification = DbusNotify::empty(&conn, None);
-	persistent_notification.set_title("iysr state".into());
+	persistent_notification.set_style(config.style(&Severity::Error));
 	let snapshot = StateSnapshot::new();
+	let mut shown = None;
+	let poll = time::Duration::from_millis(SIGNAL_POLL_MS);
 	loop {
-		let data = try!(receiver.recv());
+		actions.process_signals(&conn);
+		let (_, data) = match receiver.recv_timeout(poll) {
+			Ok(received) => received,
+			Err(mpsc::RecvTimeoutError::Timeout) => {
+				try!(thread.tick());
+				continue;
+			},
+			Err(mpsc::RecvTimeoutError::Disconnected) => return Err(InternalError::from(mpsc::RecvError)),
+		};
 		debug!("dbus_notify saw data...");
 		if snapshot.update(&data) {
-			match notification_contents(&snapshot) {
+			let contents = notification_contents(&config, &snapshot);
+			// sources re-send unchanged snapshots, which shouldn't re-show the notification
+			if contents == shown {
+				try!(thread.tick());
+				continue;
+			}
+			match contents {
 				None => {
 					try!(persistent_notification.hide());
 				},
-				Some(msg) => {
-					persistent_notification.set_body(msg);
+				Some((ref title, ref body)) => {
+					persistent_notification.set_title(title.clone());
+					persistent_notification.set_body(body.clone());
 					try!(persistent_notification.show());
 				},
 			}
-		} else {
-			// just an event - create a new one
-			let mut notification = DbusNotify::new(
-				&conn,
-				"iysr event".into(),
-				"TODO".into(),
-				Some(time::Duration::from_secs(10)));
-			try!(notification.show());
+			shown = contents;
+		} else if !is_silenced_update(&data) && !is_acknowledged_update(&data) {
+			let source = &data.source.id;
+			let subject = event_subject(&data.data)
+				.and_then(|(severity, id, attrs)| if wanted(&config, source, &severity, &id, &attrs) {
+					Some(severity)
+				} else {
+					None
+				});
+			match (subject, event_contents(&data)) {
+				(Some(severity), Some((title, body))) => {
+					// (the config ensures that this is positive)
+					let timeout = config.timeout(source).num_milliseconds();
+					let mut notification = DbusNotify::new(
+						&conn,
+						title,
+						body,
+						Some(time::Duration::from_millis(timeout as u64)));
+					notification.set_style(config.style(&severity));
+					let target = actions.target(&data);
+					match target {
+						Some(ref target) => notification.set_actions(actions.offered(target)),
+						None => (),
+					}
+					try!(notification.show());
+					match (notification.id, target) {
+						(Some(id), Some(target)) => { actions.pending.insert(id, target); },
+						_ => (),
+					}
+				},
+				_ => (),
+			}
 		}
 		try!(thread.tick());
 	}
 }
 
-pub fn main(monitor: Arc<Mutex<SystemMonitor>>, parent: &WorkerSelf<InternalError>) -> Result<Worker<InternalError>,io::Error> {
-	parent.spawn("dbus notify".into(), move |t| run(t, monitor))
+pub fn main(monitor: Arc<Mutex<SystemMonitor>>, config: NotifyConfig, systemd_sources: HashMap<String, bool>, parent: &WorkerSelf<InternalError>) -> Result<Worker<InternalError>,io::Error> {
+	parent.spawn("dbus notify".into(), move |t| run(t, monitor, config, systemd_sources))
 }
 
 pub fn test() -> Result<(),InternalError> {
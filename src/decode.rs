@@ -0,0 +1,304 @@
+// Decoding of `Update`s from JSON. This is the inverse of the
+// `Encodable` impls in monitor.rs, used to consume the output of
+// another iysr instance.
+
+use std::collections::{HashMap};
+use std::ops::Deref;
+use std::sync::{Arc};
+use rustc_serialize::json::{Json};
+use chrono;
+use monitor::*;
+use util::JsonMap;
+use super::errors::*;
+
+// Source types from other hosts which we know about. Anything else gets
+// the generic REMOTE_TYPE (`Source.typ` is static, so we can't just copy it).
+const KNOWN_TYPES: &'static [&'static str] = &[
+	"systemd", "journal", "socket", "file", "logfile", "syslog", "prometheus",
+	"ingest", "statsd", "problems", "iysr",
+];
+pub const REMOTE_TYPE: &'static str = "remote";
+
+fn invalid(what: &str, json: &Json) -> InternalError {
+	InternalError::new(format!("Invalid {}: {}", what, json))
+}
+
+fn take(obj: &mut JsonMap, key: &str) -> Json {
+	obj.remove(key).unwrap_or(Json::Null)
+}
+
+fn as_object(json: Json, what: &str) -> Result<JsonMap, InternalError> {
+	match json {
+		Json::Object(obj) => Ok(obj),
+		other => Err(invalid(what, &other)),
+	}
+}
+
+fn as_string_opt(json: Json, what: &str) -> Result<Option<String>, InternalError> {
+	match json {
+		Json::Null => Ok(None),
+		Json::String(s) => Ok(Some(s)),
+		other => Err(invalid(what, &other)),
+	}
+}
+
+fn as_string(json: Json, what: &str) -> Result<String, InternalError> {
+	let desc = format!("{}", json);
+	try!(as_string_opt(json, what)).ok_or_else(|| InternalError::new(format!("Invalid {}: {}", what, desc)))
+}
+
+fn as_i64(json: &Json, what: &str) -> Result<i64, InternalError> {
+	match *json {
+		Json::I64(n) => Ok(n),
+		Json::U64(n) => Ok(n as i64),
+		ref other => Err(invalid(what, other)),
+	}
+}
+
+fn as_attributes(json: Json) -> Result<Arc<Attributes>, InternalError> {
+	let obj = match json {
+		Json::Null => JsonMap::new(),
+		other => try!(as_object(other, "attrs")),
+	};
+	let mut attrs = HashMap::new();
+	attrs.extend(obj);
+	Ok(Arc::new(attrs))
+}
+
+pub fn source_type(name: &str) -> &'static str {
+	KNOWN_TYPES.iter().find(|t| **t == name).map(|t| *t).unwrap_or(REMOTE_TYPE)
+}
+
+pub fn decode_time(json: Json) -> Result<Time, InternalError> {
+	let obj = try!(as_object(json, "time"));
+	let sec = try!(as_i64(obj.get("sec").unwrap_or(&Json::Null), "time.sec"));
+	let ms = try!(as_i64(obj.get("ms").unwrap_or(&Json::Null), "time.ms"));
+	if ms < 0 || ms >= 1000 {
+		return Err(invalid("time.ms", &Json::I64(ms)));
+	}
+	Time::from_timestamp(sec, ms as u32)
+}
+
+fn decode_duration(json: &Json) -> Result<Duration, InternalError> {
+	let ms = match json.find("ms") {
+		Some(ms) => try!(as_i64(ms, "duration")),
+		None => return Err(invalid("duration", json)),
+	};
+	Ok(Duration(chrono::Duration::milliseconds(ms)))
+}
+
+pub fn decode_status(json: Json) -> Result<Status, InternalError> {
+	let mut obj = try!(as_object(json, "status"));
+	let state = try!(as_string(take(&mut obj, "state"), "state"));
+	let state = try!(State::from_name(state.deref()).ok_or_else(||
+		InternalError::new(format!("Unknown state: {}", state))
+	));
+	Ok(Status {
+		state: state,
+		attrs: try!(as_attributes(take(&mut obj, "attrs"))),
+	})
+}
+
+pub fn decode_state(json: Json) -> Result<HashMap<String, Status>, InternalError> {
+	let obj = try!(as_object(json, "state"));
+	let mut state = HashMap::new();
+	for (id, status) in obj {
+		state.insert(id, try!(decode_status(status)));
+	}
+	Ok(state)
+}
+
+pub fn decode_event(json: Json) -> Result<Event, InternalError> {
+	let mut obj = try!(as_object(json, "event"));
+	let severity = match try!(as_string_opt(take(&mut obj, "severity"), "severity")) {
+		Some(s) => Some(try!(Severity::from_name(s.deref()).ok_or_else(||
+			InternalError::new(format!("Unknown severity: {}", s))
+		))),
+		None => None,
+	};
+	Ok(Event {
+		id: try!(as_string_opt(take(&mut obj, "id"), "event.id")),
+		severity: severity,
+		message: try!(as_string_opt(take(&mut obj, "message"), "event.message")),
+		attrs: try!(as_attributes(take(&mut obj, "attrs"))),
+	})
+}
+
+fn decode_metric_value(json: Json) -> Result<ComputedMetricValue, InternalError> {
+	match json {
+		Json::I64(n) => Ok(ComputedMetricValue::Int(n)),
+		Json::U64(n) => Ok(ComputedMetricValue::Int(n as i64)),
+		Json::F64(n) => Ok(ComputedMetricValue::Float(n)),
+		ref other@Json::Object(_) => decode_duration(other).map(ComputedMetricValue::Duration),
+		other => Err(invalid("metric value", &other)),
+	}
+}
+
+pub fn decode_metrics(json: Json) -> Result<Metrics, InternalError> {
+	let mut obj = try!(as_object(json, "metrics"));
+	let span = try!(decode_duration(&take(&mut obj, "span")));
+	let values = match take(&mut obj, "values") {
+		Json::Array(values) => values,
+		other => return Err(invalid("metrics.values", &other)),
+	};
+	let mut rv = Vec::with_capacity(values.len());
+	for value in values {
+		let mut value = try!(as_object(value, "metric"));
+		rv.push(ComputedMetric {
+			id: try!(as_string(take(&mut value, "id"), "metric.id")),
+			value: try!(decode_metric_value(take(&mut value, "value"))),
+		});
+	}
+	let Duration(span) = span;
+	Ok(Metrics::new(rv, span))
+}
+
+// Raw metrics are submitted as {"id", "type", "value"}, where timespans are in ms
+pub fn decode_metric(json: Json) -> Result<Metric, InternalError> {
+	let mut obj = try!(as_object(json, "metric"));
+	let id = try!(as_string(take(&mut obj, "id"), "metric.id"));
+	let typ = try!(as_string(take(&mut obj, "type"), "metric.type"));
+	let value = take(&mut obj, "value");
+	let n = try!(as_i64(&value, "metric.value"));
+	if n > i32::max_value() as i64 || n < i32::min_value() as i64 {
+		return Err(invalid("metric.value", &value));
+	}
+	let n = n as i32;
+	let value = match typ.deref() {
+		"counter" => MetricValue::Counter(n),
+		"gauge" => MetricValue::Gauge(GaugeValue::Absolute(n)),
+		"gauge_delta" => MetricValue::Gauge(GaugeValue::Difference(n)),
+		"timespan" => MetricValue::Timespan(Duration(chrono::Duration::milliseconds(n as i64))),
+		other => return Err(InternalError::new(format!("Unknown metric type: {}", other))),
+	};
+	Ok(Metric {
+		id: id,
+		value: value,
+	})
+}
+
+pub fn decode_failure(json: Json) -> Result<Failure, InternalError> {
+	let mut obj = try!(as_object(json, "failure"));
+	Ok(Failure {
+		id: try!(as_string_opt(take(&mut obj, "id"), "failure.id")),
+		error: try!(as_string(take(&mut obj, "error"), "failure.error")),
+	})
+}
+
+pub fn decode_alert(json: Json) -> Result<Alert, InternalError> {
+	let mut obj = try!(as_object(json, "alert"));
+	let state = match try!(as_string(take(&mut obj, "state"), "alert.state")).deref() {
+		"Firing" => AlertState::Firing,
+		"Resolved" => AlertState::Resolved,
+		other => return Err(InternalError::new(format!("Unknown alert state: {}", other))),
+	};
+	let severity = try!(as_string(take(&mut obj, "severity"), "alert.severity"));
+	Ok(Alert {
+		id: try!(as_string(take(&mut obj, "id"), "alert.id")),
+		item: try!(as_string_opt(take(&mut obj, "item"), "alert.item")),
+		state: state,
+		severity: try!(Severity::from_name(severity.deref()).ok_or_else(||
+			InternalError::new(format!("Unknown severity: {}", severity))
+		)),
+		message: try!(as_string(take(&mut obj, "message"), "alert.message")),
+		since: try!(decode_time(take(&mut obj, "since"))),
+		attrs: try!(as_attributes(take(&mut obj, "attrs"))),
+	})
+}
+
+// Data is encoded as a [kind, payload] pair
+pub fn decode_data(json: Json) -> Result<Data, InternalError> {
+	let mut pair = match json {
+		Json::Array(pair) => pair,
+		other => return Err(invalid("data", &other)),
+	};
+	if pair.len() != 2 {
+		return Err(InternalError::new(format!("Invalid data: expected a pair, got {} items", pair.len())));
+	}
+	let payload = pair.pop().unwrap();
+	let kind = try!(as_string(pair.pop().unwrap(), "data kind"));
+	match kind.deref() {
+		"State" => decode_state(payload).map(Data::State),
+		"Event" => decode_event(payload).map(Data::Event),
+		"Metrics" => decode_metrics(payload).map(Data::Metrics),
+		"Error" => decode_failure(payload).map(Data::Error),
+		"Alert" => decode_alert(payload).map(Data::Alert),
+		other => Err(InternalError::new(format!("Unknown data kind: {}", other))),
+	}
+}
+
+// Decodes an update. If `namespace` is given, the source id
+// is prefixed with it (e.g. `web1/systemd.system`).
+pub fn decode_update(json: Json, namespace: Option<&str>) -> Result<Update, InternalError> {
+	let mut obj = try!(as_object(json, "update"));
+	let id = try!(as_string(take(&mut obj, "source"), "source"));
+	let typ = try!(as_string(take(&mut obj, "type"), "type"));
+	let id = match namespace {
+		Some(ns) => format!("{}/{}", ns, id),
+		None => id,
+	};
+	let data = try!(decode_data(take(&mut obj, "data")));
+
+	// scope isn't encoded, but sources only ever send state as a snapshot
+	let scope = match data {
+		Data::State(_) => UpdateScope::Snapshot,
+		_ => UpdateScope::Partial,
+	};
+	Ok(Update {
+		source: Arc::new(Source::new(id, source_type(typ.deref()))),
+		scope: scope,
+		time: try!(decode_time(take(&mut obj, "time"))),
+		data: data,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use rustc_serialize::json::Json;
+	use chrono::Timelike;
+	use monitor::*;
+	use super::{decode_time,decode_update,REMOTE_TYPE};
+
+	fn time(s: &str) -> Json {
+		Json::from_str(s).unwrap()
+	}
+
+	#[test]
+	fn decodes_time() {
+		let t = decode_time(time(r#"{"sec": 1462060800, "ms": 250}"#)).unwrap();
+		assert_eq!(t.timestamp(), 1462060800);
+		assert_eq!(t.time().nanosecond(), 250000000);
+	}
+
+	#[test]
+	fn rejects_invalid_ms() {
+		assert!(decode_time(time(r#"{"sec": 0, "ms": 1000}"#)).is_err());
+		assert!(decode_time(time(r#"{"sec": 0, "ms": -1}"#)).is_err());
+		assert!(decode_time(time(r#"{"sec": 0, "ms": 18446744073709551615}"#)).is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_sec() {
+		assert!(decode_time(time(r#"{"sec": 9223372036854775807, "ms": 0}"#)).is_err());
+		assert!(decode_time(time(r#"{"sec": "0", "ms": 0}"#)).is_err());
+		assert!(decode_time(time(r#"{"ms": 0}"#)).is_err());
+	}
+
+	#[test]
+	fn decodes_update() {
+		let json = time(r#"{
+			"source": "web", "type": "systemd", "time": {"sec": 10, "ms": 0},
+			"data": ["Event", {"id": "x", "severity": "Error", "message": "failed"}]
+		}"#);
+		let update = decode_update(json, Some("host")).unwrap();
+		assert_eq!(update.source.id, "host/web");
+		assert_eq!(update.source.typ, "systemd");
+		assert_eq!(update.time.timestamp(), 10);
+		match update.data {
+			Data::Event(ref event) => assert_eq!(event.severity, Some(Severity::Error)),
+			_ => panic!("expected an event"),
+		}
+		let json = time(r#"{"source": "web", "type": "other", "time": {"sec": 10, "ms": 0}, "data": ["Error", {"error": "failed"}]}"#);
+		assert_eq!(decode_update(json, None).unwrap().source.typ, REMOTE_TYPE);
+	}
+}
@@ -0,0 +1,123 @@
+// reports on the existence and freshness of files
+// (e.g. marker files touched by cron jobs)
+
+use std::collections::{HashMap};
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc};
+use std::time::{SystemTime,UNIX_EPOCH};
+use rustc_serialize::json::{Json};
+use chrono::{Duration};
+use glob::glob;
+use monitor::*;
+use config::{FileConfig,FileTarget};
+use super::errors::*;
+
+const FILE_TYPE: &'static str = "file";
+
+pub struct FileMonitor {
+	config: FileConfig,
+	source: Arc<Source>,
+}
+
+fn seconds_since(t: SystemTime, now: SystemTime) -> i64 {
+	match now.duration_since(t) {
+		Ok(d) => d.as_secs() as i64,
+		// modified in the future (clock skew); treat as brand new
+		Err(_) => 0,
+	}
+}
+
+impl FileMonitor {
+	pub fn new(config: FileConfig) -> FileMonitor {
+		let source = Arc::new(Source::new(config.common.id.clone(), FILE_TYPE));
+		FileMonitor {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn check_file(path: &Path, target: &FileTarget, now: SystemTime) -> Result<Status, io::Error> {
+		let meta = try!(fs::metadata(path));
+		let modified = try!(meta.modified());
+		let age = seconds_since(modified, now);
+
+		let mut attrs = HashMap::new();
+		attrs.insert("exists".to_string(), Json::Boolean(true));
+		attrs.insert("size".to_string(), Json::U64(meta.len()));
+		attrs.insert("age_s".to_string(), Json::I64(age));
+		attrs.insert("mtime".to_string(), Json::I64(seconds_since(UNIX_EPOCH, modified)));
+
+		let state = match target.max_age {
+			Some(max_age) if Duration::seconds(age) > max_age => {
+				attrs.insert("max_age_s".to_string(), Json::I64(max_age.num_seconds()));
+				State::Error
+			},
+			_ => State::Active,
+		};
+
+		Ok(Status {
+			state: state,
+			attrs: Arc::new(attrs),
+		})
+	}
+
+	fn missing(error: Option<String>) -> Status {
+		let mut attrs = HashMap::new();
+		attrs.insert("exists".to_string(), Json::Boolean(false));
+		match error {
+			Some(e) => { attrs.insert("error".to_string(), Json::String(e)); },
+			None => (),
+		};
+		Status {
+			state: State::Error,
+			attrs: Arc::new(attrs),
+		}
+	}
+
+	fn check(&self, target: &FileTarget, state: &mut HashMap<String, Status>) -> Result<(), InternalError> {
+		let now = SystemTime::now();
+		let paths = try!(glob(target.pattern.deref()).map_err(|e|
+			InternalError::new(format!("Invalid pattern {}: {}", target.pattern, e))
+		));
+
+		let mut found = false;
+		for path in paths {
+			found = true;
+			let (id, status) = match path {
+				Ok(path) => {
+					let status = match Self::check_file(&path, target, now) {
+						Ok(status) => status,
+						Err(e) => Self::missing(Some(format!("{}", e))),
+					};
+					(path.to_string_lossy().into_owned(), status)
+				},
+				Err(e) => {
+					(e.path().to_string_lossy().into_owned(), Self::missing(Some(format!("{}", e.error()))))
+				},
+			};
+			state.insert(id, status);
+		}
+
+		if !found {
+			state.insert(target.pattern.clone(), Self::missing(None));
+		}
+		Ok(())
+	}
+}
+
+impl PullDataSource for FileMonitor {
+	fn source(&self) -> Arc<Source> {
+		self.source.clone()
+	}
+
+	fn poll(&self) -> Result<Vec<Data>, InternalError> {
+		let mut state = HashMap::new();
+		for target in self.config.paths.iter() {
+			try!(self.check(target, &mut state));
+		}
+		Ok(vec!(Data::State(state)))
+	}
+}
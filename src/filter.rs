@@ -13,10 +13,11 @@ use std::num::ParseIntError;
 use rustc_serialize::json::Json;
 use rustc_serialize::json;
 use util::*;
-use monitor::Severity;
+use monitor::{Severity,Event};
 use config::{Pattern, Match,FilterCommon,JournalFilter};
 
-const PRIORITY : &'static str = "PRIORITY";
+pub const PRIORITY : &'static str = "PRIORITY";
+pub const MESSAGE : &'static str = "MESSAGE";
 
 pub fn get_severity(attrs: &JsonMap) -> Option<Severity> {
 	// it is a logic error to call this on a message _before_ JournalFilter::pre_mutate().
@@ -43,7 +44,7 @@ fn drill<'a>(path: &str, obj: &'a JsonMap) -> Option<&'a str> {
 		}
 }
 
-fn test(s: &str, pat: &Pattern) -> bool {
+pub fn test(s: &str, pat: &Pattern) -> bool {
 	match *pat {
 		Pattern::Glob(ref p) => p.matches(s),
 		Pattern::Regex(ref p) => p.is_match(s),
@@ -51,7 +52,7 @@ fn test(s: &str, pat: &Pattern) -> bool {
 	}
 }
 
-fn test_match(m: &Match, id: &str, attribs: &JsonMap) -> bool {
+pub fn test_match(m: &Match, id: &str, attribs: &JsonMap) -> bool {
 	let subject = match m.attr {
 		Some(ref attr) => drill(attr.deref(), attribs),
 		None => Some(id),
@@ -62,7 +63,7 @@ fn test_match(m: &Match, id: &str, attribs: &JsonMap) -> bool {
 	}
 }
 
-fn matches_common(common: &FilterCommon, id: &str, payload: &JsonMap) -> bool {
+pub fn matches_common(common: &FilterCommon, id: &str, payload: &JsonMap) -> bool {
 	let matches = |m| test_match(m, id, payload);
 	if !common.include.is_empty() {
 		if !common.include.iter().any(&matches) {
@@ -190,3 +191,24 @@ pub fn filter<T:Filter>(id: &str, filters: &Vec<T>, mut payload: JsonMap)
 	}
 	None
 }
+
+// build an Event from a filtered payload
+pub fn event_of_attrs(mut attrs: JsonMap) -> Event {
+	let message = match attrs.remove(MESSAGE) {
+		Some(Json::String(m)) => Some(m),
+		_ => None,
+	};
+
+	let severity = get_severity(&attrs);
+
+	// JSON objects are BTreeMap, but we need a HashMap
+	let mut _attrs = HashMap::new();
+	_attrs.extend(attrs);
+
+	Event {
+		id: None,
+		severity: severity,
+		message: message,
+		attrs: Arc::new(_attrs),
+	}
+}
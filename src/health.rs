@@ -0,0 +1,226 @@
+// Tracks the health of each configured source: when it last sent an
+// update, its last error and whether its thread is still running.
+// A source whose thread isn't running, or which hasn't reported within its
+// `stale_after` window, is stale: its last snapshot is re-sent with every
+// item marked `State::Unknown`. Source health is itself published as the
+// `iysr` source, with an item per source.
+
+use std::collections::{HashMap};
+use std::sync::{Arc};
+use rustc_serialize::json::{Json};
+use chrono::{Duration};
+use monitor::*;
+use config::HealthConfig;
+use system_monitor::StateSnapshot;
+use supervisor::ThreadStatus;
+
+pub const IYSR_TYPE: &'static str = "iysr";
+const IYSR_ID: &'static str = "iysr";
+pub const STALE_ATTR: &'static str = "stale";
+
+// health is re-published at least this often, so that `last_update` stays current
+const PUBLISH_INTERVAL_SECS: i64 = 60;
+
+struct SourceHealth {
+	source: Arc<Source>,
+	stale_after: Option<Duration>,
+	// used in place of `last_update` until the source first reports
+	started: Time,
+	last_update: Option<Time>,
+	last_error: Option<(Time, String)>,
+	// None for sources without their own thread (i.e. polled sources)
+	thread: Option<ThreadStatus>,
+	stale: bool,
+}
+
+impl SourceHealth {
+	fn running(&self) -> bool {
+		match self.thread {
+			None | Some(ThreadStatus::Running) => true,
+			Some(_) => false,
+		}
+	}
+
+	fn state(&self) -> State {
+		if self.thread == Some(ThreadStatus::Down) {
+			State::Error
+		} else if self.stale {
+			State::Unknown
+		} else {
+			State::Active
+		}
+	}
+
+	fn status(&self) -> Status {
+		let mut attrs = HashMap::new();
+		attrs.insert("type".to_string(), Json::String(self.source.typ.to_string()));
+		attrs.insert(STALE_ATTR.to_string(), Json::Boolean(self.stale));
+		match self.thread {
+			Some(ref thread) => {
+				attrs.insert("thread".to_string(), Json::String(thread.name().to_string()));
+				match *thread {
+					ThreadStatus::Restarting(failures) => {
+						attrs.insert("failures".to_string(), Json::U64(failures as u64));
+					},
+					_ => (),
+				}
+			},
+			None => (),
+		}
+		match self.last_update {
+			Some(ref time) => { attrs.insert("last_update".to_string(), Json::I64(time.timestamp())); },
+			None => (),
+		}
+		match self.last_error {
+			Some((ref time, ref error)) => {
+				attrs.insert("last_error".to_string(), Json::String(error.clone()));
+				attrs.insert("last_error_time".to_string(), Json::I64(time.timestamp()));
+			},
+			None => (),
+		}
+		Status {
+			state: self.state(),
+			attrs: Arc::new(attrs),
+		}
+	}
+}
+
+// Replaces every item in a snapshot with `State::Unknown`
+fn mark_stale(update: &Update, time: &Time) -> Option<Arc<Update>> {
+	let state = match update.data {
+		Data::State(ref state) => state,
+		_ => return None,
+	};
+	let state = state.iter().map(|(id, status)| {
+		let mut attrs = (*status.attrs).clone();
+		let last_known = attrs.get("actual_state").cloned()
+			.unwrap_or_else(|| Json::String(format!("{:?}", status.state)));
+		attrs.insert(STALE_ATTR.to_string(), Json::Boolean(true));
+		attrs.insert("last_known_state".to_string(), last_known);
+		(id.clone(), Status {
+			state: State::Unknown,
+			attrs: Arc::new(attrs),
+		})
+	}).collect();
+	Some(Arc::new(Update {
+		source: update.source.clone(),
+		scope: UpdateScope::Snapshot,
+		time: time.clone(),
+		data: Data::State(state),
+	}))
+}
+
+pub struct HealthMonitor {
+	sources: HashMap<String, SourceHealth>,
+	source: Arc<Source>,
+	changed: bool,
+	last_published: Option<Time>,
+}
+
+impl HealthMonitor {
+	pub fn new(config: HealthConfig, sources: Vec<Arc<Source>>) -> HealthMonitor {
+		let now = Time::now();
+		let sources = sources.into_iter().map(|source| {
+			let health = SourceHealth {
+				stale_after: config.stale_after(&source.id),
+				started: now.clone(),
+				last_update: None,
+				last_error: None,
+				thread: None,
+				stale: false,
+				source: source,
+			};
+			(health.source.id.clone(), health)
+		}).collect();
+		HealthMonitor {
+			sources: sources,
+			source: Arc::new(Source::new(IYSR_ID.to_string(), IYSR_TYPE)),
+			changed: true,
+			last_published: None,
+		}
+	}
+
+	// Records an update received from a source
+	pub fn process(&mut self, update: &Update) {
+		let health = match self.sources.get_mut(&update.source.id) {
+			Some(health) => health,
+			None => return,
+		};
+		health.last_update = Some(update.time.clone());
+		match update.data {
+			Data::Error(ref failure) => {
+				health.last_error = Some((update.time.clone(), failure.error.clone()));
+				self.changed = true;
+			},
+			_ => (),
+		}
+		// (failures reported while restarting don't count)
+		if health.stale && health.running() {
+			health.stale = false;
+			self.changed = true;
+		}
+	}
+
+	pub fn set_thread(&mut self, id: &str, thread: ThreadStatus) {
+		match self.sources.get_mut(id) {
+			Some(health) => {
+				if health.thread.as_ref() != Some(&thread) {
+					health.thread = Some(thread);
+					self.changed = true;
+				}
+			},
+			None => (),
+		}
+	}
+
+	// Marks sources which have become stale, returning their (now unknown)
+	// state followed by the current health, if it needs publishing.
+	pub fn tick(&mut self, last_state: &StateSnapshot) -> Vec<Arc<Update>> {
+		let now = Time::now();
+		let mut rv = Vec::new();
+		for health in self.sources.values_mut() {
+			let overdue = match health.stale_after {
+				Some(stale_after) => {
+					let last = health.last_update.as_ref().unwrap_or(&health.started);
+					now.duration_since(last) > stale_after
+				},
+				None => false,
+			};
+			let stale = overdue || !health.running();
+			if stale == health.stale {
+				continue;
+			}
+			health.stale = stale;
+			self.changed = true;
+			if stale {
+				info!("source {} is stale", health.source.id);
+				rv.extend(last_state.get(&health.source.id).and_then(|update| mark_stale(&update, &now)));
+			} else {
+				// the source's items stay unknown until it sends a new snapshot
+				info!("source {} is running again", health.source.id);
+			}
+		}
+
+		let due = self.last_published.as_ref()
+			.map(|last| now.duration_since(last) >= Duration::seconds(PUBLISH_INTERVAL_SECS))
+			.unwrap_or(true);
+		if self.changed || due {
+			self.changed = false;
+			self.last_published = Some(now.clone());
+			rv.push(self.snapshot(now));
+		}
+		rv
+	}
+
+	fn snapshot(&self, time: Time) -> Arc<Update> {
+		let state = self.sources.iter()
+			.map(|(id, health)| (id.clone(), health.status()))
+			.collect();
+		Arc::new(Update {
+			source: self.source.clone(),
+			scope: UpdateScope::Snapshot,
+			time: time,
+			data: Data::State(state),
+		})
+	}
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::process::{Command,Stdio,Child};
 use std::thread;
 use std::char;
@@ -18,7 +18,8 @@ use std::thread::JoinHandle;
 use util::read_all;
 use systemd_common::RuntimeError;
 use config::{JournalConfig};
-use filter::{filter,get_severity};
+use filter::{filter,event_of_attrs};
+use problems::recovered;
 extern crate thread_scoped;
 
 const JOURNAL_TYPE: &'static str = "journal";
@@ -27,6 +28,7 @@ type SharedRef<T> = Arc<Mutex<T>>;
 
 pub struct Journal {
 	config: JournalConfig,
+	source: Arc<Source>,
 }
 
 fn as_string(j: &json::Json) -> Option<String> {
@@ -47,7 +49,8 @@ fn as_int(j: &json::Json) -> Option<i64> {
 impl Journal {
 	pub fn new(config: JournalConfig) -> Result<Journal, InternalError> {
 		// TODO: use backlog
-		Ok(Journal { config: config })
+		let source = Arc::new(Source::new(config.common.id.clone(), JOURNAL_TYPE));
+		Ok(Journal { config: config, source: source })
 	}
 
 	fn spawn() -> Result<Child, InternalError> {
@@ -80,10 +83,12 @@ impl Journal {
 	) -> Result<(), InternalError>
 	{
 		let subscriber = Arc::new(Mutex::new(subscriber));
+		let mut failed = false;
 		loop {
-			match Self::follow_journal(&config, &source, &subscriber) {
+			match Self::follow_journal(&config, &source, &subscriber, failed) {
 				Ok(()) => (),
 				Err(e) => {
+					failed = true;
 					let subscriber = subscriber.lock().unwrap();
 					Self::send_update(&subscriber, Arc::new(Update {
 						data: Data::Error(Failure {
@@ -104,7 +109,9 @@ impl Journal {
 	fn follow_journal(
 		config: &JournalConfig,
 		data_source: &Arc<Source>,
-		subscriber: &Arc<Mutex<SyncSender<Arc<Update>>>>
+		subscriber: &Arc<Mutex<SyncSender<Arc<Update>>>>,
+		// whether the previous attempt failed
+		failed: bool
 	) -> Result<(), InternalError>
 	{
 		let mut child = try!(Self::spawn());
@@ -117,8 +124,18 @@ impl Journal {
 			// also remove need for `unsafe` block
 			let ok_t = thread_scoped::scoped(move|| -> Result<(), InternalError> {
 				let subscriber = subscriber.lock().unwrap();
+				let mut recovering = failed;
 				for line_r in stdout.lines() {
 					let line = try!(line_r);
+					if recovering {
+						recovering = false;
+						Self::send_update(&subscriber, Arc::new(Update {
+							data: recovered("follow", "following journal logs again".to_string()),
+							scope: UpdateScope::Partial,
+							source: data_source.clone(),
+							time: Time::now(),
+						}));
+					}
 					trace!("got journal line: {}", line);
 					let event = match Json::from_str(line.deref()) {
 						Ok(Json::Object(mut attrs)) => {
@@ -141,25 +158,7 @@ impl Journal {
 								}
 							};
 
-							filter(&source, &config.common.filters, attrs).map(|mut attrs| {
-								let message = match attrs.remove("MESSAGE") {
-									Some(Json::String(m)) => Some(m),
-									_ => None,
-								};
-
-								let severity = get_severity(&attrs);
-
-								// JSON objects are BTreeMap, but we need a HashMap
-								let mut _attrs = HashMap::new();
-								_attrs.extend(attrs);
-
-								Event {
-									id: None,
-									severity: severity,
-									message: message,
-									attrs: Arc::new(_attrs),
-								}
-							})
+							filter(&source, &config.common.filters, attrs).map(event_of_attrs)
 						},
 						_ => {
 							Some(Event {
@@ -204,9 +203,54 @@ impl Journal {
 	}
 }
 
+// The most recent `lines` journal entries for a systemd unit,
+// as {time, message} objects (oldest first)
+pub fn recent_unit_entries(unit: &str, user: bool, lines: u32) -> Result<Vec<Json>, InternalError> {
+	let mut command = Command::new("journalctl");
+	if user {
+		command.arg("--user").arg(format!("_SYSTEMD_USER_UNIT={}", unit));
+	} else {
+		command.arg(format!("_SYSTEMD_UNIT={}", unit));
+	}
+	let output = try!(command
+		.arg("--output=json")
+		.arg("--no-pager")
+		.arg(format!("--lines={}", lines))
+		.stdin(Stdio::null())
+		.output());
+	if !output.status.success() {
+		return Err(InternalError::new(format!("journalctl failed: {}",
+			String::from_utf8_lossy(&output.stderr).trim())));
+	}
+
+	let stdout = try!(String::from_utf8(output.stdout));
+	let mut rv = Vec::new();
+	for line in stdout.lines() {
+		let attrs = match Json::from_str(line) {
+			Ok(Json::Object(attrs)) => attrs,
+			_ => continue,
+		};
+		let message = match attrs.get("MESSAGE").and_then(as_string) {
+			Some(message) => message,
+			// binary messages are encoded as an array of bytes
+			None => continue,
+		};
+		let mut entry = BTreeMap::new();
+		// __REALTIME_TIMESTAMP is in usec, and encoded as a string
+		match attrs.get("__REALTIME_TIMESTAMP").and_then(as_string).and_then(|t| t.parse::<u64>().ok()) {
+			Some(t) => { entry.insert("time".to_string(), Json::U64(t / 1000000)); },
+			None => (),
+		}
+		entry.insert("message".to_string(), Json::String(message));
+		rv.push(Json::Object(entry));
+	}
+	Ok(rv)
+}
+
 
 pub struct JournalSubscription {
 	thread: Option<JoinHandle<Result<(), InternalError>>>,
+	liveness: Liveness,
 }
 impl Drop for JournalSubscription {
 	fn drop(&mut self) {
@@ -225,15 +269,23 @@ impl Drop for JournalSubscription {
 }
 
 impl PushSubscription for JournalSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
+}
+
+impl DataSource for Journal {
+	fn source(&self) -> Arc<Source> { self.source.clone() }
 }
 
 impl PushDataSource for Journal {
 	fn subscribe(&self, subscriber: SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
 		let config = self.config.clone();
-		let source = Arc::new(Source::new(config.common.id.clone(), JOURNAL_TYPE));
-		let thread = try!(thread::Builder::new().spawn(move ||
+		let source = self.source.clone();
+		let liveness = Liveness::new();
+		let guard = liveness.guard();
+		let thread = try!(thread::Builder::new().spawn(move || {
+			let _guard = guard;
 			Self::run_thread(config, source, subscriber)
-		));
-		Ok(Box::new(JournalSubscription { thread: Some(thread) }))
+		}));
+		Ok(Box::new(JournalSubscription { thread: Some(thread), liveness: liveness }))
 	}
 }
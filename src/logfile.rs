@@ -0,0 +1,275 @@
+// follows plain-text log files (like `tail -F`), producing
+// journal-style events for each line
+
+use std::collections::{BTreeMap};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead,BufReader,Seek,SeekFrom};
+use std::ops::Deref;
+use std::os::unix::fs::MetadataExt;
+use std::str::FromStr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc};
+use std::thread;
+use std::thread::JoinHandle;
+use rustc_serialize::json::{Json};
+use regex::Regex;
+use monitor::*;
+use super::errors::*;
+use util::JsonMap;
+use config::{LogfileConfig};
+use filter::{filter,event_of_attrs,PRIORITY,MESSAGE};
+use problems::recovered;
+
+const LOGFILE_TYPE: &'static str = "logfile";
+
+pub struct Logfile {
+	config: LogfileConfig,
+	source: Arc<Source>,
+}
+
+struct TailedFile {
+	path: String,
+	reader: Option<BufReader<File>>,
+	inode: u64,
+	pos: u64,
+	// trailing data which hasn't been terminated by a newline yet
+	partial: String,
+	started: bool,
+	error: Option<String>,
+}
+
+impl TailedFile {
+	fn new(path: String) -> TailedFile {
+		TailedFile {
+			path: path,
+			reader: None,
+			inode: 0,
+			pos: 0,
+			partial: String::new(),
+			started: false,
+			error: None,
+		}
+	}
+
+	// Appends any complete lines written since the last call.
+	// Files which exist when we first look at them are followed from the end,
+	// but files created later (i.e. after rotation) are read from the start.
+	fn read_lines(&mut self, lines: &mut Vec<String>) -> io::Result<()> {
+		let initial = !self.started;
+		self.started = true;
+
+		let meta = match fs::metadata(&self.path) {
+			Ok(meta) => meta,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+				// rotated away and not yet replaced; keep reading what's left of the old file
+				return self.drain(lines);
+			},
+			Err(e) => return Err(e),
+		};
+
+		let reopen = match self.reader {
+			None => true,
+			Some(_) => meta.ino() != self.inode,
+		};
+
+		if reopen {
+			if self.reader.is_some() {
+				debug!("{} rotated", self.path);
+				try!(self.drain(lines));
+			}
+			let mut file = try!(File::open(&self.path));
+			self.pos = if initial {
+				try!(file.seek(SeekFrom::End(0)))
+			} else {
+				0
+			};
+			self.inode = meta.ino();
+			self.partial.clear();
+			self.reader = Some(BufReader::new(file));
+		} else if meta.len() < self.pos {
+			debug!("{} truncated", self.path);
+			match self.reader {
+				Some(ref mut reader) => { try!(reader.seek(SeekFrom::Start(0))); },
+				None => (),
+			};
+			self.pos = 0;
+			self.partial.clear();
+		}
+		self.drain(lines)
+	}
+
+	fn drain(&mut self, lines: &mut Vec<String>) -> io::Result<()> {
+		let reader = match self.reader {
+			Some(ref mut reader) => reader,
+			None => return Ok(()),
+		};
+		loop {
+			let mut buf = String::new();
+			let n = try!(reader.read_line(&mut buf));
+			if n == 0 {
+				return Ok(());
+			}
+			self.pos += n as u64;
+			if buf.ends_with('\n') {
+				let mut line = String::new();
+				line.push_str(self.partial.deref());
+				line.push_str(buf.trim_right_matches(|c| c == '\n' || c == '\r'));
+				self.partial.clear();
+				lines.push(line);
+			} else {
+				self.partial.push_str(buf.deref());
+			}
+		}
+	}
+}
+
+fn insert_capture(attrs: &mut JsonMap, name: &str, value: &str) {
+	match name {
+		"severity" => {
+			let priority = i64::from_str(value).ok()
+				.or_else(|| Severity::from_name(value).map(|s| s.to_int()));
+			match priority {
+				Some(p) => { attrs.insert(PRIORITY.to_string(), Json::I64(p)); },
+				None => debug!("Unknown severity: {}", value),
+			}
+		},
+		"message" => {
+			attrs.insert(MESSAGE.to_string(), Json::String(value.to_string()));
+		},
+		other => {
+			attrs.insert(other.to_string(), Json::String(value.to_string()));
+		},
+	}
+}
+
+fn parse_line(path: &str, pattern: &Option<Regex>, line: String) -> JsonMap {
+	let mut attrs = BTreeMap::new();
+	attrs.insert("SOURCE".to_string(), Json::String(path.to_string()));
+	match *pattern {
+		Some(ref pattern) => match pattern.captures(line.deref()) {
+			Some(captures) => {
+				for (name, value) in captures.iter_named() {
+					match value {
+						Some(value) => insert_capture(&mut attrs, name, value),
+						None => (),
+					}
+				}
+			},
+			None => trace!("line did not match pattern: {}", line),
+		},
+		None => (),
+	}
+
+	if !attrs.contains_key(MESSAGE) {
+		attrs.insert(MESSAGE.to_string(), Json::String(line));
+	}
+	attrs
+}
+
+impl Logfile {
+	pub fn new(config: LogfileConfig) -> Logfile {
+		let source = Arc::new(Source::new(config.common.id.clone(), LOGFILE_TYPE));
+		Logfile {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn send_update(subscriber: &SyncSender<Arc<Update>>, source: &Arc<Source>, data: Data) {
+		ignore_error!(subscriber.try_send(Arc::new(Update {
+			scope: UpdateScope::Partial,
+			data: data,
+			source: source.clone(),
+			time: Time::now(),
+		})), "sending event");
+	}
+
+	fn run_thread(
+		config: LogfileConfig,
+		source: Arc<Source>,
+		subscriber: SyncSender<Arc<Update>>
+	) -> Result<(), InternalError>
+	{
+		let mut files: Vec<TailedFile> = config.paths.iter().cloned().map(TailedFile::new).collect();
+		let sleep_ms = config.interval.num_milliseconds() as u32;
+		loop {
+			for file in files.iter_mut() {
+				let mut lines = Vec::new();
+				match file.read_lines(&mut lines) {
+					Ok(()) => {
+						if file.error.take().is_some() {
+							Self::send_update(&subscriber, &source, recovered(
+								format!("tail:{}", file.path).deref(),
+								format!("following {} again", file.path)));
+						}
+					},
+					Err(e) => {
+						let error = format!("failed to follow {}: {}", file.path, e);
+						// only report each distinct error once
+						if file.error.as_ref() != Some(&error) {
+							Self::send_update(&subscriber, &source, Data::Error(Failure {
+								id: Some(format!("tail:{}", file.path)),
+								error: error.clone(),
+							}));
+						}
+						file.error = Some(error);
+					},
+				}
+
+				for line in lines {
+					trace!("got {} line: {}", file.path, line);
+					let attrs = parse_line(file.path.deref(), &config.pattern, line);
+					match filter(file.path.deref(), &config.common.filters, attrs) {
+						Some(attrs) => Self::send_update(&subscriber, &source, Data::Event(event_of_attrs(attrs))),
+						None => trace!("item filtered"),
+					}
+				}
+			}
+			thread::sleep_ms(sleep_ms);
+		}
+	}
+}
+
+pub struct LogfileSubscription {
+	thread: Option<JoinHandle<Result<(), InternalError>>>,
+	liveness: Liveness,
+}
+
+impl Drop for LogfileSubscription {
+	fn drop(&mut self) {
+		match self.thread.take() {
+			None => (),
+			Some(thread) => {
+				match thread.join() {
+					Ok(Ok(())) => (),
+					Err(e) => log_error!(e, "joining thread"),
+					Ok(Err(e)) => log_error!(e, "joining thread"),
+				}
+			}
+		}
+	}
+}
+
+impl PushSubscription for LogfileSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
+}
+
+impl DataSource for Logfile {
+	fn source(&self) -> Arc<Source> { self.source.clone() }
+}
+
+impl PushDataSource for Logfile {
+	fn subscribe(&self, subscriber: SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
+		let config = self.config.clone();
+		let source = self.source.clone();
+		let liveness = Liveness::new();
+		let guard = liveness.guard();
+		let thread = try!(thread::Builder::new().spawn(move || {
+			let _guard = guard;
+			Self::run_thread(config, source, subscriber)
+		}));
+		Ok(Box::new(LogfileSubscription { thread: Some(thread), liveness: liveness }))
+	}
+}
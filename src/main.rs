@@ -24,12 +24,33 @@ mod errors;
 
 mod monitor;
 mod system_monitor;
+mod transitions;
+mod alerts;
+mod silences;
+mod acks;
+mod problems;
+mod health;
+mod supervisor;
+mod store;
+mod report;
+mod cron;
 mod systemd;
 mod systemd_common;
 mod systemd_dbus;
 // mod systemd_subprocess;
 mod service;
 mod journal;
+mod socket;
+mod file;
+mod logfile;
+mod syslog;
+mod prometheus;
+mod decode;
+mod remote;
+mod statsd;
+mod metrics;
+mod api;
+mod agent;
 mod config;
 mod filter;
 mod dbus_common;
@@ -40,6 +61,13 @@ pub use system_monitor::*;
 use errors::InternalError;
 use systemd::*;
 use journal::*;
+use socket::Socket;
+use file::FileMonitor;
+use logfile::Logfile;
+use syslog::Syslog;
+use prometheus::Prometheus;
+use remote::Remote;
+use statsd::Statsd;
 use std::sync::{Arc,Mutex};
 use std::env;
 use std::process;
@@ -47,6 +75,9 @@ use std::io;
 use std::io::Write;
 use std::fs::File;
 use config::{Config,ConfigError, SourceConfig};
+use store::Store;
+use report::ReportQuery;
+use std::collections::HashMap;
 
 fn load_config(filename: String) -> Result<Config, ConfigError> {
 	errln!("Loading config from {}", filename);
@@ -55,13 +86,16 @@ fn load_config(filename: String) -> Result<Config, ConfigError> {
 }
 
 fn run(config: Config) -> Result<(), errors::InternalError> {
-	let pull_sources : Vec<Box<PullDataSource>> = Vec::new();
+	let mut pull_sources : Vec<Box<PullDataSource>> = Vec::new();
 	let mut push_sources : Vec<Box<PushDataSource>> = Vec::new();
+	// for notification actions: systemd source ids, and whether each is for the user instance
+	let mut systemd_sources = HashMap::new();
 
 	for module in config.sources {
 		match module {
 			SourceConfig::Systemd(conf) => {
 				let systemd = SystemdMonitor::new(conf);
+				systemd_sources.insert(systemd.id(), systemd.user());
 				// pull_sources.push(systemd.poller());
 				push_sources.push(systemd.pusher());
 			},
@@ -69,28 +103,103 @@ fn run(config: Config) -> Result<(), errors::InternalError> {
 				let journal = try!(Journal::new(conf));
 				push_sources.push(Box::new(journal));
 			},
+			SourceConfig::Socket(conf) => {
+				pull_sources.push(Box::new(Socket::new(conf)));
+			},
+			SourceConfig::File(conf) => {
+				pull_sources.push(Box::new(FileMonitor::new(conf)));
+			},
+			SourceConfig::Logfile(conf) => {
+				push_sources.push(Box::new(Logfile::new(conf)));
+			},
+			SourceConfig::Syslog(conf) => {
+				push_sources.push(Box::new(Syslog::new(conf)));
+			},
+			SourceConfig::Prometheus(conf) => {
+				pull_sources.push(Box::new(Prometheus::new(conf)));
+			},
+			SourceConfig::Remote(conf) => {
+				push_sources.push(Box::new(Remote::new(conf)));
+			},
+			SourceConfig::Statsd(conf) => {
+				push_sources.push(Box::new(Statsd::new(conf)));
+			},
 		}
 	}
 
+	let store = match config.store {
+		Some(conf) => Some(try!(Store::open(conf))),
+		None => None,
+	};
+
 	// XXX with scoped threads, we could get away with a ref instead of Arc
 	let monitor = Arc::new(Mutex::new(try!(SystemMonitor::new(
 		20000,
 		50,
 		pull_sources,
-		push_sources
+		push_sources,
+		config.flapping,
+		config.health,
+		config.restart,
+		config.alerts,
+		config.silences,
+		store
 	))));
 
+	let api = try!(api::Api::new(&monitor, config.api, config.collector, config.ingest));
+	let agent = config.agent;
+	let notify = config.notify;
+
 	let mut reaper = try!(worker::spawn("reaper".into(), move |t| {
 		let mut services : Vec<worker::Worker<InternalError>> = Vec::new();
 		// TODO: configure which services run
-		services.push(try!(service::main(monitor.clone(), &t)));
-		services.push(try!(dbus_notify::main(monitor.clone(), &t)));
+		services.push(try!(service::main(monitor.clone(), api, &t)));
+		match agent {
+			Some(agent) => services.push(try!(agent::main(monitor.clone(), agent, &t))),
+			None => (),
+		}
+		services.push(try!(dbus_notify::main(monitor.clone(), notify, systemd_sources, &t)));
 		t.await_cancel();
 		Err(InternalError::new("reaper cancelled".into()))
 	}));
 	reaper.wait().map_err(|e| e.into())
 }
 
+// report [--json] [--since TIME] [--until TIME] [--source GLOB] [--unit GLOB]
+fn run_report(config: Config, args: Vec<String>) -> Result<(), errors::InternalError> {
+	let mut json = false;
+	let mut params = HashMap::new();
+	let mut args = args.into_iter();
+	loop {
+		let arg = match args.next() {
+			Some(arg) => arg,
+			None => break,
+		};
+		match arg.as_ref() {
+			"--json" => { json = true; },
+			"--since" | "--until" | "--source" | "--unit" => {
+				let value = try!(args.next().ok_or_else(||
+					InternalError::new(format!("{} requires a value", arg))));
+				params.insert(arg[2..].to_string(), value);
+			},
+			other => return Err(InternalError::new(format!("Unknown report option: {}", other))),
+		}
+	}
+
+	let store = match config.store {
+		Some(conf) => try!(Store::open(conf)),
+		None => return Err(InternalError::new("Reports need a `store` to be configured".to_string())),
+	};
+	let query = try!(ReportQuery::parse(params).map_err(InternalError::new));
+	let reports = try!(report::availability(&store, &query));
+	if json {
+		println!("{}", report::to_json(&reports, &query).pretty());
+	} else {
+		print!("{}", report::to_text(&reports, &query));
+	}
+	Ok(())
+}
+
 
 macro_rules! fail{
 	($($arg:tt)*) => {
@@ -106,10 +215,7 @@ fn main () {
 	let mut stderr = io::stderr();
 	let mut args = env::args().skip(1);
 	let config = args.next().ok_or(ConfigError::new("--config required".to_string()));
-	match args.next() {
-		Some(_) => fail!("Too many arguments"),
-		None => (),
-	};
+	let command: Vec<String> = args.collect();
 
 	let config = match config.and_then(load_config) {
 		Ok(config) => config,
@@ -118,7 +224,12 @@ fn main () {
 		},
 	};
 
-	match run(config) {
+	let result = match command.first().map(|c| c.as_ref()) {
+		None => run(config),
+		Some("report") => run_report(config, command[1..].to_vec()),
+		Some(other) => fail!("Unknown command: {}", other),
+	};
+	match result {
 		Ok(config) => config,
 		// XXX stderr
 		Err(e) => {
@@ -0,0 +1,105 @@
+// Aggregates raw `Metric`s into `ComputedMetric`s over a span of time:
+//  - counters are summed, and reset on each flush
+//  - gauges keep their last value across flushes (so that a
+//    `Difference` applies to the previous value)
+//  - timespans are reduced to their count, mean and max
+//  - sets count the number of distinct values
+
+use std::collections::{BTreeMap,HashSet};
+use chrono;
+use chrono::{DateTime,UTC};
+use monitor::*;
+
+pub struct Aggregator {
+	counters: BTreeMap<String, i64>,
+	gauges: BTreeMap<String, i64>,
+	timespans: BTreeMap<String, Vec<chrono::Duration>>,
+	sets: BTreeMap<String, HashSet<String>>,
+	since: DateTime<UTC>,
+}
+
+impl Aggregator {
+	pub fn new() -> Aggregator {
+		Aggregator {
+			counters: BTreeMap::new(),
+			gauges: BTreeMap::new(),
+			timespans: BTreeMap::new(),
+			sets: BTreeMap::new(),
+			since: UTC::now(),
+		}
+	}
+
+	pub fn add(&mut self, metric: Metric) {
+		let Metric { id, value } = metric;
+		match value {
+			MetricValue::Counter(n) => {
+				*self.counters.entry(id).or_insert(0) += n as i64;
+			},
+			MetricValue::Gauge(GaugeValue::Absolute(n)) => {
+				self.gauges.insert(id, n as i64);
+			},
+			MetricValue::Gauge(GaugeValue::Difference(n)) => {
+				*self.gauges.entry(id).or_insert(0) += n as i64;
+			},
+			MetricValue::Timespan(Duration(d)) => {
+				self.timespans.entry(id).or_insert_with(Vec::new).push(d);
+			},
+			MetricValue::Set(value) => {
+				self.sets.entry(id).or_insert_with(HashSet::new).insert(value);
+			},
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.counters.is_empty() && self.gauges.is_empty() && self.timespans.is_empty() && self.sets.is_empty()
+	}
+
+	// Computes metrics for everything added since the last flush
+	pub fn flush(&mut self) -> Metrics {
+		let now = UTC::now();
+		let span = now - self.since;
+		self.since = now;
+
+		let mut values = Vec::new();
+		for (id, n) in ::std::mem::replace(&mut self.counters, BTreeMap::new()) {
+			values.push(ComputedMetric {
+				id: id,
+				value: ComputedMetricValue::Int(n),
+			});
+		}
+
+		for (id, n) in self.gauges.iter() {
+			values.push(ComputedMetric {
+				id: id.clone(),
+				value: ComputedMetricValue::Int(*n),
+			});
+		}
+
+		for (id, spans) in ::std::mem::replace(&mut self.timespans, BTreeMap::new()) {
+			let count = spans.len() as i32;
+			let total = spans.iter().fold(chrono::Duration::zero(), |acc, d| acc + *d);
+			let max = spans.iter().fold(chrono::Duration::zero(), |acc, d| ::std::cmp::max(acc, *d));
+			values.push(ComputedMetric {
+				id: format!("{}.count", id),
+				value: ComputedMetricValue::Int(count as i64),
+			});
+			values.push(ComputedMetric {
+				id: format!("{}.mean", id),
+				value: ComputedMetricValue::Duration(Duration(total / count)),
+			});
+			values.push(ComputedMetric {
+				id: format!("{}.max", id),
+				value: ComputedMetricValue::Duration(Duration(max)),
+			});
+		}
+
+		for (id, distinct) in ::std::mem::replace(&mut self.sets, BTreeMap::new()) {
+			values.push(ComputedMetric {
+				id: id,
+				value: ComputedMetricValue::Int(distinct.len() as i64),
+			});
+		}
+
+		Metrics::new(values, span)
+	}
+}
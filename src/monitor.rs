@@ -8,19 +8,35 @@ use std::io;
 use std::convert;
 use std::sync::mpsc;
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool,Ordering as AtomicOrdering};
 use std::cmp::Ordering;
 use rustc_serialize::json::{Json};
 use rustc_serialize::{Encodable,Encoder};
-use chrono::{DateTime,UTC};
+use chrono::{DateTime,UTC,TimeZone};
 use chrono::Timelike;
 use super::errors::InternalError;
 
-#[derive(Debug, RustcEncodable, Clone)]
+#[derive(Debug, RustcEncodable, Clone, PartialEq, Eq)]
 pub enum State {
 	Active,
 	Inactive,
 	Error,
 	Unknown,
+	// changing state too often to be meaningful
+	Flapping,
+}
+
+impl State {
+	pub fn from_name(name: &str) -> Option<State> {
+		match name {
+			"Active" => Some(State::Active),
+			"Inactive" => Some(State::Inactive),
+			"Error" => Some(State::Error),
+			"Unknown" => Some(State::Unknown),
+			"Flapping" => Some(State::Flapping),
+			_ => None,
+		}
+	}
 }
 
 #[derive(Debug,Eq,PartialEq,Clone,RustcEncodable)]
@@ -63,6 +79,21 @@ impl Severity {
 		}
 	}
 
+	// lenient parsing of the severity names commonly found in plain-text logs
+	pub fn from_name(name: &str) -> Option<Severity> {
+		match name.to_lowercase().as_ref() {
+			"emerg" | "emergency" | "panic" => Some(Severity::Emergency),
+			"alert" => Some(Severity::Alert),
+			"crit" | "critical" | "fatal" => Some(Severity::Critical),
+			"err" | "error" => Some(Severity::Error),
+			"warn" | "warning" => Some(Severity::Warning),
+			"notice" => Some(Severity::Notice),
+			"info" | "information" => Some(Severity::Info),
+			"debug" | "trace" => Some(Severity::Debug),
+			_ => None,
+		}
+	}
+
 	pub fn to_string(&self) -> String {
 		format!("{:?}", self)
 	}
@@ -106,6 +137,16 @@ pub struct Status {
 	pub attrs: Arc<Attributes>,
 }
 
+impl Status {
+	// The item's underlying state: flapping items keep it in an attribute
+	pub fn actual_state(&self) -> State {
+		match self.attrs.get("actual_state") {
+			Some(&Json::String(ref name)) => State::from_name(name).unwrap_or(self.state.clone()),
+			_ => self.state.clone(),
+		}
+	}
+}
+
 #[derive(Debug,RustcEncodable)]
 pub struct Event {
 	pub id: Option<String>,
@@ -125,6 +166,8 @@ pub enum MetricValue {
 	Counter(i32),
 	Gauge(GaugeValue),
 	Timespan(Duration),
+	// an occurrence of a distinct value, counted once per span
+	Set(String),
 }
 
 #[derive(Debug)]
@@ -151,7 +194,7 @@ impl Encodable for ComputedMetricValue {
 }
 
 #[derive(Debug)]
-pub struct Duration(chrono::Duration);
+pub struct Duration(pub chrono::Duration);
 
 impl Encodable for Duration {
 	fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
@@ -177,6 +220,19 @@ pub struct Metrics {
 	span: Duration,
 }
 
+impl Metrics {
+	pub fn new(values: Vec<ComputedMetric>, span: chrono::Duration) -> Metrics {
+		Metrics {
+			values: values,
+			span: Duration(span),
+		}
+	}
+
+	pub fn values(&self) -> &[ComputedMetric] {
+		&self.values
+	}
+}
+
 pub trait PollMonitor {
 	type T;
 	fn refresh(&self) { return }
@@ -189,6 +245,7 @@ pub enum Data {
 	Event(Event),
 	Metrics(Metrics),
 	Error(Failure),
+	Alert(Alert),
 }
 
 #[derive(Debug,RustcEncodable)]
@@ -199,6 +256,26 @@ pub struct Failure {
 	pub error: String,
 }
 
+#[derive(Debug,RustcEncodable,Clone,PartialEq,Eq)]
+pub enum AlertState {
+	Firing,
+	Resolved,
+}
+
+#[derive(Debug,RustcEncodable)]
+pub struct Alert {
+	// the id of the rule which raised this alert
+	pub id: String,
+	// the state item, event or metric id (if the rule applies to individual items)
+	pub item: Option<String>,
+	pub state: AlertState,
+	pub severity: Severity,
+	pub message: String,
+	// when the condition started to hold
+	pub since: Time,
+	pub attrs: Arc<Attributes>,
+}
+
 impl Encodable for Data {
 	fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
 		s.emit_tuple(2, {|s| {
@@ -213,6 +290,7 @@ impl Encodable for Data {
 				Data::Event(ref x) => emit_pair(s, "Event", x),
 				Data::Metrics(ref x) => emit_pair(s, "Metrics", x),
 				Data::Error(ref x) => emit_pair(s, "Error", x),
+				Data::Alert(ref x) => emit_pair(s, "Alert", x),
 			}
 		}})
 	}
@@ -224,6 +302,15 @@ impl Time {
 	pub fn now() -> Time {
 		Time(UTC::now())
 	}
+	// Fails (rather than panicking) for times chrono can't represent
+	pub fn from_timestamp(sec: i64, ms: u32) -> Result<Time, InternalError> {
+		if ms >= 1000 {
+			return Err(InternalError::new(format!("Invalid milliseconds: {}", ms)));
+		}
+		UTC.timestamp_opt(sec, ms * 1000000).single()
+			.map(Time)
+			.ok_or_else(|| InternalError::new(format!("Invalid timestamp: {}", sec)))
+	}
 	pub fn timestamp(&self) -> i64 {
 		let Time(t) = *self;
 		t.timestamp()
@@ -232,6 +319,10 @@ impl Time {
 		let Time(t) = *self;
 		t.time()
 	}
+	pub fn duration_since(&self, earlier: &Time) -> chrono::Duration {
+		let (&Time(t), &Time(earlier)) = (self, earlier);
+		t - earlier
+	}
 }
 impl fmt::Debug for Time {
 	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -250,7 +341,7 @@ impl Encodable for Time {
 	}
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum UpdateScope {
 	Snapshot, // this update represents the entire latest state
 	          // (will be cached and sent to new subscribers)
@@ -298,16 +389,47 @@ pub trait DataSource: Send + Sync {
 
 pub trait PullDataSource: Send + Sync {
 	fn source(&self) -> Arc<Source>;
-	fn poll(&self) -> Result<Data, InternalError>;
+	// The source's state, optionally followed by partial data (e.g.
+	// metrics), which is sent separately so that it doesn't replace the state
+	fn poll(&self) -> Result<Vec<Data>, InternalError>;
 }
 
 pub trait PushSubscription : Send {
+	// Whether the subscription's thread(s) are still running
+	fn is_alive(&self) -> bool { true }
 }
 
-pub trait PushDataSource: Send + Sync {
+pub trait PushDataSource: DataSource {
 	fn subscribe(&self, mpsc::SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError>;
 }
 
+// Tracks whether a thread is still running. The thread holds a
+// `LivenessGuard`, which clears the flag when it exits (or panics).
+#[derive(Clone)]
+pub struct Liveness(Arc<AtomicBool>);
+
+impl Liveness {
+	pub fn new() -> Liveness {
+		Liveness(Arc::new(AtomicBool::new(true)))
+	}
+
+	pub fn is_alive(&self) -> bool {
+		self.0.load(AtomicOrdering::SeqCst)
+	}
+
+	pub fn guard(&self) -> LivenessGuard {
+		LivenessGuard(self.clone())
+	}
+}
+
+pub struct LivenessGuard(Liveness);
+
+impl Drop for LivenessGuard {
+	fn drop(&mut self) {
+		(self.0).0.store(false, AtomicOrdering::SeqCst);
+	}
+}
+
 pub struct ErrorReporter {
 	source: Arc<Source>,
 }
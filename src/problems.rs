@@ -0,0 +1,174 @@
+// Rolls up recurring failures (those with an id) from each source into
+// a single "problems" state, with an item per (source, failure id). A
+// problem is cleared once its source reports success again: either with
+// a `recovered` event for the failure id (e.g. once a log file can be
+// read again), or with a state snapshot (unless the failure is about an
+// item which is still in error).
+
+use std::collections::{BTreeMap,HashMap};
+use std::sync::{Arc,Mutex};
+use rustc_serialize::json::{Json};
+use monitor::*;
+use acks::{Acks,ACKNOWLEDGED_ATTR};
+
+pub const PROBLEMS_TYPE: &'static str = "problems";
+const PROBLEMS_ID: &'static str = "problems";
+pub const RECOVERED_ATTR: &'static str = "recovered";
+
+// The event for a source to send once the failure `id` has been resolved
+pub fn recovered(id: &str, message: String) -> Data {
+	let mut attrs = HashMap::new();
+	attrs.insert(RECOVERED_ATTR.to_string(), Json::Boolean(true));
+	Data::Event(Event {
+		id: Some(id.to_string()),
+		severity: Some(Severity::Notice),
+		message: Some(message),
+		attrs: Arc::new(attrs),
+	})
+}
+
+struct Problem {
+	error: String,
+	first_seen: Time,
+	last_seen: Time,
+	count: u64,
+}
+
+impl Problem {
+	fn attrs(&self, source: &str, id: &str) -> Attributes {
+		let mut attrs = HashMap::new();
+		attrs.insert("source".to_string(), Json::String(source.to_string()));
+		attrs.insert("id".to_string(), Json::String(id.to_string()));
+		attrs.insert("error".to_string(), Json::String(self.error.clone()));
+		attrs.insert("first_seen".to_string(), Json::I64(self.first_seen.timestamp()));
+		attrs.insert("last_seen".to_string(), Json::I64(self.last_seen.timestamp()));
+		attrs.insert("count".to_string(), Json::U64(self.count));
+		attrs
+	}
+}
+
+pub struct Problems {
+	// keyed by (source id, failure id)
+	problems: Arc<Mutex<BTreeMap<(String, String), Problem>>>,
+	source: Arc<Source>,
+	acks: Acks,
+}
+
+impl Clone for Problems {
+	fn clone(&self) -> Problems {
+		Problems {
+			problems: self.problems.clone(),
+			source: self.source.clone(),
+			acks: self.acks.clone(),
+		}
+	}
+}
+
+impl Problems {
+	pub fn new(acks: Acks) -> Problems {
+		Problems {
+			problems: Arc::new(Mutex::new(BTreeMap::new())),
+			source: Arc::new(Source::new(PROBLEMS_ID.to_string(), PROBLEMS_TYPE)),
+			acks: acks,
+		}
+	}
+
+	pub fn is_problems(update: &Update) -> bool {
+		update.source.typ == PROBLEMS_TYPE && update.source.id == PROBLEMS_ID
+	}
+
+	// Tracks failures from an update sent by a source, returning
+	// a new snapshot of all problems if they have changed.
+	pub fn process(&self, update: &Update) -> Option<Arc<Update>> {
+		if Self::is_problems(update) {
+			return None;
+		}
+		let source = &update.source.id;
+		let changed = {
+			let mut problems = self.problems.lock().unwrap();
+			match update.data {
+				Data::Error(Failure { id: Some(ref id), ref error }) => {
+					let key = (source.clone(), id.clone());
+					let problem = problems.entry(key).or_insert_with(|| Problem {
+						error: error.clone(),
+						first_seen: update.time.clone(),
+						last_seen: update.time.clone(),
+						count: 0,
+					});
+					problem.error = error.clone();
+					problem.last_seen = update.time.clone();
+					problem.count += 1;
+					true
+				},
+				// ephemeral errors aren't rolled up
+				Data::Error(_) => false,
+				_ => {
+					let cleared: Vec<(String, String)> = problems.keys()
+						.filter(|&&(ref s, ref id)| s == source && Self::clears(update, id))
+						.cloned()
+						.collect();
+					for key in cleared.iter() {
+						problems.remove(key);
+						let (ref source, ref id) = *key;
+						// there's nothing left to acknowledge
+						self.acks.remove(source, id);
+					}
+					!cleared.is_empty()
+				},
+			}
+		};
+		if changed {
+			Some(self.snapshot())
+		} else {
+			None
+		}
+	}
+
+	// Whether `update` shows that the failure `id` from its source has been resolved
+	fn clears(update: &Update, id: &str) -> bool {
+		match (&update.scope, &update.data) {
+			(&UpdateScope::Snapshot, &Data::State(ref state)) =>
+				state.get(id).map(|status| status.state != State::Error).unwrap_or(true),
+			(_, &Data::Event(ref event)) =>
+				event.id.as_ref().map(|e| e == id).unwrap_or(false) && event.attrs.contains_key(RECOVERED_ATTR),
+			_ => false,
+		}
+	}
+
+	pub fn snapshot(&self) -> Arc<Update> {
+		let problems = self.problems.lock().unwrap();
+		let mut state = HashMap::with_capacity(problems.len());
+		for (&(ref source, ref id), problem) in problems.iter() {
+			let mut attrs = problem.attrs(source, id);
+			// problems are acknowledged by their own source and id,
+			// so the ack attribute is set here rather than by `Acks`
+			match self.acks.get(source, id) {
+				Some(ack) => { attrs.insert(ACKNOWLEDGED_ATTR.to_string(), ack); },
+				None => (),
+			}
+			state.insert(format!("{}/{}", source, id), Status {
+				state: State::Error,
+				attrs: Arc::new(attrs),
+			});
+		}
+		Arc::new(Update {
+			source: self.source.clone(),
+			scope: UpdateScope::Snapshot,
+			time: Time::now(),
+			data: Data::State(state),
+		})
+	}
+
+	pub fn to_json(&self) -> Json {
+		let problems = self.problems.lock().unwrap();
+		Json::Array(problems.iter().map(|(&(ref source, ref id), problem)| {
+			let mut json = BTreeMap::new();
+			json.extend(problem.attrs(source, id));
+			match self.acks.get(source, id) {
+				Some(ack) => { json.insert(ACKNOWLEDGED_ATTR.to_string(), ack); },
+				None => (),
+			}
+			Json::Object(json)
+		}).collect())
+	}
+}
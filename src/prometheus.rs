@@ -0,0 +1,353 @@
+// scrapes endpoints which expose metrics in the prometheus text format
+
+use std::collections::{HashMap,BTreeMap};
+use std::f64;
+use std::io::Read;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc,Mutex};
+use std::time;
+use std::time::Instant;
+use rustc_serialize::json::{Json};
+use chrono::{Duration};
+use hyper;
+use monitor::*;
+use config::{PrometheusConfig,PrometheusEndpoint,PrometheusRule};
+use filter::test_match;
+use super::errors::*;
+use util::JsonMap;
+
+const PROMETHEUS_TYPE: &'static str = "prometheus";
+const QUANTILE: &'static str = "quantile";
+const BUCKET_BOUND: &'static str = "le";
+
+#[derive(Debug,Clone)]
+struct Sample {
+	name: String,
+	labels: JsonMap,
+	value: f64,
+}
+
+impl Sample {
+	fn id(&self) -> String {
+		if self.labels.is_empty() {
+			return self.name.clone();
+		}
+		let labels: Vec<String> = self.labels.iter().map(|(k, v)| match *v {
+			Json::String(ref v) => format!("{}=\"{}\"", k, v),
+			ref other => format!("{}={}", k, other),
+		}).collect();
+		format!("{}{{{}}}", self.name, labels.join(","))
+	}
+
+	fn label(&self, name: &str) -> Option<&str> {
+		match self.labels.get(name) {
+			Some(&Json::String(ref v)) => Some(v.deref()),
+			_ => None,
+		}
+	}
+}
+
+fn parse_value(s: &str) -> Option<f64> {
+	match s {
+		"+Inf" | "Inf" => Some(f64::INFINITY),
+		"-Inf" => Some(f64::NEG_INFINITY),
+		"NaN" => Some(f64::NAN),
+		other => f64::from_str(other).ok(),
+	}
+}
+
+// parses `{name="value",...}`, returning the remainder of the line
+fn parse_labels<'a>(s: &'a str, labels: &mut JsonMap) -> Result<&'a str, InternalError> {
+	let invalid = || InternalError::new(format!("Invalid labels: {}", s));
+	let mut rest = &s[1..];
+	loop {
+		rest = rest.trim_left_matches(|c| c == ' ' || c == ',');
+		if rest.starts_with('}') {
+			return Ok(&rest[1..]);
+		}
+		let eq = try!(rest.find('=').ok_or_else(&invalid));
+		let name = rest[0..eq].trim();
+		rest = rest[eq+1..].trim_left();
+		if !rest.starts_with('"') {
+			return Err(invalid());
+		}
+		let mut value = String::new();
+		let mut end = None;
+		let mut escaped = false;
+		for (idx, c) in rest[1..].char_indices() {
+			if escaped {
+				value.push(if c == 'n' { '\n' } else { c });
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '"' {
+				end = Some(idx + 2);
+				break;
+			} else {
+				value.push(c);
+			}
+		}
+		let end = try!(end.ok_or_else(&invalid));
+		labels.insert(name.to_string(), Json::String(value));
+		rest = &rest[end..];
+	}
+}
+
+fn parse_sample(line: &str) -> Result<Sample, InternalError> {
+	let name_end = line.find(|c| c == '{' || c == ' ' || c == '\t').unwrap_or(line.len());
+	let name = &line[0..name_end];
+	let mut labels = BTreeMap::new();
+	let mut rest = &line[name_end..];
+	if rest.starts_with('{') {
+		rest = try!(parse_labels(rest, &mut labels));
+	}
+	// the value may be followed by an optional timestamp, which we ignore
+	let value = try!(rest.split_whitespace().next()
+		.and_then(parse_value)
+		.ok_or_else(|| InternalError::new(format!("Invalid sample: {}", line))));
+	Ok(Sample {
+		name: name.to_string(),
+		labels: labels,
+		value: value,
+	})
+}
+
+// returns all samples, along with the declared type of each metric family
+fn parse_exposition(body: &str) -> Result<(Vec<Sample>, HashMap<String,String>), InternalError> {
+	let mut samples = Vec::new();
+	let mut types = HashMap::new();
+	for line in body.lines() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		if line.starts_with('#') {
+			let mut parts = line[1..].split_whitespace();
+			match (parts.next(), parts.next(), parts.next()) {
+				(Some("TYPE"), Some(name), Some(typ)) => {
+					types.insert(name.to_string(), typ.to_string());
+				},
+				_ => (),
+			}
+			continue;
+		}
+		samples.push(try!(parse_sample(line)));
+	}
+	Ok((samples, types))
+}
+
+fn family_type<'a>(types: &'a HashMap<String,String>, name: &str) -> Option<(&'a str, &'a str)> {
+	// histogram & summary samples are suffixed with _bucket, _sum and _count
+	for suffix in ["", "_bucket", "_sum", "_count"].iter() {
+		if name.ends_with(suffix) {
+			let family = &name[0..name.len() - suffix.len()];
+			match types.get(family) {
+				Some(typ) => return Some((typ.deref(), *suffix)),
+				None => (),
+			}
+		}
+	}
+	None
+}
+
+// Estimate a quantile from cumulative histogram buckets, interpolating
+// linearly within the bucket (the same approach as prometheus' `histogram_quantile`)
+fn bucket_quantile(q: f64, buckets: &Vec<(f64, f64)>) -> Option<f64> {
+	let total = match buckets.last() {
+		Some(&(_, count)) => count,
+		None => return None,
+	};
+	if total == 0.0 {
+		return None;
+	}
+	let rank = q * total;
+	let mut prev_bound = 0.0;
+	let mut prev_count = 0.0;
+	for &(bound, count) in buckets.iter() {
+		if count >= rank {
+			if bound.is_infinite() {
+				return Some(prev_bound);
+			}
+			if count == prev_count {
+				return Some(bound);
+			}
+			return Some(prev_bound + (bound - prev_bound) * (rank - prev_count) / (count - prev_count));
+		}
+		prev_bound = bound;
+		prev_count = count;
+	}
+	Some(prev_bound)
+}
+
+fn quantile_label(q: f64) -> Json {
+	Json::String(format!("{}", q))
+}
+
+// Reduce histograms and summaries to the configured quantiles
+fn reduce(samples: Vec<Sample>, types: &HashMap<String,String>, quantiles: &Vec<f64>) -> Vec<Sample> {
+	let mut rv = Vec::new();
+	// keyed by (family, labels without `le`)
+	let mut histograms: BTreeMap<String, (Sample, Vec<(f64, f64)>)> = BTreeMap::new();
+
+	for sample in samples {
+		match family_type(types, sample.name.deref()) {
+			Some(("histogram", "_bucket")) => {
+				let bound = sample.label(BUCKET_BOUND).and_then(parse_value);
+				match bound {
+					Some(bound) => {
+						let mut base = sample.clone();
+						base.name = sample.name[0..sample.name.len() - "_bucket".len()].to_string();
+						base.labels.remove(BUCKET_BOUND);
+						let entry = histograms.entry(base.id()).or_insert_with(|| (base, Vec::new()));
+						entry.1.push((bound, sample.value));
+					},
+					None => debug!("histogram bucket without `le`: {}", sample.id()),
+				}
+			},
+			Some(("summary", "")) => {
+				let keep = match sample.label(QUANTILE).and_then(parse_value) {
+					Some(q) => quantiles.iter().any(|wanted| (wanted - q).abs() < 1e-9),
+					None => true,
+				};
+				if keep {
+					rv.push(sample);
+				}
+			},
+			_ => rv.push(sample),
+		}
+	}
+
+	for (_, (base, mut buckets)) in histograms {
+		buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+		for q in quantiles.iter() {
+			match bucket_quantile(*q, &buckets) {
+				Some(value) => {
+					let mut sample = base.clone();
+					sample.labels.insert(QUANTILE.to_string(), quantile_label(*q));
+					sample.value = value;
+					rv.push(sample);
+				},
+				None => (),
+			}
+		}
+	}
+	rv
+}
+
+fn rule_matches(rule: &PrometheusRule, sample: &Sample) -> bool {
+	rule.matchers.iter().all(|m| test_match(m, sample.name.deref(), &sample.labels))
+}
+
+fn rule_triggered(rule: &PrometheusRule, value: f64) -> bool {
+	rule.equals.map(|v| v == value).unwrap_or(false)
+		|| rule.above.map(|v| value > v).unwrap_or(false)
+		|| rule.below.map(|v| value < v).unwrap_or(false)
+}
+
+pub struct Prometheus {
+	config: PrometheusConfig,
+	source: Arc<Source>,
+	last_scrape: Mutex<Option<Instant>>,
+}
+
+impl Prometheus {
+	pub fn new(config: PrometheusConfig) -> Prometheus {
+		let source = Arc::new(Source::new(config.common.id.clone(), PROMETHEUS_TYPE));
+		Prometheus {
+			config: config,
+			source: source,
+			last_scrape: Mutex::new(None),
+		}
+	}
+
+	fn scrape(&self, endpoint: &PrometheusEndpoint) -> Result<Vec<Sample>, InternalError> {
+		let timeout = time::Duration::from_millis(self.config.timeout.num_milliseconds() as u64);
+		let mut client = hyper::Client::new();
+		client.set_read_timeout(Some(timeout));
+		let mut response = try!(client.get(endpoint.url.deref()).send());
+		if response.status != hyper::status::StatusCode::Ok {
+			return Err(InternalError::new(format!("{} returned {}", endpoint.url, response.status)));
+		}
+		let mut body = String::new();
+		try!(response.read_to_string(&mut body));
+		let (samples, types) = try!(parse_exposition(body.deref()));
+		Ok(reduce(samples, &types, &self.config.quantiles))
+	}
+
+	fn apply_rules(&self, endpoint: &PrometheusEndpoint, samples: &Vec<Sample>, state: &mut HashMap<String, Status>) {
+		for sample in samples.iter() {
+			let rule = match self.config.rules.iter().find(|r| rule_matches(r, sample)) {
+				Some(rule) => rule,
+				None => continue,
+			};
+			let mut attrs = HashMap::new();
+			attrs.insert("value".to_string(), Json::F64(sample.value));
+			attrs.insert("endpoint".to_string(), Json::String(endpoint.id.clone()));
+			let status = if rule_triggered(rule, sample.value) {
+				rule.state.clone()
+			} else {
+				State::Active
+			};
+			state.insert(format!("{}/{}", endpoint.id, sample.id()), Status {
+				state: status,
+				attrs: Arc::new(attrs),
+			});
+		}
+	}
+}
+
+impl PullDataSource for Prometheus {
+	fn source(&self) -> Arc<Source> {
+		self.source.clone()
+	}
+
+	// Samples are sent as `ComputedMetric` floats: the exporter has already
+	// aggregated them (so they don't go through an `Aggregator` as `Metric`s
+	// do), and `MetricValue` only holds integers.
+	fn poll(&self) -> Result<Vec<Data>, InternalError> {
+		let mut state = HashMap::new();
+		let mut values = Vec::new();
+		for endpoint in self.config.endpoints.iter() {
+			let mut attrs = HashMap::new();
+			attrs.insert("url".to_string(), Json::String(endpoint.url.clone()));
+			let scrape_state = match self.scrape(endpoint) {
+				Ok(samples) => {
+					self.apply_rules(endpoint, &samples, &mut state);
+					attrs.insert("samples".to_string(), Json::U64(samples.len() as u64));
+					for sample in samples {
+						values.push(ComputedMetric {
+							id: format!("{}/{}", endpoint.id, sample.id()),
+							value: ComputedMetricValue::Float(sample.value),
+						});
+					}
+					State::Active
+				},
+				Err(e) => {
+					debug!("scraping {} failed: {}", endpoint.url, e);
+					attrs.insert("error".to_string(), Json::String(format!("{}", e)));
+					State::Error
+				},
+			};
+			state.insert(endpoint.id.clone(), Status {
+				state: scrape_state,
+				attrs: Arc::new(attrs),
+			});
+		}
+
+		let now = Instant::now();
+		let span = {
+			let mut last_scrape = try!(self.last_scrape.lock());
+			let span = match *last_scrape {
+				Some(ref last) => {
+					let elapsed = now.duration_since(*last);
+					Duration::milliseconds((elapsed.as_secs() * 1000) as i64 + (elapsed.subsec_nanos() / 1000000) as i64)
+				},
+				None => Duration::zero(),
+			};
+			*last_scrape = Some(now);
+			span
+		};
+		Ok(vec!(Data::State(state), Data::Metrics(Metrics::new(values, span))))
+	}
+}
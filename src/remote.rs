@@ -0,0 +1,264 @@
+// Follows the event stream of another iysr instance, re-emitting its
+// updates with source ids namespaced by host (e.g. `web1/systemd.system`)
+
+use std::collections::{HashMap};
+use std::io;
+use std::io::{BufRead,BufReader};
+use std::ops::Deref;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time;
+use rustc_serialize::json::{Json};
+use chrono::{Duration};
+use hyper;
+use hyper::header::Headers;
+use monitor::*;
+use config::{RemoteConfig};
+use decode::{decode_update,REMOTE_TYPE};
+use transitions::is_transition;
+use super::errors::*;
+use problems::recovered;
+
+const INITIAL_BACKOFF_MS: i64 = 1000;
+
+pub struct Remote {
+	config: RemoteConfig,
+	source: Arc<Source>,
+}
+
+struct RemoteState<'a> {
+	config: &'a RemoteConfig,
+	source: Arc<Source>,
+	subscriber: SyncSender<Arc<Update>>,
+	last_event_id: Option<String>,
+	// whether the last attempt to follow failed
+	failed: bool,
+	// the latest snapshot of each upstream source
+	snapshots: HashMap<String, (Arc<Source>, HashMap<String, Status>)>,
+}
+
+impl<'a> RemoteState<'a> {
+	fn send(&self, update: Update) {
+		ignore_error!(self.subscriber.try_send(Arc::new(update)), "sending remote update");
+	}
+
+	fn report(&self, error: String) {
+		self.send(Update {
+			data: Data::Error(Failure {
+				id: Some("follow".to_string()),
+				error: error,
+			}),
+			scope: UpdateScope::Partial,
+			source: self.source.clone(),
+			time: Time::now(),
+		});
+	}
+
+	fn dispatch(&mut self, data: &str) -> Result<(), InternalError> {
+		let json = try!(Json::from_str(data).map_err(|e|
+			InternalError::new(format!("Invalid JSON from upstream: {}", e))
+		));
+		// the stream wraps each update as {key, overlay, data}
+		let update = match json {
+			Json::Object(mut obj) => obj.remove("data").unwrap_or(Json::Null),
+			other => return Err(InternalError::new(format!("Unexpected message from upstream: {}", other))),
+		};
+		let update = try!(decode_update(update, Some(self.config.host.deref())));
+		// we derive our own transition events from the upstream state
+		if is_transition(&update) {
+			return Ok(());
+		}
+		match update.data {
+			Data::State(ref state) => {
+				self.snapshots.insert(update.source.id.clone(), (update.source.clone(), state.clone()));
+			},
+			_ => (),
+		}
+		self.send(update);
+		Ok(())
+	}
+
+	// The upstream is unreachable, so we no longer know the state of its sources
+	fn mark_unknown(&self) {
+		for &(ref source, ref state) in self.snapshots.values() {
+			let state = state.iter().map(|(id, status)| {
+				let mut attrs = (*status.attrs).clone();
+				attrs.insert("stale".to_string(), Json::Boolean(true));
+				(id.clone(), Status {
+					state: State::Unknown,
+					attrs: Arc::new(attrs),
+				})
+			}).collect();
+			self.send(Update {
+				data: Data::State(state),
+				scope: UpdateScope::Snapshot,
+				source: source.clone(),
+				time: Time::now(),
+			});
+		}
+	}
+
+	// Follows the stream until it fails, setting `received` once any update arrives.
+	fn follow(&mut self, received: &mut bool) -> Result<(), InternalError> {
+		let timeout = time::Duration::from_millis(self.config.timeout.num_milliseconds() as u64);
+		let mut client = hyper::Client::new();
+		client.set_read_timeout(Some(timeout));
+
+		let mut headers = Headers::new();
+		match self.last_event_id {
+			Some(ref id) => headers.set_raw("Last-Event-ID", vec!(id.clone().into_bytes())),
+			None => (),
+		};
+
+		debug!("connecting to {}", self.config.url);
+		let response = try!(client.get(self.config.url.deref()).headers(headers).send());
+		if response.status != hyper::status::StatusCode::Ok {
+			return Err(InternalError::new(format!("{} returned {}", self.config.url, response.status)));
+		}
+		if self.failed {
+			self.failed = false;
+			self.send(Update {
+				data: recovered("follow", format!("following {} again", self.config.url)),
+				scope: UpdateScope::Partial,
+				source: self.source.clone(),
+				time: Time::now(),
+			});
+		}
+
+		let mut pending_id = None;
+		let mut data = String::new();
+		for line in BufReader::new(response).lines() {
+			let line = match line {
+				Ok(line) => line,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+					return Err(InternalError::new(format!("upstream silent for {}s", self.config.timeout.num_seconds())));
+				},
+				Err(e) => return Err(InternalError::from(e)),
+			};
+
+			if line.is_empty() {
+				if !data.is_empty() {
+					try!(self.dispatch(data.deref()));
+					*received = true;
+					data.clear();
+				}
+				match pending_id.take() {
+					Some(id) => { self.last_event_id = Some(id); },
+					None => (),
+				}
+				continue;
+			}
+
+			// lines starting with `:` are keepalives
+			if line.starts_with(':') {
+				continue;
+			}
+
+			let (field, value) = match line.find(':') {
+				Some(idx) => (&line[0..idx], line[idx+1..].trim_left_matches(' ')),
+				None => (line.deref(), ""),
+			};
+			match field {
+				"id" => { pending_id = Some(value.to_string()); },
+				"data" => {
+					if !data.is_empty() {
+						data.push('\n');
+					}
+					data.push_str(value);
+				},
+				"error" => return Err(InternalError::new(format!("upstream error: {}", value))),
+				other => debug!("ignoring unknown SSE field: {}", other),
+			}
+		}
+		Err(InternalError::new("upstream closed the connection".to_string()))
+	}
+}
+
+impl Remote {
+	pub fn new(config: RemoteConfig) -> Remote {
+		let source = Arc::new(Source::new(config.common.id.clone(), REMOTE_TYPE));
+		Remote {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn run_thread(
+		config: RemoteConfig,
+		source: Arc<Source>,
+		subscriber: SyncSender<Arc<Update>>
+	) -> Result<(), InternalError>
+	{
+		let mut state = RemoteState {
+			config: &config,
+			source: source,
+			subscriber: subscriber,
+			last_event_id: None,
+			failed: false,
+			snapshots: HashMap::new(),
+		};
+		let mut backoff = Duration::milliseconds(INITIAL_BACKOFF_MS);
+		loop {
+			let mut received = false;
+			match state.follow(&mut received) {
+				Ok(()) => (),
+				Err(e) => {
+					state.report(format!("failed to follow {}: {}", config.url, e));
+					state.failed = true;
+				},
+			}
+			state.mark_unknown();
+
+			if received {
+				backoff = Duration::milliseconds(INITIAL_BACKOFF_MS);
+			}
+			debug!("reconnecting to {} in {}ms", config.url, backoff.num_milliseconds());
+			thread::sleep_ms(backoff.num_milliseconds() as u32);
+			backoff = ::std::cmp::min(backoff * 2, config.max_backoff);
+		}
+	}
+}
+
+pub struct RemoteSubscription {
+	thread: Option<JoinHandle<Result<(), InternalError>>>,
+	liveness: Liveness,
+}
+
+impl Drop for RemoteSubscription {
+	fn drop(&mut self) {
+		match self.thread.take() {
+			None => (),
+			Some(thread) => {
+				match thread.join() {
+					Ok(Ok(())) => (),
+					Err(e) => log_error!(e, "joining thread"),
+					Ok(Err(e)) => log_error!(e, "joining thread"),
+				}
+			}
+		}
+	}
+}
+
+impl PushSubscription for RemoteSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
+}
+
+impl DataSource for Remote {
+	fn source(&self) -> Arc<Source> { self.source.clone() }
+}
+
+impl PushDataSource for Remote {
+	fn subscribe(&self, subscriber: SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
+		let config = self.config.clone();
+		let source = self.source.clone();
+		let liveness = Liveness::new();
+		let guard = liveness.guard();
+		let thread = try!(thread::Builder::new().spawn(move || {
+			let _guard = guard;
+			Self::run_thread(config, source, subscriber)
+		}));
+		Ok(Box::new(RemoteSubscription { thread: Some(thread), liveness: liveness }))
+	}
+}
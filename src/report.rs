@@ -0,0 +1,435 @@
+// Availability reports, computed from the state snapshots in the store.
+// For each unit (state item) this gives the time it was observed up or
+// down within a window, the number of failures (transitions into
+// `State::Error`) and the mean time to recovery. Time spent in an unknown
+// state (e.g. while its source was stale) isn't counted either way, and
+// neither are gaps in the store longer than `MAX_GAP_SECS`, since iysr
+// probably wasn't running.
+
+use std::collections::{BTreeMap,HashMap};
+use std::ops::Deref;
+use std::sync::{Arc};
+use rustc_serialize::json::{Json};
+use chrono::{NaiveDate,UTC,TimeZone};
+use glob::Pattern;
+use monitor::*;
+use store::{Store,HistoryQuery,SNAPSHOT_REFRESH_SECS};
+use errors::*;
+
+const DEFAULT_WINDOW_SECS: i64 = 30 * 24 * 60 * 60;
+
+// the store re-writes snapshots every `SNAPSHOT_REFRESH_SECS` while
+// running, so a unit's condition isn't assumed to hold for longer
+const MAX_GAP_SECS: i64 = 2 * SNAPSHOT_REFRESH_SECS;
+
+// Parses a unix timestamp, a (UTC) date like `2016-05-01`,
+// or a duration like `30d` (meaning that long ago)
+pub fn parse_time(s: &str, now: i64) -> Result<i64, String> {
+	let invalid = || format!("Invalid time: {}", s);
+	match s.parse::<i64>() {
+		Ok(timestamp) => return Ok(timestamp),
+		Err(_) => (),
+	}
+	match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+		Ok(date) => return Ok(date.and_hms(0, 0, 0).timestamp()),
+		Err(_) => (),
+	}
+	let suffix_loc = try!(s.find(|c: char| !c.is_numeric()).ok_or_else(&invalid));
+	let value: i64 = try!(s[0..suffix_loc].parse().map_err(|_| invalid()));
+	let unit = match &s[suffix_loc..] {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => return Err(invalid()),
+	};
+	value.checked_mul(unit).and_then(|ago| now.checked_sub(ago)).ok_or_else(invalid)
+}
+
+// Times outside chrono's range can't be formatted
+fn check_range(key: &str, time: i64) -> Result<i64, String> {
+	match Time::from_timestamp(time, 0) {
+		Ok(_) => Ok(time),
+		Err(_) => Err(format!("`{}` is out of range", key)),
+	}
+}
+
+pub struct ReportQuery {
+	pub source: Option<Pattern>,
+	pub unit: Option<Pattern>,
+	// in seconds since the epoch
+	pub since: i64,
+	pub until: i64,
+}
+
+impl ReportQuery {
+	// from `source`, `unit`, `since` and `until` parameters. The
+	// window defaults to the last 30 days.
+	pub fn parse(mut params: HashMap<String, String>) -> Result<ReportQuery, String> {
+		let now = Time::now().timestamp();
+		let pattern = |key: &str, value: Option<String>| -> Result<Option<Pattern>, String> {
+			match value {
+				Some(value) => Pattern::new(value.deref()).map(Some).map_err(|e| format!("Invalid `{}`: {}", key, e)),
+				None => Ok(None),
+			}
+		};
+		let source = try!(pattern("source", params.remove("source")));
+		let unit = try!(pattern("unit", params.remove("unit")));
+		let until = match params.remove("until") {
+			Some(until) => try!(parse_time(until.deref(), now).and_then(|t| check_range("until", t))),
+			None => now,
+		};
+		let since = match params.remove("since") {
+			Some(since) => try!(parse_time(since.deref(), now).and_then(|t| check_range("since", t))),
+			None => until - DEFAULT_WINDOW_SECS,
+		};
+		if since >= until {
+			return Err("`since` must be before `until`".to_string());
+		}
+		if !params.is_empty() {
+			let keys: Vec<String> = params.keys().cloned().collect();
+			return Err(format!("Unknown parameter(s): {}", keys.join(", ")));
+		}
+		Ok(ReportQuery {
+			source: source,
+			unit: unit,
+			since: since,
+			until: until,
+		})
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Condition {
+	Up,
+	Down,
+	Unknown,
+}
+
+impl Condition {
+	fn of(status: &Status) -> Condition {
+		match status.actual_state() {
+			State::Active | State::Inactive => Condition::Up,
+			State::Error => Condition::Down,
+			State::Unknown | State::Flapping => Condition::Unknown,
+		}
+	}
+}
+
+pub struct UnitReport {
+	pub source: String,
+	pub unit: String,
+	// seconds in which the unit's state was known
+	pub observed: i64,
+	pub downtime: i64,
+	pub failures: u64,
+	// durations of the outages which ended within the window
+	repairs: Vec<i64>,
+	// unclipped, for measuring repair times
+	current: Option<(Condition, i64)>,
+	down_since: Option<i64>,
+}
+
+impl UnitReport {
+	fn new(source: String, unit: String) -> UnitReport {
+		UnitReport {
+			source: source,
+			unit: unit,
+			observed: 0,
+			downtime: 0,
+			failures: 0,
+			repairs: Vec::new(),
+			current: None,
+			down_since: None,
+		}
+	}
+
+	// Records the unit's condition (None if it's no longer reported) at `time`
+	fn observe(&mut self, condition: Option<Condition>, time: i64, query: &ReportQuery) {
+		match self.current {
+			Some((current, since)) => {
+				let end = ::std::cmp::min(time, since.saturating_add(MAX_GAP_SECS));
+				let duration = ::std::cmp::min(end, query.until) - ::std::cmp::max(since, query.since);
+				if duration > 0 {
+					match current {
+						Condition::Up => self.observed += duration,
+						Condition::Down => {
+							self.observed += duration;
+							self.downtime += duration;
+						},
+						Condition::Unknown => (),
+					}
+				}
+			},
+			None => (),
+		}
+		let in_window = time >= query.since && time <= query.until;
+		let was_down = self.current.map(|(c, _)| c == Condition::Down).unwrap_or(false);
+		match condition {
+			Some(Condition::Down) if !was_down => {
+				// an outage continues through unknown states
+				if self.down_since.is_none() {
+					self.down_since = Some(time);
+					if in_window {
+						self.failures += 1;
+					}
+				}
+			},
+			Some(Condition::Up) => {
+				match self.down_since.take() {
+					Some(since) if in_window => self.repairs.push(time - since),
+					_ => (),
+				}
+			},
+			_ => (),
+		}
+		self.current = condition.map(|c| (c, time));
+	}
+
+	pub fn availability(&self) -> Option<f64> {
+		if self.observed == 0 {
+			None
+		} else {
+			Some(100.0 * (self.observed - self.downtime) as f64 / self.observed as f64)
+		}
+	}
+
+	// mean time to recovery, in seconds
+	pub fn mttr(&self) -> Option<i64> {
+		if self.repairs.is_empty() {
+			None
+		} else {
+			Some(self.repairs.iter().fold(0, |total, d| total + d) / self.repairs.len() as i64)
+		}
+	}
+
+	pub fn to_json(&self) -> Json {
+		let mut rv = BTreeMap::new();
+		rv.insert("source".to_string(), Json::String(self.source.clone()));
+		rv.insert("unit".to_string(), Json::String(self.unit.clone()));
+		rv.insert("observed_secs".to_string(), Json::I64(self.observed));
+		rv.insert("downtime_secs".to_string(), Json::I64(self.downtime));
+		rv.insert("failures".to_string(), Json::U64(self.failures));
+		rv.insert("mttr_secs".to_string(), self.mttr().map(Json::I64).unwrap_or(Json::Null));
+		rv.insert("availability".to_string(), self.availability().map(Json::F64).unwrap_or(Json::Null));
+		Json::Object(rv)
+	}
+}
+
+pub fn availability(store: &Store, query: &ReportQuery) -> Result<Vec<UnitReport>, InternalError> {
+	// earlier snapshots give the state at the start of the window
+	let history = HistoryQuery {
+		source: query.source.clone(),
+		unit: None,
+		severity: None,
+		since: None,
+		until: Some(query.until),
+		limit: ::std::usize::MAX,
+	};
+	let mut units: BTreeMap<(String, String), UnitReport> = BTreeMap::new();
+	try!(store.scan(&history, |update| {
+		let state = match (&update.scope, &update.data) {
+			(&UpdateScope::Snapshot, &Data::State(ref state)) => state,
+			_ => return,
+		};
+		let source = update.source.id.clone();
+		let time = update.time.timestamp();
+		// units missing from a snapshot are no longer being reported
+		for (&(ref s, ref unit), report) in units.iter_mut() {
+			if *s == source && !state.contains_key(unit) {
+				report.observe(None, time, query);
+			}
+		}
+		for (unit, status) in state.iter() {
+			match query.unit {
+				Some(ref pattern) if !pattern.matches(unit.deref()) => continue,
+				_ => (),
+			}
+			units.entry((source.clone(), unit.clone()))
+				.or_insert_with(|| UnitReport::new(source.clone(), unit.clone()))
+				.observe(Some(Condition::of(status)), time, query);
+		}
+	}));
+	Ok(units.into_iter().map(|(_, mut report)| {
+		report.observe(None, query.until, query);
+		report
+	}).collect())
+}
+
+pub fn to_json(reports: &[UnitReport], query: &ReportQuery) -> Json {
+	let mut rv = BTreeMap::new();
+	rv.insert("since".to_string(), Json::I64(query.since));
+	rv.insert("until".to_string(), Json::I64(query.until));
+	rv.insert("units".to_string(), Json::Array(reports.iter().map(UnitReport::to_json).collect()));
+	Json::Object(rv)
+}
+
+pub fn format_duration(secs: i64) -> String {
+	let (days, hours, minutes, secs) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+	if days > 0 {
+		format!("{}d {}h", days, hours)
+	} else if hours > 0 {
+		format!("{}h {}m", hours, minutes)
+	} else if minutes > 0 {
+		format!("{}m {}s", minutes, secs)
+	} else {
+		format!("{}s", secs)
+	}
+}
+
+pub fn to_text(reports: &[UnitReport], query: &ReportQuery) -> String {
+	let format_time = |t: i64| UTC.timestamp(t, 0).format("%Y-%m-%d %H:%M UTC").to_string();
+	let mut rows = vec!(vec!(
+		"SOURCE".to_string(), "UNIT".to_string(), "AVAILABILITY".to_string(),
+		"DOWNTIME".to_string(), "FAILURES".to_string(), "MTTR".to_string(),
+	));
+	for report in reports.iter() {
+		rows.push(vec!(
+			report.source.clone(),
+			report.unit.clone(),
+			report.availability().map(|a| format!("{:.3}%", a)).unwrap_or("-".to_string()),
+			format_duration(report.downtime),
+			format!("{}", report.failures),
+			report.mttr().map(format_duration).unwrap_or("-".to_string()),
+		));
+	}
+	let mut widths = vec!(0; rows[0].len());
+	for row in rows.iter() {
+		for (i, cell) in row.iter().enumerate() {
+			widths[i] = ::std::cmp::max(widths[i], cell.chars().count());
+		}
+	}
+
+	let mut rv = format!("Availability from {} to {}\n\n", format_time(query.since), format_time(query.until));
+	for row in rows.iter() {
+		let cells: Vec<String> = row.iter().enumerate().map(|(i, cell)| {
+			let padding = ::std::iter::repeat(' ').take(widths[i] - cell.chars().count()).collect::<String>();
+			// left-align the names, right-align the numbers
+			if i < 2 { format!("{}{}", cell, padding) } else { format!("{}{}", padding, cell) }
+		}).collect();
+		rv.push_str(cells.join("  ").trim_right());
+		rv.push('\n');
+	}
+	if reports.is_empty() {
+		rv.push_str("(no units observed)\n");
+	}
+	rv
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::sync::Arc;
+	use rustc_serialize::json::Json;
+	use monitor::{State,Status};
+	use super::{Condition,UnitReport,ReportQuery,MAX_GAP_SECS,parse_time};
+
+	fn query(since: i64, until: i64) -> ReportQuery {
+		ReportQuery {
+			source: None,
+			unit: None,
+			since: since,
+			until: until,
+		}
+	}
+
+	fn report(observations: &[(Option<Condition>, i64)], query: &ReportQuery) -> UnitReport {
+		let mut report = UnitReport::new("test".to_string(), "unit".to_string());
+		for &(condition, time) in observations {
+			report.observe(condition, time, query);
+		}
+		report.observe(None, query.until, query);
+		report
+	}
+
+	#[test]
+	fn clips_to_window() {
+		let query = query(1000, 2000);
+		let report = report(&[
+			(Some(Condition::Up), 500),
+			(Some(Condition::Down), 1200),
+			(Some(Condition::Up), 1500),
+		], &query);
+		assert_eq!(report.observed, 1000);
+		assert_eq!(report.downtime, 300);
+		assert_eq!(report.failures, 1);
+		assert_eq!(report.mttr(), Some(300));
+		assert_eq!(report.availability(), Some(70.0));
+	}
+
+	#[test]
+	fn ignores_failures_before_window() {
+		let query = query(1000, 2000);
+		let report = report(&[
+			(Some(Condition::Down), 900),
+			(Some(Condition::Up), 1100),
+		], &query);
+		assert_eq!(report.failures, 0);
+		assert_eq!(report.downtime, 100);
+		// the repair still ended within the window
+		assert_eq!(report.mttr(), Some(200));
+	}
+
+	#[test]
+	fn outage_continues_through_unknown() {
+		let query = query(0, 1000);
+		let report = report(&[
+			(Some(Condition::Down), 0),
+			(Some(Condition::Unknown), 100),
+			(Some(Condition::Down), 200),
+			(Some(Condition::Up), 400),
+		], &query);
+		assert_eq!(report.failures, 1);
+		assert_eq!(report.downtime, 300);
+		assert_eq!(report.observed, 900);
+		assert_eq!(report.mttr(), Some(400));
+	}
+
+	#[test]
+	fn gaps_are_unknown() {
+		let query = query(0, 10 * MAX_GAP_SECS);
+		let report = report(&[
+			(Some(Condition::Down), 0),
+			(Some(Condition::Down), 4 * MAX_GAP_SECS),
+		], &query);
+		assert_eq!(report.observed, 2 * MAX_GAP_SECS);
+		assert_eq!(report.downtime, 2 * MAX_GAP_SECS);
+		assert_eq!(report.failures, 1);
+		assert_eq!(report.mttr(), None);
+	}
+
+	#[test]
+	fn nothing_observed() {
+		let query = query(0, 1000);
+		let report = report(&[(Some(Condition::Unknown), 0)], &query);
+		assert_eq!(report.observed, 0);
+		assert_eq!(report.availability(), None);
+	}
+
+	#[test]
+	fn flapping_uses_actual_state() {
+		let mut attrs = HashMap::new();
+		attrs.insert("actual_state".to_string(), Json::String("Error".to_string()));
+		let status = Status {
+			state: State::Flapping,
+			attrs: Arc::new(attrs),
+		};
+		assert!(Condition::of(&status) == Condition::Down);
+		let status = Status {
+			state: State::Flapping,
+			attrs: Arc::new(HashMap::new()),
+		};
+		assert!(Condition::of(&status) == Condition::Unknown);
+	}
+
+	#[test]
+	fn parses_times() {
+		assert_eq!(parse_time("100", 1000000), Ok(100));
+		assert_eq!(parse_time("2016-05-01", 1000000), Ok(1462060800));
+		assert_eq!(parse_time("2d", 1000000), Ok(1000000 - 2 * 24 * 60 * 60));
+		assert_eq!(parse_time("30m", 1000000), Ok(1000000 - 30 * 60));
+		assert!(parse_time("5x", 1000000).is_err());
+		assert!(parse_time("d", 1000000).is_err());
+		assert!(parse_time("9223372036854775807d", 1000000).is_err());
+	}
+}
@@ -21,9 +21,12 @@ use rustc_serialize::json;
 use rustc_serialize::json::Json;
 use rustc_serialize::{Encodable, Encoder};
 use worker::{Worker,WorkerSelf};
+use api;
+use api::Api;
 
 struct Server {
 	monitor: Arc<Mutex<SystemMonitor>>,
+	api: Api,
 }
 
 fn write_sse_end(dest: &mut io::Write) -> io::Result<()> {
@@ -116,6 +119,18 @@ fn write_sse(dest: &mut io::Write, prefix: &str, data: &str) -> io::Result<()> {
 	_write_sse(dest, prefix, data, true)
 }
 
+fn write_sse_id(dest: &mut io::Write, id: u64) -> io::Result<()> {
+	write!(dest, "id: {}\n", id)
+}
+
+// the id of the last event seen by a reconnecting EventSource
+fn last_event_id(request: &Request) -> Option<u64> {
+	request.headers.get_raw("Last-Event-ID")
+		.and_then(|values| values.first())
+		.and_then(|value| String::from_utf8(value.clone()).ok())
+		.and_then(|value| value.trim().parse().ok())
+}
+
 fn write_sse_keepalive(dest: &mut io::Write) -> io::Result<()> {
 	try!(write!(dest, ":\n"));
 	dest.flush()
@@ -124,9 +139,9 @@ fn write_sse_keepalive(dest: &mut io::Write) -> io::Result<()> {
 impl Server {
 	// 'a: 'stream means "'a outlives 'stream" - i.e. the reference
 	// lives less long than the data it references
-	fn try_handle<'a, 'stream: 'a>(&self, response: &'a mut Response<'stream, Streaming>) -> Result<(), InternalError> {
+	fn try_handle<'a, 'stream: 'a>(&self, last_event_id: Option<u64>, response: &'a mut Response<'stream, Streaming>) -> Result<(), InternalError> {
 		let receiver = {
-			try!(try!(self.monitor.lock()).subscribe())
+			try!(try!(self.monitor.lock()).subscribe_from(last_event_id))
 		};
 
 		let mut writer = WriteSSE {
@@ -163,9 +178,10 @@ impl Server {
 		loop {
 			match try!(combined_data.recv()) {
 				None => try!(writer.keepalive()),
-				Some(data) => {
+				Some((seq, data)) => {
 					let old_state = last_state;
 					last_state = Some(data.clone());
+					try!(write_sse_id(writer.response, seq));
 					try!(writer.emit_json(|s| {
 						s.emit_struct("data", 3, {|s| {
 							fn emit_pair<S:Encoder,V:Encodable>(s: &mut S, k: &'static str, v: &V) -> Result<(),S::Error> {
@@ -202,7 +218,19 @@ impl Server {
 }
 
 impl Handler for Server {
-	fn handle<'a, 'k>(&'a self, _: Request<'a, 'k>, mut response: Response<'a, Fresh>) {
+	fn handle<'a, 'k>(&'a self, request: Request<'a, 'k>, response: Response<'a, Fresh>) {
+		let endpoint = api::route(&request.method, api::request_path(&request).deref());
+		match endpoint {
+			Some(endpoint) => self.api.handle(endpoint, request, response),
+			// everything else gets the event stream
+			None => self.handle_stream(request, response),
+		}
+	}
+}
+
+impl Server {
+	fn handle_stream<'a, 'k>(&'a self, request: Request<'a, 'k>, mut response: Response<'a, Fresh>) {
+		let last_event_id = last_event_id(&request);
 		{
 			use hyper::header::*;
 			use hyper::mime::*;
@@ -219,7 +247,7 @@ impl Handler for Server {
 		match response.start() {
 			Err(e) => debug!("Unable to start response: {}", e),
 			Ok(mut response) => {
-				match self.try_handle(&mut response) {
+				match self.try_handle(last_event_id, &mut response) {
 					Ok(()) => (),
 					Err(e) => {
 						// TODO: don't bother trying to report this exception
@@ -239,11 +267,12 @@ impl Handler for Server {
 	}
 }
 
-pub fn main(monitor: Arc<Mutex<SystemMonitor>>, parent: &WorkerSelf<InternalError>) -> Result<worker::Worker<InternalError>,InternalError> {
-	let server = Server { monitor: monitor };
-	match hyper::Server::http("127.0.0.1:3000").and_then(|s| s.handle(server)) {
+pub fn main(monitor: Arc<Mutex<SystemMonitor>>, api: Api, parent: &WorkerSelf<InternalError>) -> Result<worker::Worker<InternalError>,InternalError> {
+	let listen = api.listen().to_string();
+	let server = Server { monitor: monitor, api: api };
+	match hyper::Server::http(listen.deref()).and_then(|s| s.handle(server)) {
 		Ok(mut server) => {
-			errln!("Starting HTTP server on port 3000");
+			errln!("Starting HTTP server on {}", listen);
 			parent.spawn("http-server".into(), move |t:worker::WorkerSelf<InternalError>| {
 				t.await_cancel();
 				match server.close() {
@@ -0,0 +1,305 @@
+// Silences suppress notifications and alerts for matching items, either
+// during recurring maintenance windows (from the config) or for a fixed
+// period (created at runtime, e.g. over HTTP). Silenced items are still
+// reported, but flagged with a `silenced` attribute naming the silence.
+
+use std::collections::{HashMap,HashSet,BTreeMap};
+use std::ops::Deref;
+use std::sync::{Arc,Mutex};
+use rustc_serialize::json::{Json};
+use chrono;
+use chrono::{DateTime,Local};
+use monitor::*;
+use config::{Match,Pattern,SilenceMatch,SilenceWindow,SilenceRequest};
+use cron::Schedule;
+use filter;
+
+pub const SILENCED_ATTR: &'static str = "silenced";
+
+pub fn is_silenced(attrs: &Attributes) -> bool {
+	attrs.contains_key(SILENCED_ATTR)
+}
+
+// Whether notifiers should ignore this update
+pub fn is_silenced_update(update: &Update) -> bool {
+	match update.data {
+		Data::Event(ref event) => is_silenced(&event.attrs),
+		Data::Alert(ref alert) => is_silenced(&alert.attrs),
+		_ => false,
+	}
+}
+
+fn test_match(m: &Match, id: &str, attrs: &Attributes) -> bool {
+	let subject = match m.attr {
+		Some(ref attr) => match attrs.get(attr) {
+			Some(&Json::String(ref s)) => Some(s.deref()),
+			_ => None,
+		},
+		None => Some(id),
+	};
+	subject.map(|s| filter::test(s, &m.pattern)).unwrap_or(false)
+}
+
+enum Window {
+	Recurring(Schedule, chrono::Duration),
+	// in seconds since the epoch
+	Fixed(i64, i64),
+}
+
+struct Silence {
+	id: String,
+	target: SilenceMatch,
+	window: Window,
+}
+
+impl Silence {
+	fn is_active(&self, now: &DateTime<Local>) -> bool {
+		match self.window {
+			Window::Recurring(ref schedule, duration) => schedule.within(now, duration),
+			Window::Fixed(start, end) => now.timestamp() >= start && now.timestamp() < end,
+		}
+	}
+
+	fn matches(&self, source: &str, id: &str, attrs: &Attributes) -> bool {
+		self.target.source.as_ref().map(|p| p.matches(source)).unwrap_or(true)
+			&& self.target.matchers.iter().all(|m| test_match(m, id, attrs))
+	}
+
+	fn to_json(&self, now: &DateTime<Local>) -> Json {
+		let mut rv = BTreeMap::new();
+		rv.insert("id".to_string(), Json::String(self.id.clone()));
+		rv.insert("active".to_string(), Json::Boolean(self.is_active(now)));
+		match self.target.source {
+			Some(ref source) => { rv.insert("source".to_string(), Json::String(format!("{}", source))); },
+			None => (),
+		}
+		let matchers = self.target.matchers.iter().map(|m| {
+			let mut matcher = BTreeMap::new();
+			match m.attr {
+				Some(ref attr) => { matcher.insert("attr".to_string(), Json::String(attr.clone())); },
+				None => (),
+			}
+			matcher.insert("pattern".to_string(), Json::String(match m.pattern {
+				Pattern::Glob(ref p) => format!("{}", p),
+				Pattern::Regex(ref p) => format!("{}", p),
+				Pattern::Literal(ref p) => p.clone(),
+			}));
+			Json::Object(matcher)
+		}).collect();
+		rv.insert("match".to_string(), Json::Array(matchers));
+		match self.target.comment {
+			Some(ref comment) => { rv.insert("comment".to_string(), Json::String(comment.clone())); },
+			None => (),
+		}
+		match self.window {
+			Window::Recurring(ref schedule, duration) => {
+				rv.insert("schedule".to_string(), Json::String(format!("{}", schedule)));
+				let mut d = BTreeMap::new();
+				d.insert("ms".to_string(), Json::I64(duration.num_milliseconds()));
+				rv.insert("duration".to_string(), Json::Object(d));
+			},
+			Window::Fixed(start, end) => {
+				rv.insert("start".to_string(), Json::I64(start));
+				rv.insert("end".to_string(), Json::I64(end));
+			},
+		}
+		Json::Object(rv)
+	}
+}
+
+struct Inner {
+	silences: Vec<Silence>,
+	next_id: u64,
+	// the silences which were active when last checked
+	active: HashSet<String>,
+}
+
+impl Inner {
+	// Recurring windows are only checked once per tick (by `changed()`),
+	// since matching a schedule means walking back through the window
+	fn active(&self, now: &DateTime<Local>) -> Vec<&Silence> {
+		self.silences.iter().filter(|s| match s.window {
+			Window::Recurring(_, _) => self.active.contains(&s.id),
+			Window::Fixed(_, _) => s.is_active(now),
+		}).collect()
+	}
+}
+
+pub struct Silences {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl Clone for Silences {
+	fn clone(&self) -> Silences {
+		Silences { inner: self.inner.clone() }
+	}
+}
+
+impl Silences {
+	pub fn new(windows: Vec<SilenceWindow>) -> Silences {
+		let silences = windows.into_iter().map(|window| Silence {
+			id: window.id,
+			target: window.target,
+			window: Window::Recurring(window.schedule, window.duration),
+		}).collect();
+		Silences {
+			inner: Arc::new(Mutex::new(Inner {
+				silences: silences,
+				next_id: 0,
+				active: HashSet::new(),
+			})),
+		}
+	}
+
+	// Adds a one-off silence, returning its id
+	pub fn add(&self, request: SilenceRequest) -> Result<String, String> {
+		let start = request.start.unwrap_or_else(|| Local::now().timestamp());
+		let end = match (request.end, request.duration) {
+			(Some(end), _) => end,
+			(None, Some(duration)) => start + duration.num_seconds(),
+			(None, None) => return Err("either `end` or `duration` is required".to_string()),
+		};
+		if end <= start {
+			return Err("silence ends before it starts".to_string());
+		}
+		let mut inner = self.inner.lock().unwrap();
+		inner.next_id += 1;
+		let id = format!("runtime-{}", inner.next_id);
+		inner.silences.push(Silence {
+			id: id.clone(),
+			target: request.target,
+			window: Window::Fixed(start, end),
+		});
+		Ok(id)
+	}
+
+	// Removes a one-off silence. Maintenance windows from the
+	// config can't be removed at runtime.
+	pub fn remove(&self, id: &str) -> bool {
+		let mut inner = self.inner.lock().unwrap();
+		let idx = inner.silences.iter().position(|silence| match silence.window {
+			Window::Fixed(_, _) => silence.id == id,
+			Window::Recurring(_, _) => false,
+		});
+		match idx {
+			Some(idx) => {
+				inner.silences.remove(idx);
+				true
+			},
+			None => false,
+		}
+	}
+
+	pub fn to_json(&self) -> Json {
+		let now = Local::now();
+		let inner = self.inner.lock().unwrap();
+		Json::Array(inner.silences.iter().map(|silence| silence.to_json(&now)).collect())
+	}
+
+	// Expires finished one-off silences, and returns whether the set of
+	// active silences has changed since the last call (in which case
+	// the current state should be re-flagged)
+	pub fn changed(&self) -> bool {
+		let now = Local::now();
+		let mut inner = self.inner.lock().unwrap();
+		inner.silences.retain(|silence| match silence.window {
+			Window::Fixed(_, end) => end > now.timestamp(),
+			Window::Recurring(_, _) => true,
+		});
+		let active: HashSet<String> = inner.silences.iter()
+			.filter(|silence| silence.is_active(&now))
+			.map(|silence| silence.id.clone())
+			.collect();
+		let changed = active != inner.active;
+		inner.active = active;
+		changed
+	}
+
+	// Whether an item (e.g. a metric, which has no attrs to flag) is silenced
+	pub fn is_silenced(&self, source: &str, id: &str) -> bool {
+		let now = Local::now();
+		let inner = self.inner.lock().unwrap();
+		let attrs: Attributes = HashMap::new();
+		let active = inner.active(&now);
+		active.iter().any(|s| s.matches(source, id, &attrs))
+	}
+
+	// Flags any silenced items (or events / alerts) in `update`, and
+	// removes the flag from items which are no longer silenced.
+	pub fn apply(&self, update: Arc<Update>) -> Arc<Update> {
+		let data = {
+			let now = Local::now();
+			let inner = self.inner.lock().unwrap();
+			let active = inner.active(&now);
+			let source = update.source.id.deref();
+			let silence_for = |id: &str, attrs: &Attributes| {
+				active.iter().find(|s| s.matches(source, id, attrs)).map(|s| Json::String(s.id.clone()))
+			};
+			let flag = |attrs: &Arc<Attributes>, silence: Json| {
+				let mut attrs = (**attrs).clone();
+				attrs.insert(SILENCED_ATTR.to_string(), silence);
+				Arc::new(attrs)
+			};
+
+			match update.data {
+				Data::State(ref state) => {
+					let mut changed = false;
+					let mut rv = HashMap::with_capacity(state.len());
+					for (id, status) in state.iter() {
+						let silence = silence_for(id, &status.attrs);
+						if status.attrs.get(SILENCED_ATTR) == silence.as_ref() {
+							rv.insert(id.clone(), status.clone());
+							continue;
+						}
+						changed = true;
+						let attrs = match silence {
+							Some(silence) => flag(&status.attrs, silence),
+							None => {
+								let mut attrs = (*status.attrs).clone();
+								attrs.remove(SILENCED_ATTR);
+								Arc::new(attrs)
+							},
+						};
+						rv.insert(id.clone(), Status {
+							state: status.state.clone(),
+							attrs: attrs,
+						});
+					}
+					if changed { Some(Data::State(rv)) } else { None }
+				},
+				Data::Event(ref event) => {
+					let id = event.id.as_ref().map(|id| id.deref()).unwrap_or("");
+					silence_for(id, &event.attrs).map(|silence| Data::Event(Event {
+						id: event.id.clone(),
+						severity: event.severity.clone(),
+						message: event.message.clone(),
+						attrs: flag(&event.attrs, silence),
+					}))
+				},
+				Data::Alert(ref alert) if !is_silenced(&alert.attrs) => {
+					let id = alert.item.as_ref().map(|id| id.deref()).unwrap_or("");
+					silence_for(id, &alert.attrs).map(|silence| Data::Alert(Alert {
+						id: alert.id.clone(),
+						item: alert.item.clone(),
+						state: alert.state.clone(),
+						severity: alert.severity.clone(),
+						message: alert.message.clone(),
+						since: alert.since.clone(),
+						attrs: flag(&alert.attrs, silence),
+					}))
+				},
+				_ => None,
+			}
+		};
+
+		match data {
+			None => update,
+			Some(data) => Arc::new(Update {
+				source: update.source.clone(),
+				scope: update.scope.clone(),
+				time: update.time.clone(),
+				data: data,
+			}),
+		}
+	}
+}
@@ -0,0 +1,163 @@
+// checks that TCP / unix sockets are accepting connections
+// (and optionally that they respond sensibly to a probe)
+
+use std::collections::{HashMap};
+use std::io;
+use std::io::{Read,Write};
+use std::net::{TcpStream,ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::ops::Deref;
+use std::sync::{Arc};
+use std::time;
+use std::time::Instant;
+use rustc_serialize::json::{Json};
+use chrono::{Duration};
+use monitor::*;
+use config::{SocketConfig,SocketTarget,SocketAddress};
+use super::errors::*;
+
+const SOCKET_TYPE: &'static str = "socket";
+
+// we never need the whole response, just enough to match against `expect`
+const MAX_RESPONSE_BYTES: usize = 4096;
+
+trait Stream: Read + Write {}
+impl Stream for TcpStream {}
+impl Stream for UnixStream {}
+
+pub struct Socket {
+	config: SocketConfig,
+	source: Arc<Source>,
+}
+
+fn std_duration(d: Duration) -> time::Duration {
+	time::Duration::from_millis(d.num_milliseconds() as u64)
+}
+
+fn elapsed_ms(start: &Instant) -> i64 {
+	let elapsed = start.elapsed();
+	(elapsed.as_secs() * 1000) as i64 + (elapsed.subsec_nanos() / 1000000) as i64
+}
+
+fn connect(address: &SocketAddress, timeout: time::Duration) -> io::Result<Box<Stream>> {
+	match *address {
+		SocketAddress::Tcp(ref addr) => {
+			let mut last_err = io::Error::new(io::ErrorKind::Other, format!("No addresses found for {}", addr));
+			for resolved in try!(addr.to_socket_addrs()) {
+				match TcpStream::connect_timeout(&resolved, timeout) {
+					Ok(stream) => {
+						try!(stream.set_read_timeout(Some(timeout)));
+						try!(stream.set_write_timeout(Some(timeout)));
+						return Ok(Box::new(stream));
+					},
+					Err(e) => { last_err = e; },
+				}
+			}
+			Err(last_err)
+		},
+		SocketAddress::Unix(ref path) => {
+			let stream = try!(UnixStream::connect(path));
+			try!(stream.set_read_timeout(Some(timeout)));
+			try!(stream.set_write_timeout(Some(timeout)));
+			Ok(Box::new(stream))
+		},
+	}
+}
+
+fn describe(address: &SocketAddress) -> String {
+	match *address {
+		SocketAddress::Tcp(ref addr) => format!("tcp:{}", addr),
+		SocketAddress::Unix(ref path) => format!("unix:{}", path),
+	}
+}
+
+// Read from the stream until the response matches (or we give up).
+// Returns the response text, and whether it matched.
+fn read_response(stream: &mut Stream, target: &SocketTarget) -> io::Result<(String, bool)> {
+	let mut response = Vec::new();
+	let mut buf = [0u8; 512];
+	loop {
+		let text = String::from_utf8_lossy(&response).into_owned();
+		let matched = match target.expect {
+			Some(ref pattern) => pattern.is_match(text.deref()),
+			None => true,
+		};
+		if matched || response.len() >= MAX_RESPONSE_BYTES {
+			return Ok((text, matched));
+		}
+		let n = match stream.read(&mut buf) {
+			Ok(n) => n,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => 0,
+			Err(e) => return Err(e),
+		};
+		if n == 0 {
+			return Ok((text, false));
+		}
+		response.extend(buf[0..n].iter());
+	}
+}
+
+impl Socket {
+	pub fn new(config: SocketConfig) -> Socket {
+		let source = Arc::new(Source::new(config.common.id.clone(), SOCKET_TYPE));
+		Socket {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn check(&self, target: &SocketTarget) -> Status {
+		let mut attrs = HashMap::new();
+		attrs.insert("address".to_string(), Json::String(describe(&target.address)));
+
+		let start = Instant::now();
+		let result = connect(&target.address, std_duration(self.config.timeout)).and_then(|mut stream| {
+			attrs.insert("latency_ms".to_string(), Json::I64(elapsed_ms(&start)));
+			match target.send {
+				Some(ref probe) => {
+					try!(stream.write_all(probe.as_bytes()));
+					try!(stream.flush());
+				},
+				None => (),
+			};
+			match target.expect {
+				None => Ok(None),
+				Some(_) => read_response(&mut *stream, target).map(Some),
+			}
+		});
+
+		let state = match result {
+			Ok(None) => State::Active,
+			Ok(Some((_, true))) => State::Active,
+			Ok(Some((response, false))) => {
+				attrs.insert("error".to_string(), Json::String("unexpected response".to_string()));
+				attrs.insert("response".to_string(), Json::String(response));
+				State::Error
+			},
+			Err(e) => {
+				debug!("socket check {} failed: {}", target.id, e);
+				attrs.insert("error".to_string(), Json::String(format!("{}", e)));
+				State::Error
+			},
+		};
+
+		Status {
+			state: state,
+			attrs: Arc::new(attrs),
+		}
+	}
+}
+
+impl PullDataSource for Socket {
+	fn source(&self) -> Arc<Source> {
+		self.source.clone()
+	}
+
+	fn poll(&self) -> Result<Vec<Data>, InternalError> {
+		let mut state = HashMap::new();
+		for target in self.config.targets.iter() {
+			state.insert(target.id.clone(), self.check(target));
+		}
+		Ok(vec!(Data::State(state)))
+	}
+}
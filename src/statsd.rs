@@ -0,0 +1,317 @@
+// receives metrics in the StatsD line protocol over UDP, e.g.
+// `requests:1|c|@0.1`, `queue:+3|g`, `render:320|ms`, `users:bob|s`.
+// Metrics are aggregated and sent every `interval`.
+
+use std::io;
+use std::net::{UdpSocket};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time;
+use std::time::Instant;
+use chrono;
+use monitor::*;
+use metrics::Aggregator;
+use config::StatsdConfig;
+use super::errors::*;
+use problems::recovered;
+
+const STATSD_TYPE: &'static str = "statsd";
+const MAX_PACKET_BYTES: usize = 64 * 1024;
+
+pub struct Statsd {
+	config: StatsdConfig,
+	source: Arc<Source>,
+}
+
+fn parse_number(s: &str) -> Result<f64, String> {
+	f64::from_str(s).map_err(|_| format!("invalid value: {}", s))
+}
+
+fn to_i32(n: f64) -> Result<i32, String> {
+	let n = n.round();
+	if n.is_nan() || n > i32::max_value() as f64 || n < i32::min_value() as f64 {
+		return Err(format!("value out of range: {}", n));
+	}
+	Ok(n as i32)
+}
+
+// parses a single `value|type[|@rate]` sample for metric `id`
+fn parse_sample(id: &str, sample: &str) -> Result<Metric, String> {
+	let mut parts = sample.split('|');
+	let value = parts.next().unwrap_or("");
+	let typ = try!(parts.next().ok_or_else(|| format!("missing type: {}", sample)));
+	let mut rate = 1.0;
+	for part in parts {
+		if part.starts_with('@') {
+			rate = try!(parse_number(&part[1..]));
+			if rate <= 0.0 || rate > 1.0 {
+				return Err(format!("invalid sample rate: {}", part));
+			}
+		}
+		// anything else (e.g. dogstatsd `#tags`) is ignored
+	}
+
+	let value = match typ {
+		"c" => MetricValue::Counter(try!(to_i32(try!(parse_number(value)) / rate))),
+		"g" => {
+			let n = try!(to_i32(try!(parse_number(value))));
+			// a leading sign means the gauge is adjusted, rather than set
+			if value.starts_with('+') || value.starts_with('-') {
+				MetricValue::Gauge(GaugeValue::Difference(n))
+			} else {
+				MetricValue::Gauge(GaugeValue::Absolute(n))
+			}
+		},
+		"ms" => {
+			let ms = try!(parse_number(value));
+			if ms < 0.0 {
+				return Err(format!("negative timing: {}", value));
+			}
+			MetricValue::Timespan(Duration(chrono::Duration::microseconds((ms * 1000.0) as i64)))
+		},
+		"s" => MetricValue::Set(value.to_string()),
+		other => return Err(format!("unknown metric type: {}", other)),
+	};
+	Ok(Metric {
+		id: id.to_string(),
+		value: value,
+	})
+}
+
+// parses `id:sample[:sample...]`
+fn parse_line(line: &str) -> Result<Vec<Metric>, String> {
+	let mut parts = line.split(':');
+	let id = parts.next().unwrap_or("").trim();
+	if id.is_empty() {
+		return Err(format!("missing metric name: {}", line));
+	}
+	let mut rv = Vec::new();
+	for sample in parts {
+		rv.push(try!(parse_sample(id, sample.trim())));
+	}
+	if rv.is_empty() {
+		return Err(format!("missing value: {}", line));
+	}
+	Ok(rv)
+}
+
+struct StatsdReceiver {
+	source: Arc<Source>,
+	subscriber: SyncSender<Arc<Update>>,
+	aggregator: Aggregator,
+	invalid: u32,
+	last_invalid: Option<String>,
+	// whether invalid lines were reported at the last flush
+	reported_invalid: bool,
+}
+
+impl StatsdReceiver {
+	fn receive(&mut self, packet: &[u8]) {
+		let text = String::from_utf8_lossy(packet);
+		for line in text.lines() {
+			let line = line.trim();
+			if line.is_empty() {
+				continue;
+			}
+			match parse_line(line) {
+				Ok(metrics) => {
+					for metric in metrics {
+						self.aggregator.add(metric);
+					}
+				},
+				Err(e) => {
+					trace!("invalid statsd line: {}", e);
+					self.invalid += 1;
+					self.last_invalid = Some(e);
+				},
+			}
+		}
+	}
+
+	fn send(&self, data: Data) {
+		ignore_error!(self.subscriber.try_send(Arc::new(Update {
+			data: data,
+			scope: UpdateScope::Partial,
+			source: self.source.clone(),
+			time: Time::now(),
+		})), "sending statsd update");
+	}
+
+	fn flush(&mut self) {
+		if self.invalid > 0 {
+			let error = format!("ignored {} invalid line(s), most recently: {}",
+				self.invalid,
+				self.last_invalid.take().unwrap_or_default());
+			self.invalid = 0;
+			self.send(Data::Error(Failure {
+				id: Some("parse".to_string()),
+				error: error,
+			}));
+			self.reported_invalid = true;
+		} else if self.reported_invalid {
+			self.send(recovered("parse", "no invalid lines since the last interval".to_string()));
+			self.reported_invalid = false;
+		}
+		if !self.aggregator.is_empty() {
+			let metrics = self.aggregator.flush();
+			self.send(Data::Metrics(metrics));
+		}
+	}
+}
+
+impl Statsd {
+	pub fn new(config: StatsdConfig) -> Statsd {
+		let source = Arc::new(Source::new(config.common.id.clone(), STATSD_TYPE));
+		Statsd {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn run_thread(socket: UdpSocket, interval: time::Duration, mut receiver: StatsdReceiver) -> Result<(), InternalError> {
+		let mut buf = vec!(0u8; MAX_PACKET_BYTES);
+		let mut next_flush = Instant::now() + interval;
+		loop {
+			let now = Instant::now();
+			if now >= next_flush {
+				receiver.flush();
+				next_flush = now + interval;
+				continue;
+			}
+			try!(socket.set_read_timeout(Some(next_flush - now)));
+			match socket.recv_from(&mut buf) {
+				Ok((len, _)) => receiver.receive(&buf[0..len]),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+				Err(e) => return Err(InternalError::from(e)),
+			}
+		}
+	}
+}
+
+pub struct StatsdSubscription {
+	thread: Option<JoinHandle<Result<(), InternalError>>>,
+	liveness: Liveness,
+}
+
+impl Drop for StatsdSubscription {
+	fn drop(&mut self) {
+		match self.thread.take() {
+			None => (),
+			Some(thread) => {
+				match thread.join() {
+					Ok(Ok(())) => (),
+					Err(e) => log_error!(e, "joining thread"),
+					Ok(Err(e)) => log_error!(e, "joining thread"),
+				}
+			}
+		}
+	}
+}
+
+impl PushSubscription for StatsdSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
+}
+
+impl DataSource for Statsd {
+	fn source(&self) -> Arc<Source> { self.source.clone() }
+}
+
+impl PushDataSource for Statsd {
+	fn subscribe(&self, subscriber: SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
+		// bind upfront, so that configuration errors are reported immediately
+		let socket = try!(UdpSocket::bind(self.config.address.deref()));
+		let interval = time::Duration::from_millis(self.config.interval.num_milliseconds() as u64);
+		let receiver = StatsdReceiver {
+			source: self.source.clone(),
+			subscriber: subscriber,
+			aggregator: Aggregator::new(),
+			invalid: 0,
+			last_invalid: None,
+			reported_invalid: false,
+		};
+		let liveness = Liveness::new();
+		let guard = liveness.guard();
+		let thread = try!(thread::Builder::new().spawn(move || {
+			let _guard = guard;
+			Self::run_thread(socket, interval, receiver)
+		}));
+		Ok(Box::new(StatsdSubscription { thread: Some(thread), liveness: liveness }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use monitor::*;
+	use super::parse_line;
+
+	fn value(line: &str) -> MetricValue {
+		let mut metrics = parse_line(line).unwrap();
+		assert_eq!(metrics.len(), 1);
+		metrics.pop().unwrap().value
+	}
+
+	#[test]
+	fn parses_counters() {
+		match value("requests:3|c") {
+			MetricValue::Counter(3) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+		// scaled up by the sample rate
+		match value("requests:1|c|@0.1") {
+			MetricValue::Counter(10) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn parses_gauges() {
+		match value("queue:5|g") {
+			MetricValue::Gauge(GaugeValue::Absolute(5)) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+		match value("queue:+3|g") {
+			MetricValue::Gauge(GaugeValue::Difference(3)) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+		match value("queue:-3|g") {
+			MetricValue::Gauge(GaugeValue::Difference(-3)) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn parses_timings_and_sets() {
+		match value("render:320.5|ms") {
+			MetricValue::Timespan(Duration(d)) => assert_eq!(d.num_microseconds(), Some(320500)),
+			other => panic!("unexpected {:?}", other),
+		}
+		match value("users:bob|s|#web") {
+			MetricValue::Set(ref s) if s == "bob" => (),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn parses_multiple_samples() {
+		let metrics = parse_line("requests:1|c:2|c").unwrap();
+		assert_eq!(metrics.len(), 2);
+		assert!(metrics.iter().all(|m| m.id == "requests"));
+	}
+
+	#[test]
+	fn rejects_invalid_lines() {
+		assert!(parse_line(":1|c").is_err());
+		assert!(parse_line("requests").is_err());
+		assert!(parse_line("requests:1").is_err());
+		assert!(parse_line("requests:x|c").is_err());
+		assert!(parse_line("requests:1|x").is_err());
+		assert!(parse_line("requests:1|c|@0").is_err());
+		assert!(parse_line("requests:1|c|@2").is_err());
+		assert!(parse_line("render:-1|ms").is_err());
+		assert!(parse_line("queue:1e20|g").is_err());
+	}
+}
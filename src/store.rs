@@ -0,0 +1,546 @@
+// An append-only log of updates on disk, so that state and events survive
+// restarts. Updates are written as one JSON object per line, to numbered
+// segment files in the store directory (`00000000000000000001.log`, ...).
+// A new segment is started once the current one reaches `segment_size`,
+// and the oldest segments are deleted once they're older than `max_age`
+// or the whole log exceeds `max_size`.
+
+use std::collections::{HashMap,VecDeque};
+use std::fs;
+use std::fs::{File,OpenOptions};
+use std::io::{BufRead,BufReader,Write};
+use std::ops::Deref;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use std::time;
+use std::time::{Instant,SystemTime};
+use rustc_serialize::json;
+use rustc_serialize::json::{Json};
+use glob::Pattern;
+use monitor::*;
+use config::StoreConfig;
+use decode::decode_update;
+use errors::*;
+
+const SEGMENT_SUFFIX: &'static str = ".log";
+
+// age-based retention is also checked this often, in case segments fill slowly
+const RETENTION_CHECK_SECS: u64 = 3600;
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+// unchanged state snapshots are still re-written this often, so that
+// a longer gap in the log means iysr (or the source) wasn't running
+pub const SNAPSHOT_REFRESH_SECS: i64 = 600;
+
+#[derive(Clone)]
+struct Segment {
+	number: u64,
+	path: PathBuf,
+	size: u64,
+}
+
+impl Segment {
+	fn modified(&self) -> Option<SystemTime> {
+		fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+	}
+}
+
+fn segment_number(path: &Path) -> Option<u64> {
+	let name = match path.file_name().and_then(|n| n.to_str()) {
+		Some(name) => name,
+		None => return None,
+	};
+	if !name.ends_with(SEGMENT_SUFFIX) {
+		return None;
+	}
+	name[0..name.len() - SEGMENT_SUFFIX.len()].parse().ok()
+}
+
+// Calls `f` with each update in a segment, in order. Lines which
+// can't be decoded (e.g. one truncated by a crash) are skipped.
+fn read_segment<F>(segment: &Segment, f: &mut F) -> Result<(), InternalError>
+	where F: FnMut(Update)
+{
+	let file = try!(File::open(&segment.path));
+	for line in BufReader::new(file).lines() {
+		let line = try!(line);
+		if line.is_empty() {
+			continue;
+		}
+		let update = Json::from_str(line.deref())
+			.map_err(|e| InternalError::new(format!("{}", e)))
+			.and_then(|json| decode_update(json, None));
+		match update {
+			Ok(update) => f(update),
+			Err(e) => warn!("skipping invalid entry in {}: {}", segment.path.display(), e),
+		}
+	}
+	Ok(())
+}
+
+// The subset of an update matching `unit`, if any
+fn match_unit(update: Update, unit: &str) -> Option<Update> {
+	let data = match update.data {
+		Data::State(state) => {
+			let state: HashMap<String, Status> = state.into_iter().filter(|&(ref id, _)| id == unit).collect();
+			if state.is_empty() {
+				return None;
+			}
+			Data::State(state)
+		},
+		Data::Event(event) => {
+			let matches = event.id.as_ref().map(|id| id == unit).unwrap_or(false)
+				|| event.attrs.get("unit") == Some(&Json::String(unit.to_string()));
+			if !matches {
+				return None;
+			}
+			Data::Event(event)
+		},
+		Data::Alert(alert) => {
+			if alert.item.as_ref().map(|item| item != unit).unwrap_or(true) {
+				return None;
+			}
+			Data::Alert(alert)
+		},
+		Data::Error(failure) => {
+			if failure.id.as_ref().map(|id| id != unit).unwrap_or(true) {
+				return None;
+			}
+			Data::Error(failure)
+		},
+		Data::Metrics(_) => return None,
+	};
+	Some(Update {
+		source: update.source,
+		scope: update.scope,
+		time: update.time,
+		data: data,
+	})
+}
+
+fn severity(data: &Data) -> Option<Severity> {
+	match *data {
+		Data::Event(ref event) => event.severity.clone(),
+		Data::Alert(ref alert) => Some(alert.severity.clone()),
+		Data::Error(_) => Some(Severity::Error),
+		_ => None,
+	}
+}
+
+pub struct HistoryQuery {
+	pub source: Option<Pattern>,
+	// a state item, or the event / alert / failure about it
+	pub unit: Option<String>,
+	// the minimum severity (which excludes state snapshots)
+	pub severity: Option<Severity>,
+	// in seconds since the epoch
+	pub since: Option<i64>,
+	pub until: Option<i64>,
+	// only the most recent `limit` updates are returned
+	pub limit: usize,
+}
+
+impl HistoryQuery {
+	pub fn all() -> HistoryQuery {
+		HistoryQuery {
+			source: None,
+			unit: None,
+			severity: None,
+			since: None,
+			until: None,
+			limit: DEFAULT_HISTORY_LIMIT,
+		}
+	}
+
+	fn apply(&self, update: Update) -> Option<Update> {
+		let time = update.time.timestamp();
+		if self.since.map(|since| time < since).unwrap_or(false)
+			|| self.until.map(|until| time > until).unwrap_or(false) {
+			return None;
+		}
+		match self.source {
+			Some(ref pattern) if !pattern.matches(update.source.id.deref()) => return None,
+			_ => (),
+		}
+		match self.severity {
+			Some(ref min) => match severity(&update.data) {
+				Some(ref severity) if severity >= min => (),
+				_ => return None,
+			},
+			None => (),
+		}
+		match self.unit {
+			Some(ref unit) => match_unit(update, unit.deref()),
+			None => Some(update),
+		}
+	}
+}
+
+struct Inner {
+	config: StoreConfig,
+	// oldest first; the last one is being written to
+	segments: VecDeque<Segment>,
+	file: Option<File>,
+	// the data of the last snapshot written for each source (and when), so
+	// that unchanged snapshots (e.g. from each poll) aren't repeated
+	snapshots: HashMap<String, (String, i64)>,
+	// the most recent snapshot from each source when the store was opened
+	recovered: Vec<Arc<Update>>,
+	last_retention_check: Instant,
+}
+
+impl Inner {
+	fn total_size(&self) -> u64 {
+		self.segments.iter().fold(0, |total, segment| total + segment.size)
+	}
+
+	fn new_segment(&mut self) -> Result<(), InternalError> {
+		let number = self.segments.back().map(|s| s.number + 1).unwrap_or(1);
+		let path = Path::new(self.config.dir.deref()).join(format!("{:020}{}", number, SEGMENT_SUFFIX));
+		debug!("starting store segment {}", path.display());
+		let file = try!(OpenOptions::new().create(true).append(true).open(&path));
+		self.file = Some(file);
+		self.segments.push_back(Segment {
+			number: number,
+			path: path,
+			size: 0,
+		});
+		Ok(())
+	}
+
+	// Deletes the oldest segments (but never the current one) which are past retention
+	fn enforce_retention(&mut self) {
+		self.last_retention_check = Instant::now();
+		let cutoff = self.config.max_age.map(|age|
+			SystemTime::now() - time::Duration::from_secs(age.num_seconds() as u64));
+		while self.segments.len() > 1 {
+			let expired = {
+				let oldest = self.segments.front().unwrap();
+				let too_old = match (cutoff, oldest.modified()) {
+					(Some(cutoff), Some(modified)) => modified < cutoff,
+					_ => false,
+				};
+				let too_big = self.config.max_size.map(|max| self.total_size() > max).unwrap_or(false);
+				too_old || too_big
+			};
+			if !expired {
+				break;
+			}
+			let segment = self.segments.pop_front().unwrap();
+			debug!("removing store segment {}", segment.path.display());
+			ignore_error!(fs::remove_file(&segment.path), "removing store segment");
+		}
+	}
+
+	fn write(&mut self, line: String) -> Result<(), InternalError> {
+		let full = self.segments.back().map(|s| s.size >= self.config.segment_size).unwrap_or(true);
+		if full || self.file.is_none() {
+			try!(self.new_segment());
+			self.enforce_retention();
+		} else if self.last_retention_check.elapsed() >= time::Duration::from_secs(RETENTION_CHECK_SECS) {
+			self.enforce_retention();
+		}
+		{
+			let file = self.file.as_mut().unwrap();
+			try!(file.write_all(line.as_bytes()));
+			try!(file.write_all(b"\n"));
+		}
+		match self.segments.back_mut() {
+			Some(segment) => segment.size += line.len() as u64 + 1,
+			None => (),
+		}
+		Ok(())
+	}
+}
+
+pub struct Store {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl Clone for Store {
+	fn clone(&self) -> Store {
+		Store { inner: self.inner.clone() }
+	}
+}
+
+impl Store {
+	// Opens (or creates) the store, reading back the latest snapshot from each source
+	pub fn open(config: StoreConfig) -> Result<Store, InternalError> {
+		try!(fs::create_dir_all(config.dir.deref()).map_err(|e|
+			InternalError::new(format!("Can't create store directory {}: {}", config.dir, e))));
+		let mut segments = Vec::new();
+		for entry in try!(fs::read_dir(config.dir.deref())) {
+			let path = try!(entry).path();
+			match segment_number(&path) {
+				Some(number) => {
+					let size = try!(fs::metadata(&path)).len();
+					segments.push(Segment { number: number, path: path, size: size });
+				},
+				None => (),
+			}
+		}
+		segments.sort_by(|a, b| a.number.cmp(&b.number));
+
+		let mut latest: HashMap<String, Update> = HashMap::new();
+		for segment in segments.iter() {
+			try!(read_segment(segment, &mut |update: Update| match update.scope {
+				UpdateScope::Snapshot => { latest.insert(update.source.id.clone(), update); },
+				UpdateScope::Partial => (),
+			}));
+		}
+		let mut snapshots = HashMap::new();
+		for (id, update) in latest.iter() {
+			snapshots.insert(id.clone(), (try!(json::encode(&update.data)), update.time.timestamp()));
+		}
+		info!("loaded {} snapshots from {} store segments", latest.len(), segments.len());
+
+		// retention is enforced on the first write, so that opening
+		// the store to read it (e.g. for a report) doesn't modify it
+		let inner = Inner {
+			config: config,
+			segments: segments.into_iter().collect(),
+			// always start a new segment, rather than appending to one which may end in a partial line
+			file: None,
+			snapshots: snapshots,
+			recovered: latest.into_iter().map(|(_, update)| Arc::new(update)).collect(),
+			last_retention_check: Instant::now(),
+		};
+		Ok(Store { inner: Arc::new(Mutex::new(inner)) })
+	}
+
+	// The latest snapshot from each source, as of when the store was opened
+	pub fn recovered(&self) -> Vec<Arc<Update>> {
+		self.inner.lock().unwrap().recovered.clone()
+	}
+
+	// Appends an update to the log. Metrics aren't stored, and snapshots are
+	// skipped if they haven't changed (unless they're due a refresh).
+	pub fn append(&self, update: &Update) -> Result<(), InternalError> {
+		let mut inner = self.inner.lock().unwrap();
+		match (&update.scope, &update.data) {
+			(_, &Data::Metrics(_)) => return Ok(()),
+			(&UpdateScope::Snapshot, data) => {
+				let encoded = try!(json::encode(data));
+				let time = update.time.timestamp();
+				let unchanged = match inner.snapshots.get(&update.source.id) {
+					Some(&(ref last, written)) => *last == encoded && time - written < SNAPSHOT_REFRESH_SECS,
+					None => false,
+				};
+				if unchanged {
+					return Ok(());
+				}
+				inner.snapshots.insert(update.source.id.clone(), (encoded, time));
+			},
+			_ => (),
+		}
+		let line = try!(json::encode(update));
+		inner.write(line)
+	}
+
+	// Re-writes state snapshots which haven't been written for
+	// `SNAPSHOT_REFRESH_SECS`, as of now. Sources which only report
+	// changes would otherwise leave gaps while iysr is running.
+	pub fn refresh(&self, snapshots: Vec<Arc<Update>>) -> Result<(), InternalError> {
+		let now = Time::now();
+		for update in snapshots {
+			let due = {
+				let inner = self.inner.lock().unwrap();
+				match inner.snapshots.get(&update.source.id) {
+					Some(&(_, written)) => now.timestamp() - written >= SNAPSHOT_REFRESH_SECS,
+					None => false,
+				}
+			};
+			let state = match update.data {
+				Data::State(ref state) if due => state.clone(),
+				_ => continue,
+			};
+			try!(self.append(&Update {
+				source: update.source.clone(),
+				scope: UpdateScope::Snapshot,
+				time: now.clone(),
+				data: Data::State(state),
+			}));
+		}
+		Ok(())
+	}
+
+	// Calls `f` with every update matching `query` (ignoring its
+	// `limit`), oldest first, without reading them all into memory
+	pub fn scan<F>(&self, query: &HistoryQuery, mut f: F) -> Result<(), InternalError>
+		where F: FnMut(Update)
+	{
+		// segments are read without holding the lock, so that appends aren't held up
+		let segments: Vec<Segment> = {
+			let inner = self.inner.lock().unwrap();
+			inner.segments.iter().cloned().collect()
+		};
+		let mut matching = |update: Update| match query.apply(update) {
+			Some(update) => f(update),
+			None => (),
+		};
+		for segment in segments.iter() {
+			match read_segment(segment, &mut matching) {
+				Ok(()) => (),
+				// removed by retention in the meantime
+				Err(_) if !segment.path.exists() => (),
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	// The most recent `query.limit` updates matching `query`, oldest first
+	pub fn query(&self, query: &HistoryQuery) -> Result<Vec<Update>, InternalError> {
+		if query.limit == 0 {
+			return Ok(Vec::new());
+		}
+		let mut rv = VecDeque::new();
+		try!(self.scan(query, |update| {
+			if rv.len() >= query.limit {
+				rv.pop_front();
+			}
+			rv.push_back(update);
+		}));
+		Ok(rv.into_iter().collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::env;
+	use std::fs;
+	use std::sync::Arc;
+	use rustc_serialize::json::Json;
+	use glob::Pattern;
+	use monitor::*;
+	use config::StoreConfig;
+	use super::{Store,HistoryQuery,SNAPSHOT_REFRESH_SECS};
+
+	fn update(source: &str, time: i64, scope: UpdateScope, data: Data) -> Update {
+		Update {
+			source: Arc::new(Source::new(source.to_string(), "test")),
+			scope: scope,
+			time: Time::from_timestamp(time, 0).unwrap(),
+			data: data,
+		}
+	}
+
+	fn event(id: &str, severity: Severity, attrs: Attributes) -> Data {
+		Data::Event(Event {
+			id: Some(id.to_string()),
+			severity: Some(severity),
+			message: None,
+			attrs: Arc::new(attrs),
+		})
+	}
+
+	fn state(ids: &[&str]) -> Data {
+		Data::State(ids.iter().map(|id| (id.to_string(), Status {
+			state: State::Active,
+			attrs: Arc::new(HashMap::new()),
+		})).collect())
+	}
+
+	fn open(name: &str) -> Store {
+		let dir = env::temp_dir().join(format!("iysr-store-test-{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		Store::open(StoreConfig {
+			dir: dir.to_str().unwrap().to_string(),
+			max_age: None,
+			max_size: None,
+			segment_size: 1 << 20,
+		}).unwrap()
+	}
+
+	#[test]
+	fn apply_filters_time_and_source() {
+		let query = HistoryQuery {
+			source: Some(Pattern::new("web*").unwrap()),
+			since: Some(100),
+			until: Some(200),
+			.. HistoryQuery::all()
+		};
+		let at = |source: &str, time: i64| update(source, time, UpdateScope::Partial, event("x", Severity::Info, HashMap::new()));
+		assert!(query.apply(at("web1", 100)).is_some());
+		assert!(query.apply(at("web1", 200)).is_some());
+		assert!(query.apply(at("web1", 99)).is_none());
+		assert!(query.apply(at("web1", 201)).is_none());
+		assert!(query.apply(at("db1", 150)).is_none());
+	}
+
+	#[test]
+	fn apply_filters_severity() {
+		let query = HistoryQuery {
+			severity: Some(Severity::Warning),
+			.. HistoryQuery::all()
+		};
+		let at = |data: Data| update("test", 0, UpdateScope::Partial, data);
+		assert!(query.apply(at(event("x", Severity::Error, HashMap::new()))).is_some());
+		assert!(query.apply(at(event("x", Severity::Warning, HashMap::new()))).is_some());
+		assert!(query.apply(at(event("x", Severity::Notice, HashMap::new()))).is_none());
+		assert!(query.apply(at(state(&["x"]))).is_none());
+	}
+
+	#[test]
+	fn match_unit_filters_state() {
+		let query = HistoryQuery {
+			unit: Some("web".to_string()),
+			.. HistoryQuery::all()
+		};
+		let at = |data: Data| update("test", 0, UpdateScope::Snapshot, data);
+		match query.apply(at(state(&["web", "db"]))).map(|u| u.data) {
+			Some(Data::State(ref state)) => {
+				assert_eq!(state.len(), 1);
+				assert!(state.contains_key("web"));
+			},
+			_ => panic!("expected the web item"),
+		}
+		assert!(query.apply(at(state(&["db"]))).is_none());
+	}
+
+	#[test]
+	fn match_unit_filters_events() {
+		let query = HistoryQuery {
+			unit: Some("web".to_string()),
+			.. HistoryQuery::all()
+		};
+		let at = |data: Data| update("test", 0, UpdateScope::Partial, data);
+		let mut attrs = HashMap::new();
+		attrs.insert("unit".to_string(), Json::String("web".to_string()));
+		assert!(query.apply(at(event("web", Severity::Info, HashMap::new()))).is_some());
+		assert!(query.apply(at(event("restart", Severity::Info, attrs))).is_some());
+		assert!(query.apply(at(event("db", Severity::Info, HashMap::new()))).is_none());
+		assert!(query.apply(at(Data::Error(Failure {
+			id: None,
+			error: "failed".to_string(),
+		}))).is_none());
+	}
+
+	#[test]
+	fn query_limit() {
+		let store = open("limit");
+		for time in 1..6 {
+			store.append(&update("test", time, UpdateScope::Partial, event("x", Severity::Info, HashMap::new()))).unwrap();
+		}
+		let times = |limit: usize| -> Vec<i64> {
+			let query = HistoryQuery { limit: limit, .. HistoryQuery::all() };
+			store.query(&query).unwrap().iter().map(|u| u.time.timestamp()).collect()
+		};
+		assert_eq!(times(0), Vec::<i64>::new());
+		assert_eq!(times(2), vec!(4, 5));
+		assert_eq!(times(10), vec!(1, 2, 3, 4, 5));
+	}
+
+	#[test]
+	fn unchanged_snapshots_are_refreshed() {
+		let store = open("refresh");
+		let at = |time: i64| update("test", time, UpdateScope::Snapshot, state(&["web"]));
+		store.append(&at(0)).unwrap();
+		store.append(&at(1)).unwrap();
+		store.append(&at(SNAPSHOT_REFRESH_SECS)).unwrap();
+		let times: Vec<i64> = store.query(&HistoryQuery::all()).unwrap().iter().map(|u| u.time.timestamp()).collect();
+		assert_eq!(times, vec!(0, SNAPSHOT_REFRESH_SECS));
+	}
+}
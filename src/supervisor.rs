@@ -0,0 +1,242 @@
+// Restarts push sources whose threads have exited (e.g. `watch_units`
+// returning after a fatal DBus error), with exponential backoff and
+// jitter. Each restart is reported as a `Failure` from the source.
+// After `max_restarts` consecutive failures, the source is given up on
+// and marked as down.
+
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time;
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
+use monitor::*;
+use config::RestartConfig;
+use errors::*;
+use problems::recovered;
+
+const RESTART_FAILURE_ID: &'static str = "restart";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThreadStatus {
+	Running,
+	// waiting to be restarted, after this many consecutive failures
+	Restarting(u32),
+	Down,
+}
+
+impl ThreadStatus {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			ThreadStatus::Running => "running",
+			ThreadStatus::Restarting(_) => "restarting",
+			ThreadStatus::Down => "down",
+		}
+	}
+}
+
+fn to_std(d: ::chrono::Duration) -> time::Duration {
+	time::Duration::from_millis(::std::cmp::max(d.num_milliseconds(), 0) as u64)
+}
+
+// Picks a delay between half and all of `ms`, so that sources
+// which failed together don't all restart at once
+fn jitter(ms: u64) -> u64 {
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+		.map(|d| d.subsec_nanos() as u64)
+		.unwrap_or(0);
+	ms / 2 + nanos % (ms / 2 + 1)
+}
+
+struct Supervised {
+	source: Box<PushDataSource>,
+	subscription: Option<Box<PushSubscription>>,
+	status: ThreadStatus,
+	// consecutive failures, reset once a subscription has stayed up for `reset_after`
+	failures: u32,
+	started: Instant,
+	restart_at: Instant,
+}
+
+struct Inner {
+	config: RestartConfig,
+	sender: SyncSender<Arc<Update>>,
+	sources: Vec<Supervised>,
+}
+
+impl Inner {
+	fn report(&self, source: &Supervised, error: String) {
+		warn!("{}: {}", source.source.source().id, error);
+		ignore_error!(self.sender.try_send(Arc::new(Update {
+			source: source.source.source(),
+			scope: UpdateScope::Partial,
+			time: Time::now(),
+			data: Data::Error(Failure {
+				id: Some(RESTART_FAILURE_ID.to_string()),
+				error: error,
+			}),
+		})), "sending restart failure");
+	}
+
+	fn report_restarted(&self, source: &Supervised) {
+		ignore_error!(self.sender.try_send(Arc::new(Update {
+			source: source.source.source(),
+			scope: UpdateScope::Partial,
+			time: Time::now(),
+			data: recovered(RESTART_FAILURE_ID, format!("restarted after {} failure(s)", source.failures)),
+		})), "sending restart");
+	}
+
+	fn backoff(&self, failures: u32) -> time::Duration {
+		let initial = self.config.initial_backoff.num_milliseconds() as u64;
+		let max = self.config.max_backoff.num_milliseconds() as u64;
+		let mut ms = ::std::cmp::max(initial, 1);
+		for _ in 1..failures {
+			if ms >= max {
+				break;
+			}
+			ms = ms * 2;
+		}
+		time::Duration::from_millis(jitter(::std::cmp::min(ms, max)))
+	}
+
+	// Schedules a restart after a failure, or gives up on the source
+	fn failed(&self, source: &mut Supervised, reason: String) {
+		source.failures += 1;
+		if self.config.max_restarts > 0 && source.failures > self.config.max_restarts {
+			source.status = ThreadStatus::Down;
+			let error = format!("{}; giving up after {} restarts", reason, self.config.max_restarts);
+			self.report(source, error);
+			return;
+		}
+		let delay = self.backoff(source.failures);
+		source.status = ThreadStatus::Restarting(source.failures);
+		source.restart_at = Instant::now() + delay;
+		let error = format!("{}; restarting in {}s (attempt {})",
+			reason, delay.as_secs(), source.failures);
+		self.report(source, error);
+	}
+}
+
+pub struct Supervisor {
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl Clone for Supervisor {
+	fn clone(&self) -> Supervisor {
+		Supervisor { inner: self.inner.clone() }
+	}
+}
+
+impl Supervisor {
+	pub fn new(config: RestartConfig, sources: Vec<Box<PushDataSource>>, sender: SyncSender<Arc<Update>>) -> Supervisor {
+		let now = Instant::now();
+		Supervisor {
+			inner: Arc::new(Mutex::new(Inner {
+				config: config,
+				sender: sender,
+				sources: sources.into_iter().map(|source| Supervised {
+					source: source,
+					subscription: None,
+					status: ThreadStatus::Running,
+					failures: 0,
+					started: now,
+					restart_at: now,
+				}).collect(),
+			})),
+		}
+	}
+
+	pub fn sources(&self) -> Vec<Arc<Source>> {
+		let inner = self.inner.lock().unwrap();
+		inner.sources.iter().map(|s| s.source.source()).collect()
+	}
+
+	// Subscribes to every source. Failing to start is a configuration
+	// problem rather than a crash, so any error is returned (after
+	// dropping the subscriptions which did start).
+	pub fn start(&self) -> Result<(), InternalError> {
+		let mut inner = self.inner.lock().unwrap();
+		let sender = inner.sender.clone();
+		let mut error = None;
+		for source in inner.sources.iter_mut() {
+			match source.source.subscribe(sender.clone()) {
+				Ok(subscription) => {
+					source.subscription = Some(subscription);
+					source.started = Instant::now();
+				},
+				Err(e) => {
+					error = Some(e);
+					break;
+				},
+			}
+		}
+		match error {
+			None => Ok(()),
+			Some(e) => {
+				for source in inner.sources.iter_mut() {
+					drop(source.subscription.take());
+				}
+				Err(e)
+			},
+		}
+	}
+
+	pub fn stop(&self) {
+		let mut inner = self.inner.lock().unwrap();
+		for source in inner.sources.iter_mut() {
+			drop(source.subscription.take());
+		}
+	}
+
+	// Restarts any sources which have died and are due a restart
+	pub fn check(&self) {
+		let mut inner = self.inner.lock().unwrap();
+		let reset_after = to_std(inner.config.reset_after);
+		let now = Instant::now();
+		// take the sources out, so that `inner` can be borrowed while updating them
+		let mut sources = ::std::mem::replace(&mut inner.sources, Vec::new());
+		for source in sources.iter_mut() {
+			match source.status.clone() {
+				ThreadStatus::Running => {
+					let alive = source.subscription.as_ref().map(|s| s.is_alive());
+					match alive {
+						// not started
+						None => (),
+						Some(true) => {
+							if source.failures > 0 && now.duration_since(source.started) >= reset_after {
+								source.failures = 0;
+							}
+						},
+						Some(false) => {
+							// other threads belonging to this subscription may still be
+							// running (and block joining), so drop it in the background
+							let subscription = source.subscription.take();
+							ignore_error!(thread::Builder::new().spawn(move || drop(subscription)).map(|_| ()),
+								"spawning cleanup thread");
+							inner.failed(source, "source stopped unexpectedly".to_string());
+						},
+					}
+				},
+				ThreadStatus::Restarting(_) if now >= source.restart_at => {
+					info!("restarting source {}", source.source.source().id);
+					match source.source.subscribe(inner.sender.clone()) {
+						Ok(subscription) => {
+							source.subscription = Some(subscription);
+							source.status = ThreadStatus::Running;
+							source.started = now;
+							inner.report_restarted(source);
+						},
+						Err(e) => inner.failed(source, format!("failed to restart source: {}", e)),
+					}
+				},
+				ThreadStatus::Restarting(_) | ThreadStatus::Down => (),
+			}
+		}
+		inner.sources = sources;
+	}
+
+	pub fn statuses(&self) -> Vec<(Arc<Source>, ThreadStatus)> {
+		let inner = self.inner.lock().unwrap();
+		inner.sources.iter().map(|s| (s.source.source(), s.status.clone())).collect()
+	}
+}
@@ -0,0 +1,467 @@
+// receives syslog messages over the network (RFC 5424 and RFC 3164),
+// producing journal-style events for each message
+
+use std::collections::{BTreeMap};
+use std::io;
+use std::io::{BufRead,BufReader,Read};
+use std::net::{UdpSocket,TcpListener,TcpStream};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc};
+use std::thread;
+use std::thread::JoinHandle;
+use rustc_serialize::json::{Json};
+use monitor::*;
+use super::errors::*;
+use util::JsonMap;
+use config::{SyslogConfig,JournalFilter};
+use filter::{filter,event_of_attrs,PRIORITY,MESSAGE};
+
+const SYSLOG_TYPE: &'static str = "syslog";
+const NIL: &'static str = "-";
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+// the most digits an octet-counted frame's length may have
+const MAX_LENGTH_DIGITS: u64 = 10;
+
+pub struct Syslog {
+	config: SyslogConfig,
+	source: Arc<Source>,
+}
+
+#[derive(Clone)]
+struct Emitter {
+	filters: Arc<Vec<JournalFilter>>,
+	source: Arc<Source>,
+	subscriber: SyncSender<Arc<Update>>,
+}
+
+impl Emitter {
+	fn emit(&self, raw: &[u8]) {
+		let text = String::from_utf8_lossy(raw);
+		let text = text.trim_right_matches(|c| c == '\n' || c == '\r' || c == '\0');
+		if text.is_empty() {
+			return;
+		}
+		trace!("got syslog message: {}", text);
+		let attrs = parse_message(text);
+		let id = match attrs.get("SOURCE") {
+			Some(&Json::String(ref s)) => s.clone(),
+			_ => "UNKNOWN".to_string(),
+		};
+		match filter(id.deref(), &self.filters, attrs) {
+			Some(attrs) => {
+				ignore_error!(self.subscriber.try_send(Arc::new(Update {
+					scope: UpdateScope::Partial,
+					data: Data::Event(event_of_attrs(attrs)),
+					source: self.source.clone(),
+					time: Time::now(),
+				})), "sending event");
+			},
+			None => trace!("item filtered"),
+		}
+	}
+
+	fn report(&self, id: &str, error: String) {
+		ignore_error!(self.subscriber.try_send(Arc::new(Update {
+			scope: UpdateScope::Partial,
+			data: Data::Error(Failure {
+				id: Some(id.to_string()),
+				error: error,
+			}),
+			source: self.source.clone(),
+			time: Time::now(),
+		})), "sending error");
+	}
+}
+
+// split off the next space-delimited field
+fn next_field<'a>(s: &'a str) -> (&'a str, &'a str) {
+	match s.find(' ') {
+		Some(idx) => (&s[0..idx], &s[idx+1..]),
+		None => (s, ""),
+	}
+}
+
+fn insert_field(attrs: &mut JsonMap, key: &str, value: &str) {
+	if value != NIL && !value.is_empty() {
+		attrs.insert(key.to_string(), Json::String(value.to_string()));
+	}
+}
+
+// parses `<PRI>`, returning the remainder of the message
+fn parse_pri<'a>(msg: &'a str, attrs: &mut JsonMap) -> &'a str {
+	if !msg.starts_with('<') {
+		return msg;
+	}
+	match msg.find('>') {
+		Some(end) if end <= 4 => {
+			match i64::from_str(&msg[1..end]) {
+				Ok(pri) => {
+					match Severity::from_syslog(pri % 8) {
+						Ok(severity) => { attrs.insert(PRIORITY.to_string(), Json::I64(severity.to_int())); },
+						Err(e) => debug!("{}", e),
+					};
+					attrs.insert("SYSLOG_FACILITY".to_string(), Json::I64(pri / 8));
+					&msg[end+1..]
+				},
+				Err(_) => msg,
+			}
+		},
+		_ => msg,
+	}
+}
+
+// Parses one or more SD-ELEMENTs, inserting each param as `<SD-ID>.<PARAM-NAME>`.
+// Returns the remainder of the message.
+fn parse_structured_data<'a>(msg: &'a str, attrs: &mut JsonMap) -> &'a str {
+	if msg.starts_with(NIL) {
+		return next_field(msg).1;
+	}
+
+	let mut rest = msg;
+	while rest.starts_with('[') {
+		let element = &rest[1..];
+		let id_end = match element.find(|c| c == ' ' || c == ']') {
+			Some(idx) => idx,
+			None => break,
+		};
+		let id = &element[0..id_end];
+		let mut params = &element[id_end..];
+		loop {
+			params = params.trim_left_matches(' ');
+			if params.starts_with(']') {
+				params = &params[1..];
+				break;
+			}
+			let name_end = match params.find("=\"") {
+				Some(idx) => idx,
+				None => return "",
+			};
+			let name = &params[0..name_end];
+			let mut value = String::new();
+			let mut end = None;
+			let mut escaped = false;
+			let value_start = name_end + 2;
+			for (idx, c) in params[value_start..].char_indices() {
+				if escaped {
+					value.push(c);
+					escaped = false;
+				} else if c == '\\' {
+					escaped = true;
+				} else if c == '"' {
+					end = Some(value_start + idx + 1);
+					break;
+				} else {
+					value.push(c);
+				}
+			}
+			match end {
+				Some(end) => {
+					attrs.insert(format!("{}.{}", id, name), Json::String(value));
+					params = &params[end..];
+				},
+				None => return "",
+			}
+		}
+		rest = params;
+	}
+	rest.trim_left_matches(' ')
+}
+
+// VERSION SP TIMESTAMP SP HOSTNAME SP APP-NAME SP PROCID SP MSGID SP STRUCTURED-DATA [SP MSG]
+fn parse_rfc5424(msg: &str, attrs: &mut JsonMap) {
+	let (_version, rest) = next_field(msg);
+	let (timestamp, rest) = next_field(rest);
+	let (hostname, rest) = next_field(rest);
+	let (app_name, rest) = next_field(rest);
+	let (procid, rest) = next_field(rest);
+	let (msgid, rest) = next_field(rest);
+	insert_field(attrs, "SYSLOG_TIMESTAMP", timestamp);
+	insert_field(attrs, "HOSTNAME", hostname);
+	insert_field(attrs, "SYSLOG_IDENTIFIER", app_name);
+	insert_field(attrs, "SYSLOG_PID", procid);
+	insert_field(attrs, "SYSLOG_MSGID", msgid);
+	let message = parse_structured_data(rest, attrs);
+	let message = message.trim_left_matches('\u{feff}');
+	attrs.insert(MESSAGE.to_string(), Json::String(message.to_string()));
+}
+
+// TIMESTAMP SP HOSTNAME SP TAG[PID]: MSG
+// (in practice, senders frequently omit the timestamp and/or hostname)
+fn parse_rfc3164(msg: &str, attrs: &mut JsonMap) {
+	let mut rest = msg;
+	{
+		let bytes = rest.as_bytes();
+		// e.g. "Oct 11 22:14:15 "
+		if bytes.len() > 16 && bytes[3] == b' ' && bytes[9] == b':' && bytes[12] == b':' && bytes[15] == b' ' {
+			insert_field(attrs, "SYSLOG_TIMESTAMP", &rest[0..15]);
+			rest = &rest[16..];
+		}
+	}
+
+	let is_tag = |field: &str| field.ends_with(':') || field.contains('[');
+	let (first, after_first) = next_field(rest);
+	let (tag, message) = if is_tag(first) {
+		(first, after_first)
+	} else {
+		let (second, after_second) = next_field(after_first);
+		if is_tag(second) {
+			insert_field(attrs, "HOSTNAME", first);
+			(second, after_second)
+		} else {
+			("", rest)
+		}
+	};
+
+	let tag = tag.trim_right_matches(':');
+	match tag.find('[') {
+		Some(idx) => {
+			insert_field(attrs, "SYSLOG_IDENTIFIER", &tag[0..idx]);
+			insert_field(attrs, "SYSLOG_PID", tag[idx+1..].trim_right_matches(']'));
+		},
+		None => insert_field(attrs, "SYSLOG_IDENTIFIER", tag),
+	}
+	attrs.insert(MESSAGE.to_string(), Json::String(message.to_string()));
+}
+
+fn parse_message(msg: &str) -> JsonMap {
+	let mut attrs = BTreeMap::new();
+	let rest = parse_pri(msg, &mut attrs);
+
+	// RFC 5424 messages start with a version number
+	let (version, _) = next_field(rest);
+	if !version.is_empty() && version.len() <= 2 && version.chars().all(|c| c.is_digit(10)) {
+		parse_rfc5424(rest, &mut attrs);
+	} else {
+		parse_rfc3164(rest, &mut attrs);
+	}
+
+	let source = match attrs.get("SYSLOG_IDENTIFIER").or_else(|| attrs.get("HOSTNAME")) {
+		Some(&Json::String(ref s)) => Some(s.clone()),
+		_ => None,
+	};
+	match source {
+		Some(s) => { attrs.insert("SOURCE".to_string(), Json::String(s)); },
+		None => (),
+	};
+	attrs
+}
+
+// TCP transport supports both octet-counted (RFC 6587) and newline-delimited framing,
+// detected per-message by whether the frame starts with a digit
+fn read_frames<R: BufRead>(mut reader: R, emitter: &Emitter) -> Result<(), InternalError> {
+	loop {
+		let octet_counted = {
+			let buf = try!(reader.fill_buf());
+			if buf.is_empty() {
+				return Ok(());
+			}
+			(buf[0] as char).is_digit(10)
+		};
+
+		if octet_counted {
+			let mut len = Vec::new();
+			try!((&mut reader).take(MAX_LENGTH_DIGITS + 1).read_until(b' ', &mut len));
+			if len.last() != Some(&b' ') {
+				return Err(InternalError::new(format!("Invalid syslog frame length: {}",
+					String::from_utf8_lossy(&len))));
+			}
+			let len = try!(String::from_utf8(len));
+			let len = try!(usize::from_str(len.trim()).map_err(|_|
+				InternalError::new(format!("Invalid syslog frame length: {}", len))
+			));
+			if len > MAX_MESSAGE_BYTES {
+				return Err(InternalError::new(format!("Syslog frame too large: {}", len)));
+			}
+			let mut msg = vec!(0u8; len);
+			try!(reader.read_exact(&mut msg));
+			emitter.emit(&msg);
+		} else {
+			let mut line = Vec::new();
+			try!((&mut reader).take(MAX_MESSAGE_BYTES as u64).read_until(b'\n', &mut line));
+			emitter.emit(&line);
+		}
+	}
+}
+
+impl Syslog {
+	pub fn new(config: SyslogConfig) -> Syslog {
+		let source = Arc::new(Source::new(config.common.id.clone(), SYSLOG_TYPE));
+		Syslog {
+			config: config,
+			source: source,
+		}
+	}
+
+	fn run_udp(socket: UdpSocket, emitter: Emitter) -> Result<(), InternalError> {
+		let mut buf = vec!(0u8; MAX_MESSAGE_BYTES);
+		loop {
+			let (len, _) = try!(socket.recv_from(&mut buf));
+			emitter.emit(&buf[0..len]);
+		}
+	}
+
+	fn run_tcp(listener: TcpListener, emitter: Emitter) -> Result<(), InternalError> {
+		for stream in listener.incoming() {
+			let stream: TcpStream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					emitter.report("accept", format!("failed to accept syslog connection: {}", e));
+					continue;
+				},
+			};
+			let emitter = emitter.clone();
+			ignore_error!(thread::Builder::new().spawn(move || {
+				let peer = stream.peer_addr().map(|a| format!("{}", a)).unwrap_or("<unknown>".to_string());
+				debug!("accepted syslog connection from {}", peer);
+				match read_frames(BufReader::new(stream), &emitter) {
+					Ok(()) => debug!("syslog connection from {} closed", peer),
+					Err(e) => emitter.report("connection", format!("syslog connection from {} failed: {}", peer, e)),
+				}
+			}).map(|_| ()), "spawning connection thread");
+		}
+		Ok(())
+	}
+}
+
+pub struct SyslogSubscription {
+	threads: Vec<JoinHandle<Result<(), InternalError>>>,
+	// shared by the listener threads, so it's cleared when any of them exits
+	liveness: Liveness,
+}
+
+impl Drop for SyslogSubscription {
+	fn drop(&mut self) {
+		for thread in self.threads.drain(..) {
+			match thread.join() {
+				Ok(Ok(())) => (),
+				Err(e) => log_error!(e, "joining thread"),
+				Ok(Err(e)) => log_error!(e, "joining thread"),
+			}
+		}
+	}
+}
+
+impl PushSubscription for SyslogSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
+}
+
+impl DataSource for Syslog {
+	fn source(&self) -> Arc<Source> { self.source.clone() }
+}
+
+impl PushDataSource for Syslog {
+	fn subscribe(&self, subscriber: SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
+		let emitter = Emitter {
+			filters: Arc::new(self.config.common.filters.clone()),
+			source: self.source.clone(),
+			subscriber: subscriber,
+		};
+
+		// bind sockets upfront, so that configuration errors are reported immediately
+		let udp = match self.config.udp {
+			Some(ref addr) => Some(try!(UdpSocket::bind(addr.deref()))),
+			None => None,
+		};
+		let tcp = match self.config.tcp {
+			Some(ref addr) => Some(try!(TcpListener::bind(addr.deref()))),
+			None => None,
+		};
+
+		let liveness = Liveness::new();
+		let mut threads = Vec::new();
+		match udp {
+			Some(socket) => {
+				let emitter = emitter.clone();
+				let guard = liveness.guard();
+				threads.push(try!(thread::Builder::new().spawn(move || {
+					let _guard = guard;
+					Self::run_udp(socket, emitter)
+				})));
+			},
+			None => (),
+		};
+		match tcp {
+			Some(listener) => {
+				let emitter = emitter.clone();
+				let guard = liveness.guard();
+				threads.push(try!(thread::Builder::new().spawn(move || {
+					let _guard = guard;
+					Self::run_tcp(listener, emitter)
+				})));
+			},
+			None => (),
+		};
+		Ok(Box::new(SyslogSubscription { threads: threads, liveness: liveness }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rustc_serialize::json::Json;
+	use monitor::Severity;
+	use util::JsonMap;
+	use filter::{PRIORITY,MESSAGE};
+	use super::parse_message;
+
+	fn string<'a>(attrs: &'a JsonMap, key: &str) -> Option<&'a str> {
+		match attrs.get(key) {
+			Some(&Json::String(ref s)) => Some(s),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn parses_rfc5424() {
+		let attrs = parse_message("<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - \u{feff}'su root' failed for lonvick");
+		assert_eq!(attrs.get(PRIORITY), Some(&Json::I64(Severity::Critical.to_int())));
+		assert_eq!(attrs.get("SYSLOG_FACILITY"), Some(&Json::I64(4)));
+		assert_eq!(string(&attrs, "SYSLOG_TIMESTAMP"), Some("2003-10-11T22:14:15.003Z"));
+		assert_eq!(string(&attrs, "HOSTNAME"), Some("mymachine.example.com"));
+		assert_eq!(string(&attrs, "SYSLOG_IDENTIFIER"), Some("su"));
+		assert_eq!(string(&attrs, "SYSLOG_PID"), None);
+		assert_eq!(string(&attrs, "SYSLOG_MSGID"), Some("ID47"));
+		assert_eq!(string(&attrs, MESSAGE), Some("'su root' failed for lonvick"));
+		assert_eq!(string(&attrs, "SOURCE"), Some("su"));
+	}
+
+	#[test]
+	fn parses_structured_data() {
+		let attrs = parse_message(concat!(
+			"<165>1 2003-10-11T22:14:15.003Z host app 42 - ",
+			"[origin@32473 ip=\"10.0.0.1\" note=\"a \\\"quoted\\\" \\]\"][meta class=\"high\"] An event"));
+		assert_eq!(string(&attrs, "SYSLOG_PID"), Some("42"));
+		assert_eq!(string(&attrs, "SYSLOG_MSGID"), None);
+		assert_eq!(string(&attrs, "origin@32473.ip"), Some("10.0.0.1"));
+		assert_eq!(string(&attrs, "origin@32473.note"), Some("a \"quoted\" ]"));
+		assert_eq!(string(&attrs, "meta.class"), Some("high"));
+		assert_eq!(string(&attrs, MESSAGE), Some("An event"));
+	}
+
+	#[test]
+	fn parses_rfc3164() {
+		let attrs = parse_message("<13>Oct 11 22:14:15 myhost sshd[123]: Accepted publickey");
+		assert_eq!(attrs.get(PRIORITY), Some(&Json::I64(Severity::Notice.to_int())));
+		assert_eq!(attrs.get("SYSLOG_FACILITY"), Some(&Json::I64(1)));
+		assert_eq!(string(&attrs, "SYSLOG_TIMESTAMP"), Some("Oct 11 22:14:15"));
+		assert_eq!(string(&attrs, "HOSTNAME"), Some("myhost"));
+		assert_eq!(string(&attrs, "SYSLOG_IDENTIFIER"), Some("sshd"));
+		assert_eq!(string(&attrs, "SYSLOG_PID"), Some("123"));
+		assert_eq!(string(&attrs, MESSAGE), Some("Accepted publickey"));
+	}
+
+	#[test]
+	fn parses_rfc3164_without_header() {
+		let attrs = parse_message("<13>cron: job done");
+		assert_eq!(string(&attrs, "HOSTNAME"), None);
+		assert_eq!(string(&attrs, "SYSLOG_IDENTIFIER"), Some("cron"));
+		assert_eq!(string(&attrs, MESSAGE), Some("job done"));
+
+		let attrs = parse_message("just a message");
+		assert_eq!(attrs.get(PRIORITY), None);
+		assert_eq!(string(&attrs, "SYSLOG_IDENTIFIER"), None);
+		assert_eq!(string(&attrs, "SOURCE"), None);
+		assert_eq!(string(&attrs, MESSAGE), Some("just a message"));
+	}
+}
@@ -1,15 +1,26 @@
 use chrono::{DateTime,UTC};
-use std::collections::{HashMap,BTreeMap};
+use std::collections::{HashMap,BTreeMap,VecDeque};
 use std::collections::hash_map::{Entry};
 use std::sync::mpsc;
 use std::sync::{Arc,Mutex};
 use std::thread;
+use std::time;
+use std::time::Instant;
 use std::fmt;
 use std::mem;
 use std::error::Error;
 use std::ops::Deref;
 use monitor::*;
 use errors::*;
+use transitions::TransitionDetector;
+use config::{FlappingConfig,HealthConfig,RestartConfig,AlertRule,SilenceWindow};
+use alerts::AlertEngine;
+use silences::Silences;
+use acks::Acks;
+use problems::Problems;
+use health::HealthMonitor;
+use supervisor::Supervisor;
+use store::Store;
 use rustc_serialize::{Encoder,Encodable};
 
 #[derive(Debug)]
@@ -31,7 +42,14 @@ impl Encodable for SourceStatus {
 	}
 }
 
-type Listeners = HashMap<u32, mpsc::SyncSender<Arc<Update>>>;
+// Each update delivered to listeners is tagged with a sequence number,
+// so that a subscriber can resume from the last update it saw.
+pub type Sequenced = (u64, Arc<Update>);
+
+// how often the run loop wakes up when there are no updates
+const TICK_MS: u64 = 1000;
+
+type Listeners = HashMap<u32, mpsc::SyncSender<Sequenced>>;
 
 type SharedRef<T> = Arc<Mutex<T>>;
 
@@ -76,7 +94,7 @@ impl<T> Drop for Receiver<T> {
 
 enum ThreadState {
 	NotRunning(mpsc::Receiver<Arc<Update>>, Vec<Box<PullDataSource>>),
-	Running(thread::JoinHandle<Result<(),InternalError>>, thread::JoinHandle<()>, Vec<Box<PushSubscription>>),
+	Running(thread::JoinHandle<Result<(),InternalError>>, thread::JoinHandle<()>, Supervisor),
 	Ended,
 }
 
@@ -125,6 +143,11 @@ impl StateSnapshot {
 		}
 	}
 
+	pub fn get(&self, source: &str) -> Option<Arc<Update>> {
+		let state = self.state.lock().unwrap();
+		state.get(source).cloned()
+	}
+
 	pub fn values(&self) -> Vec<Arc<Update>> {
 		let state = self.state.lock().unwrap();
 		state.values().map(|update| update.clone()).collect()
@@ -137,13 +160,79 @@ impl Clone for StateSnapshot {
 	}
 }
 
+struct HistoryInner {
+	seq: u64,
+	capacity: usize,
+	updates: VecDeque<Sequenced>,
+}
+
+// A bounded buffer of recent updates
+pub struct History {
+	inner: SharedRef<HistoryInner>,
+}
+
+impl History {
+	pub fn new(capacity: usize) -> History {
+		History {
+			inner: Arc::new(Mutex::new(HistoryInner {
+				seq: 0,
+				capacity: capacity,
+				updates: VecDeque::with_capacity(capacity),
+			})),
+		}
+	}
+
+	// records an update, returning its sequence number
+	pub fn push(&self, update: &Arc<Update>) -> u64 {
+		let mut inner = self.inner.lock().unwrap();
+		inner.seq += 1;
+		let seq = inner.seq;
+		if inner.updates.len() >= inner.capacity {
+			let _:Option<Sequenced> = inner.updates.pop_front();
+		}
+		inner.updates.push_back((seq, update.clone()));
+		seq
+	}
+
+	pub fn current(&self) -> u64 {
+		self.inner.lock().unwrap().seq
+	}
+
+	// All updates after `seq`, or None if some of them have
+	// already been discarded (or `seq` is from some other run)
+	pub fn since(&self, seq: u64) -> Option<Vec<Sequenced>> {
+		let inner = self.inner.lock().unwrap();
+		if seq > inner.seq {
+			return None;
+		}
+		match inner.updates.front() {
+			Some(&(first, _)) if first > seq + 1 => None,
+			_ => Some(inner.updates.iter().filter(|&&(s, _)| s > seq).cloned().collect()),
+		}
+	}
+}
+
+impl Clone for History {
+	fn clone(&self) -> History {
+		History { inner : self.inner.clone() }
+	}
+}
+
 pub struct SystemMonitor {
 	poll_time_ms: u32,
 	thread_state: ThreadState,
 	event_writable: mpsc::SyncSender<Arc<Update>>,
 	listeners: SharedRef<Listeners>,
-	push_sources: Vec<Box<PushDataSource>>,
+	supervisor: Supervisor,
 	last_state: StateSnapshot,
+	history: History,
+	flapping: FlappingConfig,
+	health: HealthConfig,
+	alerts: Vec<AlertRule>,
+	silences: Silences,
+	acks: Acks,
+	problems: Problems,
+	store: Option<Store>,
 	subscriber_id: u32,
 }
 
@@ -152,7 +241,7 @@ impl Drop for SystemMonitor {
 		self.thread_state.bind(|state| match state {
 			ThreadState::Running(t1, t2, resources) => {
 				debug!("Joining system monitor thread");
-				drop(resources);
+				resources.stop();
 				match t1.join() {
 					Ok(Ok(())) => (),
 					Err(e) => log_error!(e, "joining thread"),
@@ -172,14 +261,40 @@ impl SystemMonitor {
 		event_buffer:usize,
 		pull_sources: Vec<Box<PullDataSource>>,
 		push_sources: Vec<Box<PushDataSource>>,
+		flapping: FlappingConfig,
+		health: HealthConfig,
+		restart: RestartConfig,
+		alerts: Vec<AlertRule>,
+		silences: Vec<SilenceWindow>,
+		store: Option<Store>,
 	) -> Result<SystemMonitor, InternalError> {
 		let (w,r) = mpsc::sync_channel(event_buffer);
+		let acks = Acks::new();
+		// pick up where we left off, until sources report their current state
+		let last_state = StateSnapshot::new();
+		match store {
+			Some(ref store) => {
+				// problems are rebuilt from scratch, so old ones would never be cleared
+				for update in store.recovered().into_iter().filter(|u| !Problems::is_problems(u)) {
+					last_state.update(&update);
+				}
+			},
+			None => (),
+		}
 		Ok(SystemMonitor {
 			poll_time_ms: poll_time,
 			// XXX can we remove these ARCs? They could at least be Boxes, I think
 			listeners: Arc::new(Mutex::new(HashMap::new())),
-			push_sources: push_sources,
-			last_state: StateSnapshot::new(),
+			supervisor: Supervisor::new(restart, push_sources, w.clone()),
+			last_state: last_state,
+			history: History::new(event_buffer),
+			flapping: flapping,
+			health: health,
+			alerts: alerts,
+			silences: Silences::new(silences),
+			problems: Problems::new(acks.clone()),
+			acks: acks,
+			store: store,
 			event_writable: w,
 			thread_state: ThreadState::NotRunning(r, pull_sources),
 			subscriber_id: 0,
@@ -189,7 +304,6 @@ impl SystemMonitor {
 	fn poll_loop(
 			sleep_ms: u32,
 			pull_sources: Vec<Box<PullDataSource>>,
-			last_state: StateSnapshot,
 			event_writable: mpsc::SyncSender<Arc<Update>>)
 	{
 		// XXX stop loop when last listener deregisters
@@ -198,22 +312,27 @@ impl SystemMonitor {
 			for source in pull_sources.iter() {
 				// XXX can we not clone this?
 				let time = Time::now();
-				let data = match source.poll() {
-					Ok(data) => data,
-					Err(e) => Data::Error(Failure {
+				let polled = match source.poll() {
+					Ok(polled) => polled,
+					Err(e) => vec!(Data::Error(Failure {
 						error: format!("{}", e),
 						id: Some("poll".to_string()),
-					}),
+					})),
 				};
-				let data = Arc::new(Update {
-					time: time,
-					source: source.source(),
-					data: data,
-					scope: UpdateScope::Snapshot,
-				});
-				last_state.update(&data);
-				ignore_error!(event_writable.try_send(data.clone()), "sending poll result");
-				state.push(data);
+				for data in polled {
+					let scope = match data {
+						Data::State(_) | Data::Error(_) => UpdateScope::Snapshot,
+						_ => UpdateScope::Partial,
+					};
+					let data = Arc::new(Update {
+						time: time.clone(),
+						source: source.source(),
+						data: data,
+						scope: scope,
+					});
+					ignore_error!(event_writable.try_send(data.clone()), "sending poll result");
+					state.push(data);
+				}
 			}
 			thread::sleep_ms(sleep_ms);
 		}
@@ -222,31 +341,151 @@ impl SystemMonitor {
 	fn run_loop(
 			event_readable: mpsc::Receiver<Arc<Update>>,
 			last_state: StateSnapshot,
-			listeners: SharedRef<Listeners>) -> Result<(), InternalError>
+			history: History,
+			listeners: SharedRef<Listeners>,
+			flapping: FlappingConfig,
+			mut health: HealthMonitor,
+			supervisor: Supervisor,
+			alerts: Vec<AlertRule>,
+			silences: Silences,
+			acks: Acks,
+			problems: Problems,
+			store: Option<Store>) -> Result<(), InternalError>
 	{
+		let mut transitions = TransitionDetector::new(flapping);
+		let mut alerts = AlertEngine::new(alerts, silences.clone());
+		let tick = time::Duration::from_millis(TICK_MS);
+		let mut last_tick = Instant::now();
 		// XXX stop loop when last listener deregisters
 		loop {
-			let data : Arc<Update> = try!(event_readable.recv());
-			{
+			let received = match event_readable.recv_timeout(tick) {
+				Ok(data) => Some(data),
+				Err(mpsc::RecvTimeoutError::Timeout) => None,
+				Err(mpsc::RecvTimeoutError::Disconnected) => return Err(InternalError::from(mpsc::RecvError)),
+			};
+
+			// time-based changes are checked even while updates keep arriving.
+			// These come first, since `received` may supersede them.
+			let ticked = last_tick.elapsed() >= tick;
+			let mut updates = Vec::new();
+			if ticked {
+				last_tick = Instant::now();
+				let silences_changed = silences.changed();
+				let acks_changed = acks.changed();
+				if silences_changed || acks_changed {
+					// re-flag the current state of every source
+					updates.extend(last_state.values());
+				}
+				if acks_changed {
+					updates.push(problems.snapshot());
+				}
+				updates.extend(transitions.tick());
+
+				// restart failures are sent like any other update from the source
+				supervisor.check();
+				for (source, status) in supervisor.statuses() {
+					health.set_thread(&source.id, status);
+				}
+				// stale sources go through transition detection, so
+				// their items going `Unknown` produce events
+				for data in health.tick(&last_state) {
+					updates.extend(transitions.process(data));
+				}
+				match store {
+					Some(ref store) => ignore_error!(store.refresh(last_state.values()), "refreshing store"),
+					None => (),
+				}
+			}
+			match received {
+				Some(data) => {
+					health.process(&data);
+					let problem = problems.process(&data);
+					updates.extend(transitions.process(data));
+					updates.extend(problem);
+				},
+				None => (),
+			}
+
+			// derived events and alerts are delivered just like updates from sources
+			let mut updates: Vec<Arc<Update>> = updates.into_iter()
+				.map(|data| acks.apply(silences.apply(data)))
+				.collect();
+			let mut fired = Vec::new();
+			for data in updates.iter() {
+				fired.extend(alerts.process(data));
+			}
+			if ticked {
+				fired.extend(alerts.tick());
+			}
+			updates.extend(fired.into_iter().map(|data| acks.apply(silences.apply(data))));
+			for data in updates {
 				last_state.update(&data);
+				match store {
+					Some(ref store) => ignore_error!(store.append(&data), "storing update"),
+					None => (),
+				}
+				let seq = history.push(&data);
 				let listeners = listeners.lock().unwrap();
 				for listener in listeners.values() {
-					ignore_error!(listener.try_send(data.clone()), "sending data to listener");
+					ignore_error!(listener.try_send((seq, data.clone())), "sending data to listener");
 				}
 			}
 		}
 	}
 
-	pub fn subscribe(&mut self) -> Result<Receiver<Arc<Update>>, InternalError> {
+	pub fn silences(&self) -> Silences {
+		self.silences.clone()
+	}
+
+	pub fn acks(&self) -> Acks {
+		self.acks.clone()
+	}
+
+	pub fn problems(&self) -> Problems {
+		self.problems.clone()
+	}
+
+	pub fn store(&self) -> Option<Store> {
+		self.store.clone()
+	}
+
+	// A sender for injecting updates from outside of the configured
+	// sources (e.g. those received over HTTP)
+	pub fn emitter(&self) -> mpsc::SyncSender<Arc<Update>> {
+		self.event_writable.clone()
+	}
+
+	pub fn subscribe(&mut self) -> Result<Receiver<Sequenced>, InternalError> {
+		self.subscribe_from(None)
+	}
+
+	// Subscribe, resuming after the update numbered `last_seq`. Subscribers
+	// always receive the latest state snapshots, but partial updates
+	// (e.g. events) are only replayed if they're still in our history.
+	pub fn subscribe_from(&mut self, last_seq: Option<u64>) -> Result<Receiver<Sequenced>, InternalError> {
+		// missed partial updates come first (in order), followed by the
+		// snapshots, so that the last seq sent is always the current one
+		let mut initial: Vec<Sequenced> = match last_seq.and_then(|seq| self.history.since(seq)) {
+			Some(missed) => {
+				debug!("replaying {} missed updates", missed.len());
+				missed.into_iter().filter(|&(_, ref update)| match update.scope {
+					UpdateScope::Partial => true,
+					UpdateScope::Snapshot => false,
+				}).collect()
+			},
+			None => Vec::new(),
+		};
+
+		let seq = self.history.current();
+		let snapshots = self.last_state.values();
+		debug!("sending {} initial updates from last_state", snapshots.len());
+		debug!("initial_states: {:?}", snapshots);
+		initial.extend(snapshots.into_iter().map(|update| (seq, update)));
+
 		// XXX make this react to self.pull_sources.len()
-		let (sender, receiver) = mpsc::sync_channel(10);
-		{
-			let initial_states = self.last_state.values();
-			debug!("sending {} initial updates from last_state", initial_states.len());
-			debug!("initial_states: {:?}", initial_states);
-			for update in initial_states.iter() {
-				ignore_error!(sender.try_send(update.clone()), "sending initial state");
-			}
+		let (sender, receiver) = mpsc::sync_channel(10 + initial.len());
+		for update in initial.into_iter() {
+			ignore_error!(sender.try_send(update), "sending initial state");
 		}
 
 		let mut id;
@@ -281,10 +520,18 @@ impl SystemMonitor {
 		// we can't use `self` in the closure below while
 		// self.thread_state is mutably borrowed
 		let last_state = &self.last_state;
-		let push_sources = &mut self.push_sources;
+		let history = &self.history;
+		let supervisor = &self.supervisor;
 		let listeners = &self.listeners;
 		let event_writable = &self.event_writable;
 		let sleep_ms = self.poll_time_ms;
+		let flapping = self.flapping.clone();
+		let health = self.health.clone();
+		let alerts = self.alerts.clone();
+		let silences = self.silences.clone();
+		let acks = self.acks.clone();
+		let problems = self.problems.clone();
+		let store = self.store.clone();
 
 		try!(self.thread_state.try_bind(|state| match state {
 			r@ThreadState::Running(_,_,_) => Ok(r),
@@ -296,39 +543,40 @@ impl SystemMonitor {
 				// to send `event_readable` and `pull_sources` to their respective threads. We can't just let the
 				// threads own these variables, as then we can't return the same NotRunning state in the case of failure.
 				debug!("Starting system monitor thread");
-				let mut subscriptions = Vec::new();
-				for source in push_sources.iter_mut() {
-					match source.subscribe(event_writable.clone()) {
-						Ok(subscription) => {
-							subscriptions.push(subscription);
-						},
-						Err(e) => return Err(
-							(ThreadState::NotRunning(event_readable, pull_sources), InternalError::from(e))
-						),
-					}
+				let mut sources: Vec<Arc<Source>> = pull_sources.iter().map(|source| source.source()).collect();
+				sources.extend(supervisor.sources());
+				match supervisor.start() {
+					Ok(()) => (),
+					Err(e) => return Err((ThreadState::NotRunning(event_readable, pull_sources), e)),
 				}
 
 				// kick off the first thread
 				let (t1_send, t1_recv) = mpsc::sync_channel(0);
 				let poll_thread = match thread::Builder::new().spawn(move || {
-					let (pull_sources, last_state, event_writable) = t1_recv.recv().unwrap();
-					Self::poll_loop(sleep_ms, pull_sources, last_state, event_writable)
+					let (pull_sources, event_writable) = t1_recv.recv().unwrap();
+					Self::poll_loop(sleep_ms, pull_sources, event_writable)
 				}) {
-					Err(e) => return Err((ThreadState::NotRunning(event_readable, pull_sources), InternalError::from(e))),
+					Err(e) => {
+						supervisor.stop();
+						return Err((ThreadState::NotRunning(event_readable, pull_sources), InternalError::from(e)))
+					},
 					Ok(poll_thread) => poll_thread
 				};
 
 				// now the second
+				let health = HealthMonitor::new(health, sources);
 				let (t2_send, t2_recv) = mpsc::sync_channel(0);
 				let event_thread = match thread::Builder::new().spawn(move || {
-					let (event_readable, last_state, listeners) = t2_recv.recv().unwrap();
-					Self::run_loop(event_readable, last_state, listeners)
+					let (event_readable, last_state, history, listeners, supervisor) = t2_recv.recv().unwrap();
+					Self::run_loop(event_readable, last_state, history, listeners,
+						flapping, health, supervisor, alerts, silences, acks, problems, store)
 				}) {
 					Err(e) => {
 						// we spawned the first thread, but not the second!
 						drop(t1_send);
 						drop(t2_send);
 						ignore_error!(poll_thread.join(), "joining thread");
+						supervisor.stop();
 						return Err((ThreadState::NotRunning(event_readable, pull_sources), InternalError::from(e)))
 					},
 					Ok(event_thread) => event_thread
@@ -336,9 +584,9 @@ impl SystemMonitor {
 				
 				// Both of the threads are now succesfully started and therefore waiting on our queue.
 				// So `unwap()` is safe, as there's no way those threads could have died.
-				t1_send.send((pull_sources, last_state.clone(), event_writable.clone())).unwrap();
-				t2_send.send((event_readable, last_state.clone(), listeners.clone())).unwrap();
-				Ok(ThreadState::Running(event_thread, poll_thread, subscriptions))
+				t1_send.send((pull_sources, event_writable.clone())).unwrap();
+				t2_send.send((event_readable, last_state.clone(), history.clone(), listeners.clone(), supervisor.clone())).unwrap();
+				Ok(ThreadState::Running(event_thread, poll_thread, supervisor.clone()))
 			}
 		}));
 		Ok(rv)
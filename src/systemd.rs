@@ -11,6 +11,7 @@ use std::sync::{Arc};
 use std::io::{BufRead, BufReader};
 use std::fmt;
 use rustc_serialize::json::{Json};
+use chrono;
 use chrono::{DateTime,Local};
 use monitor::*;
 use config::SystemdConfig;
@@ -25,12 +26,14 @@ extern crate dbus;
 pub struct SystemdMonitor {
 	ignored_types: HashSet<String>,
 	user: bool,
+	options: UnitOptions,
 	source: Arc<Source>,
 }
 
 pub struct SystemdPusher {
 	ignored_types: HashSet<String>,
 	user: bool,
+	options: UnitOptions,
 	source: Arc<Source>,
 }
 
@@ -38,6 +41,12 @@ impl SystemdMonitor {
 	pub fn new(conf: SystemdConfig) -> SystemdMonitor {
 		let common = conf.common;
 		let user = conf.user.unwrap_or(false);
+		let options = UnitOptions {
+			timer_grace: conf.timer_grace.unwrap_or_else(|| chrono::Duration::minutes(5)),
+			expect: conf.expect,
+			journal_lines: ::std::cmp::max(conf.journal_lines.unwrap_or(10), 0) as u32,
+			user: user,
+		};
 
 		// TODO: use includes instead of hard-coded stufff
 		let mut ignored = HashSet::new();
@@ -50,6 +59,7 @@ impl SystemdMonitor {
 		SystemdMonitor {
 			source: Arc::new(Source::new(common.id, SYSTEMD_TYPE)),
 			user: user,
+			options: options,
 			ignored_types: ignored,
 		}
 	}
@@ -62,10 +72,20 @@ impl SystemdMonitor {
 	// 	})
 	// }
 
+	pub fn id(&self) -> String {
+		self.source.id.clone()
+	}
+
+	// whether this watches the user's (session) systemd instance
+	pub fn user(&self) -> bool {
+		self.user
+	}
+
 	pub fn pusher(&self) -> Box<SystemdPusher> {
 		Box::new(SystemdPusher {
 			ignored_types: self.ignored_types.clone(),
 			user: self.user,
+			options: self.options.clone(),
 			source: self.source.clone(),
 		})
 	}
@@ -79,11 +99,15 @@ impl PushDataSource for SystemdPusher {
 	fn subscribe(&self, sender: mpsc::SyncSender<Arc<Update>>) -> Result<Box<PushSubscription>, InternalError> {
 		let which = if self.user { BusType::Session } else { BusType::System };
 		let ignored_types = self.ignored_types.clone();
+		let options = self.options.clone();
 
 		let error_reporter = ErrorReporter::new(self);
 		let source = self.source();
+		let liveness = Liveness::new();
+		let guard = liveness.guard();
 		let thread = try!(thread::Builder::new().spawn(move|| -> Result<(), InternalError> {
-			let rv = watch_units(&sender, source.clone(), which, ignored_types, error_reporter);
+			let _guard = guard;
+			let rv = watch_units(&sender, source.clone(), which, ignored_types, options, error_reporter);
 			match rv {
 				Ok(()) => Ok(()),
 				Err(e) => {
@@ -100,6 +124,6 @@ impl PushDataSource for SystemdPusher {
 				}
 			}
 		}));
-		Ok(Box::new(SystemdDbusSubscription::new(thread)))
+		Ok(Box::new(SystemdDbusSubscription::new(thread, liveness)))
 	}
 }
@@ -11,15 +11,26 @@ use std::sync::{Arc};
 use std::io::{BufRead, BufReader};
 use std::fmt;
 use rustc_serialize::json::{Json};
+use chrono;
 use chrono::{DateTime,Local};
 use monitor::*;
-use config::SystemdConfig;
+use config::{SystemdConfig,UnitRule,UnitExpectation};
 use util::read_all;
 use dbus::{Connection,BusType,Message,MessageItem,Props};
 use super::errors::*;
 
 pub const SYSTEMD_TYPE : &'static str = "systemd";
 
+// how units should be interpreted, beyond their ActiveState
+#[derive(Clone)]
+pub struct UnitOptions {
+	pub timer_grace: chrono::Duration,
+	pub expect: Vec<UnitRule>,
+	// the number of journal lines to attach to failed units
+	pub journal_lines: u32,
+	pub user: bool,
+}
+
 #[derive(Debug)]
 pub enum RuntimeError {
 	UnexpectedBlankLine,
@@ -67,3 +78,25 @@ pub fn state_of_active_state(active_state: &str) -> State {
 	}
 }
 
+pub fn unit_expectation<'a>(rules: &'a Vec<UnitRule>, unit: &str) -> Option<&'a UnitExpectation> {
+	rules.iter().find(|rule| rule.pattern.matches(unit)).map(|rule| &rule.expect)
+}
+
+// The health of a unit, given what we expect of it. `result` is the
+// service's `Result` property, if known.
+pub fn expected_state(expect: Option<&UnitExpectation>, active_state: &str, result: Option<&str>) -> State {
+	let state = state_of_active_state(active_state);
+	match (expect, state) {
+		(None, state) => state,
+		(_, State::Error) => State::Error,
+		(Some(&UnitExpectation::MustBeActive), State::Inactive) => State::Error,
+		(Some(&UnitExpectation::IgnoreInactive), State::Inactive) => State::Active,
+		(Some(&UnitExpectation::Oneshot), State::Active) | (Some(&UnitExpectation::Oneshot), State::Inactive) => {
+			match result {
+				None | Some("success") => State::Active,
+				Some(_) => State::Error,
+			}
+		},
+		(_, state) => state,
+	}
+}
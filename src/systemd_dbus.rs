@@ -12,10 +12,14 @@ use std::sync::mpsc;
 use std::sync::{Arc};
 use std::io::{BufRead, BufReader};
 use std::fmt;
+use std::time;
+use std::time::Instant;
 use rustc_serialize::json::{Json};
-use chrono::{DateTime,Local};
+use chrono;
+use chrono::{DateTime,Local,UTC,Timelike};
 use monitor::*;
-use config::SystemdConfig;
+use config::{SystemdConfig,UnitExpectation};
+use journal::recent_unit_entries;
 use util::read_all;
 use dbus::{Connection,BusType,Message,MessageItem,MessageType,Props,ConnectionItem,Path};
 use super::dbus_common::*;
@@ -29,6 +33,17 @@ const SYSTEMD_MANAGER_IFACE: &'static str = "org.freedesktop.systemd1.Manager";
 const DBUS_PROPERTIES_IFACE: &'static str = "org.freedesktop.DBus.Properties";
 const DBUS_ROOT_IFACE: &'static str = "org.freedesktop.DBus";
 const SYSTEMD_UNIT_IFACE: &'static str = "org.freedesktop.systemd1.Unit";
+const SYSTEMD_TIMER_IFACE: &'static str = "org.freedesktop.systemd1.Timer";
+const SYSTEMD_SERVICE_IFACE: &'static str = "org.freedesktop.systemd1.Service";
+const TIMER_SUFFIX: &'static str = ".timer";
+const SERVICE_SUFFIX: &'static str = ".service";
+const JOURNAL_ATTR: &'static str = "journal";
+// Timers can become overdue without any DBus activity, so we recheck them this often
+const TIMER_CHECK_INTERVAL_MS: u32 = 1000 * 30;
+// how often to check for journal entries which are being read
+const JOURNAL_POLL_MS: u32 = 200;
+// how long snapshots are held back while reading journal entries for failed units
+const JOURNAL_WAIT_MS: u64 = 2000;
 const UNIT_ADDED: &'static str = "UnitNew";
 const UNIT_REMOVED: &'static str = "UnitRemoved";
 const RELOADING: &'static str = "Reloading";
@@ -69,13 +84,14 @@ pub fn watch_units(
 	source: Arc<Source>,
 	bus: BusType,
 	ignored_types: HashSet<String>,
+	options: UnitOptions,
 	error_reporter: ErrorReporter
 	) -> Result<(), InternalError>
 {
 	use dbus::MessageItem::*;
 	debug!("Connecting to {:?} bus", bus);
 	let conn = try!(Connection::get_private(bus));
-	let mut dbus_state = DBusState::new(&conn, sender, source, &ignored_types, error_reporter);
+	let mut dbus_state = DBusState::new(&conn, sender, source, &ignored_types, &options, error_reporter);
 
 	debug!("Subscribing to {}", SYSTEMD_DBUS_DEST);
 	let _:Message = try!(call_method(&conn, try!(method_call("Subscribe"))));
@@ -103,10 +119,19 @@ pub fn watch_units(
 	// initial state computed - send it
 	try!(dbus_state.emit());
 
+	let timer_check_interval = time::Duration::from_millis(TIMER_CHECK_INTERVAL_MS as u64);
+	let mut last_timer_check = Instant::now();
 	loop {
-		let messages = conn.iter(1000 * 60); // 1min timeout
-		for message in messages {
-			ignore_error!(dbus_state.process_message(message), "dbus message");
+		let timeout = if dbus_state.reading_journals() { JOURNAL_POLL_MS } else { TIMER_CHECK_INTERVAL_MS };
+		// the timeout yields a `Nothing` item, so we'll still check timers when the bus is idle
+		match conn.iter(timeout as i32).next() {
+			Some(message) => ignore_error!(dbus_state.process_message(message), "dbus message"),
+			None => (),
+		}
+		ignore_error!(dbus_state.attach_journals(), "attaching journal entries");
+		if last_timer_check.elapsed() >= timer_check_interval {
+			ignore_error!(dbus_state.check_timers(), "checking timers");
+			last_timer_check = Instant::now();
 		}
 	}
 }
@@ -139,17 +164,29 @@ fn property_match_rule(path: &str) -> String {
 	// "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',member='Foo',path='/bar/foo',destination=':452345.34',arg2='bar'" 
 }
 
+// Asks systemd to restart a unit, as `systemctl restart` would
+pub fn restart_unit(bus: BusType, unit: &str) -> Result<(), InternalError> {
+	let conn = try!(Connection::get_private(bus));
+	let call = try!(method_call("RestartUnit"))
+		.append(MessageItem::Str(unit.to_string()))
+		.append(MessageItem::Str("replace".to_string()));
+	let _:Message = try!(call_method(&conn, call));
+	Ok(())
+}
+
 pub struct SystemdDbusSubscription {
 	thread: Option<JoinHandle<Result<(), InternalError>>>,
+	liveness: Liveness,
 }
 
 impl SystemdDbusSubscription {
-	pub fn new(thread: JoinHandle<Result<(), InternalError>>) -> SystemdDbusSubscription {
-		SystemdDbusSubscription { thread: Some(thread) }
+	pub fn new(thread: JoinHandle<Result<(), InternalError>>, liveness: Liveness) -> SystemdDbusSubscription {
+		SystemdDbusSubscription { thread: Some(thread), liveness: liveness }
 	}
 }
 
 impl PushSubscription for SystemdDbusSubscription {
+	fn is_alive(&self) -> bool { self.liveness.is_alive() }
 }
 
 impl Drop for SystemdDbusSubscription {
@@ -169,26 +206,122 @@ impl Drop for SystemdDbusSubscription {
 }
 
 
+#[derive(Debug,Clone)]
+struct TimerInfo {
+	// the unit which this timer activates
+	triggers: String,
+	// realtime (usec since the epoch) of the next scheduled run, or 0 if there is none
+	// (or the timer isn't running)
+	next_elapse: u64,
+	overdue: bool,
+}
+
 #[derive(Debug,Clone)]
 struct DBusUnit {
 	status: Status,
 	name: String,
 	path: String,
+	timer: Option<TimerInfo>,
+}
+
+// Reads the journal entries of failed units on a separate thread, since
+// `journalctl` can be slow and would otherwise hold up DBus messages
+struct JournalReader {
+	requests: mpsc::Sender<String>,
+	results: mpsc::Receiver<(String, Option<Json>)>,
+	// units whose entries are being read
+	pending: HashSet<String>,
+}
+
+impl JournalReader {
+	fn new(user: bool, lines: u32) -> Result<JournalReader, InternalError> {
+		let (request_sender, requests) = mpsc::channel::<String>();
+		let (result_sender, results) = mpsc::channel();
+		// ends once the reader (and so `request_sender`) is dropped
+		try!(thread::Builder::new().name("journal reader".to_string()).spawn(move || {
+			for unit in requests.iter() {
+				let entries = match recent_unit_entries(unit.deref(), user, lines) {
+					Ok(entries) => Some(Json::Array(entries)),
+					Err(e) => {
+						debug!("Unable to read journal for {}: {}", unit, e);
+						None
+					},
+				};
+				if result_sender.send((unit, entries)).is_err() {
+					break;
+				}
+			}
+		}));
+		Ok(JournalReader {
+			requests: request_sender,
+			results: results,
+			pending: HashSet::new(),
+		})
+	}
+
+	fn request(&mut self, unit: &str) {
+		if self.pending.insert(unit.to_string()) {
+			ignore_error!(self.requests.send(unit.to_string()).map_err(|_|
+				InternalError::new("journal reader stopped".to_string())), "reading journal");
+		}
+	}
+
+	// Entries which have been read since the last call
+	fn finished(&mut self) -> Vec<(String, Option<Json>)> {
+		let mut rv = Vec::new();
+		loop {
+			match self.results.try_recv() {
+				Ok((unit, entries)) => {
+					self.pending.remove(&unit);
+					rv.push((unit, entries));
+				},
+				Err(_) => break,
+			}
+		}
+		rv
+	}
 }
 
 struct DBusState<'a> {
 	conn: &'a Connection,
 	sender: &'a mpsc::SyncSender<Arc<Update>>,
 	ignored_types: &'a HashSet<String>,
+	options: &'a UnitOptions,
 	source: Arc<Source>,
 	error_reporter: ErrorReporter,
 	// XXX these should be keyed as `Path`, but that's not hashable
 	units: HashMap<String,DBusUnit>,
 	state: HashMap<String,Status>,
+	journals: Option<JournalReader>,
+	// when a snapshot was first held back waiting for journal entries
+	held_since: Option<Instant>,
+}
+
+fn get_prop(conn: &Connection, path: &str, iface: &str, name: &str) -> Result<MessageItem,InternalError> {
+	Ok(try!(Props::new(conn, SYSTEMD_DBUS_DEST, path, iface, DBUS_CALL_TIMEOUT).get(name)))
 }
 
 fn get_unit_prop(conn: &Connection, path: &str, name: &str) -> Result<MessageItem,InternalError> {
-	Ok(try!(Props::new(conn, SYSTEMD_DBUS_DEST, path, SYSTEMD_UNIT_IFACE, DBUS_CALL_TIMEOUT).get(name)))
+	get_prop(conn, path, SYSTEMD_UNIT_IFACE, name)
+}
+
+fn get_usec_prop(conn: &Connection, path: &str, iface: &str, name: &str) -> Result<u64,InternalError> {
+	match try!(get_prop(conn, path, iface, name)) {
+		MessageItem::UInt64(n) => Ok(n),
+		other => Err(InternalError::new(format!("Invalid {}: {:?}", name, other))),
+	}
+}
+
+fn now_usec() -> u64 {
+	let now = UTC::now();
+	(now.timestamp() as u64) * 1000000 + (now.nanosecond() / 1000) as u64
+}
+
+// systemd reports USEC_INFINITY (u64::MAX) when there's no next elapse
+fn is_overdue(next_elapse: u64, grace: chrono::Duration) -> bool {
+	let grace_usec = grace.num_microseconds().unwrap_or(0) as u64;
+	next_elapse != 0 && next_elapse != ::std::u64::MAX
+		&& now_usec() > next_elapse.saturating_add(grace_usec)
 }
 
 impl<'a> DBusState<'a> {
@@ -197,6 +330,7 @@ impl<'a> DBusState<'a> {
 		sender: &'a mpsc::SyncSender<Arc<Update>>,
 		source: Arc<Source>,
 		ignored_types: &'a HashSet<String>,
+		options: &'a UnitOptions,
 		error_reporter: ErrorReporter
 	) -> DBusState<'a>
 	{
@@ -204,10 +338,23 @@ impl<'a> DBusState<'a> {
 			conn: conn,
 			sender: sender,
 			ignored_types: ignored_types,
+			options: options,
 			source: source,
 			error_reporter: error_reporter,
 			units: HashMap::new(),
 			state: HashMap::new(),
+			held_since: None,
+			journals: if options.journal_lines > 0 {
+				match JournalReader::new(options.user, options.journal_lines) {
+					Ok(reader) => Some(reader),
+					Err(e) => {
+						warn!("Unable to start journal reader: {}", e);
+						None
+					},
+				}
+			} else {
+				None
+			},
 		}
 	}
 
@@ -252,41 +399,286 @@ impl<'a> DBusState<'a> {
 	}
 
 
-	fn get_unit_status(&mut self, path: &String) -> Result<Status, InternalError> {
+	fn get_unit_status(&mut self, name: &str, path: &String) -> Result<(Status, Option<TimerInfo>), InternalError> {
 		use dbus::MessageItem::*;
 		let active_state = match try!(get_unit_prop(self.conn, path, "ActiveState")) {
-			Str(s) => state_of_active_state(s.deref()),
+			Str(s) => s,
 			other => return Err(InternalError::new(format!("Invalid ActiveState: {:?}", other))),
 		};
 
 		// ActiveState,SubState,Result,ExecMainExitTimestamp,ExecMainStartTimestamp,StatusText
-		let attrs = HashMap::new(); // XXX populate
+		let mut attrs = HashMap::new(); // XXX populate
+
+		let expect = unit_expectation(&self.options.expect, name);
+		let result = match expect {
+			Some(&UnitExpectation::Oneshot) if name.ends_with(SERVICE_SUFFIX) => {
+				match try!(get_prop(self.conn, path, SYSTEMD_SERVICE_IFACE, "Result")) {
+					Str(result) => Some(result),
+					other => return Err(InternalError::new(format!("Invalid Result: {:?}", other))),
+				}
+			},
+			_ => None,
+		};
+		match expect {
+			Some(expect) => {
+				attrs.insert("active_state".to_string(), Json::String(active_state.clone()));
+				attrs.insert("expect".to_string(), Json::String(expect.name().to_string()));
+			},
+			None => (),
+		};
+		match result {
+			Some(ref result) => { attrs.insert("result".to_string(), Json::String(result.clone())); },
+			None => (),
+		};
+		let active_state = expected_state(expect, active_state.deref(), result.as_ref().map(|r| r.deref()));
+
+		let (state, timer) = if name.ends_with(TIMER_SUFFIX) {
+			let (state, timer) = try!(self.get_timer_status(path, active_state, &mut attrs));
+			(state, Some(timer))
+		} else {
+			(active_state, None)
+		};
 
-		Ok(Status {
-			state: active_state,
+		Ok((Status {
+			state: state,
 			attrs: Arc::new(attrs),
-		})
+		}, timer))
 	}
 
-	fn check_unit(&mut self, path: &str) -> Result<(), InternalError> {
+	// The `Result` of a service ("success", "exit-code", etc), if it's loaded
+	fn get_service_result(&self, name: &str) -> Result<Option<String>, InternalError> {
+		use dbus::MessageItem::*;
+		if !name.ends_with(SERVICE_SUFFIX) {
+			return Ok(None);
+		}
+		let mut call = try!(method_call("GetUnit"));
+		call.append_items(&[Str(name.to_string())]);
+		let path = match call_method(self.conn, call) {
+			Ok(reply) => match reply.get_items().into_iter().next() {
+				Some(ObjectPath(path)) => path.to_string(),
+				other => return Err(InternalError::new(format!("Invalid GetUnit response: {:?}", other))),
+			},
+			Err(e) => {
+				// most likely the unit isn't loaded (i.e. it's never run)
+				debug!("Can't get unit {}: {}", name, InternalError::from(e));
+				return Ok(None);
+			},
+		};
+		match try!(get_prop(self.conn, path.deref(), SYSTEMD_SERVICE_IFACE, "Result")) {
+			Str(result) => Ok(Some(result)),
+			other => Err(InternalError::new(format!("Invalid Result: {:?}", other))),
+		}
+	}
+
+	// A timer is in error if its last run failed, or if
+	// it's overdue by more than `timer_grace`
+	fn get_timer_status(&self, path: &str, state: State, attrs: &mut Attributes) -> Result<(State, TimerInfo), InternalError> {
+		use dbus::MessageItem::*;
+		let triggers = match try!(get_prop(self.conn, path, SYSTEMD_TIMER_IFACE, "Unit")) {
+			Str(s) => s,
+			other => return Err(InternalError::new(format!("Invalid Unit: {:?}", other))),
+		};
+		let next_elapse = try!(get_usec_prop(self.conn, path, SYSTEMD_TIMER_IFACE, "NextElapseUSecRealtime"));
+		let last_trigger = try!(get_usec_prop(self.conn, path, SYSTEMD_TIMER_IFACE, "LastTriggerUSec"));
+		let result = try!(self.get_service_result(triggers.deref()));
+
+		attrs.insert("triggers".to_string(), Json::String(triggers.clone()));
+		if next_elapse != 0 && next_elapse != ::std::u64::MAX {
+			attrs.insert("next_elapse".to_string(), Json::U64(next_elapse / 1000000));
+		}
+		if last_trigger != 0 {
+			attrs.insert("last_trigger".to_string(), Json::U64(last_trigger / 1000000));
+		}
+		match result {
+			Some(ref result) => { attrs.insert("result".to_string(), Json::String(result.clone())); },
+			None => (),
+		}
+
+		// a stopped timer has no schedule to keep
+		let scheduled = match state {
+			State::Active => next_elapse,
+			_ => 0,
+		};
+		let overdue = is_overdue(scheduled, self.options.timer_grace);
+		if overdue {
+			attrs.insert("overdue".to_string(), Json::Boolean(true));
+		}
+
+		let failed = match result {
+			Some(ref result) => result != "success",
+			None => false,
+		};
+		let state = if failed || overdue { State::Error } else { state };
+		Ok((state, TimerInfo {
+			triggers: triggers,
+			next_elapse: scheduled,
+			overdue: overdue,
+		}))
+	}
+
+	// When a unit fails, start reading its recent journal entries (which are
+	// attached by `attach_journals`, and kept for as long as it remains failed).
+	// These also end up on the transition event derived by the monitor, since
+	// `emit` holds back snapshots until they've been read.
+	fn annotate_failure(&mut self, name: &str, previous: Option<&Status>, status: &mut Status) {
+		match status.state {
+			State::Error => (),
+			_ => return,
+		};
+
+		match previous {
+			Some(&Status { state: State::Error, ref attrs }) => {
+				match attrs.get(JOURNAL_ATTR) {
+					Some(journal) => {
+						let mut new_attrs = (*status.attrs).clone();
+						new_attrs.insert(JOURNAL_ATTR.to_string(), journal.clone());
+						status.attrs = Arc::new(new_attrs);
+					},
+					// still being read, or unavailable
+					None => (),
+				}
+			},
+			_ => match self.journals {
+				Some(ref mut journals) => journals.request(name),
+				None => (),
+			},
+		}
+	}
+
+	fn reading_journals(&self) -> bool {
+		self.journals.as_ref().map(|j| !j.pending.is_empty()).unwrap_or(false)
+	}
+
+	// Attaches journal entries which have been read to their (still failed) units
+	fn attach_journals(&mut self) -> Result<(), InternalError> {
+		let finished = match self.journals {
+			Some(ref mut journals) => journals.finished(),
+			None => return Ok(()),
+		};
+		// the snapshot with the failure has already been sent if it isn't held
+		let late = self.held_since.is_none();
+		let mut changed = false;
+		for (name, entries) in finished {
+			let entries = match entries {
+				Some(entries) => entries,
+				None => continue,
+			};
+			let unit = self.units.values()
+				.find(|unit| unit.name == name && unit.status.state == State::Error)
+				.cloned();
+			let mut unit = match unit {
+				Some(unit) => unit,
+				None => continue,
+			};
+			if late {
+				try!(self.emit_journal_event(name.deref(), entries.clone()));
+			}
+			let mut attrs = (*unit.status.attrs).clone();
+			attrs.insert(JOURNAL_ATTR.to_string(), entries);
+			unit.status.attrs = Arc::new(attrs);
+			self._update_unit(unit);
+			changed = true;
+		}
+		// a held snapshot is sent once all entries are read, or it's waited too long
+		if changed || self.held_since.is_some() {
+			try!(self.emit());
+		}
+		Ok(())
+	}
+
+	// For entries read too late to be on the transition event
+	fn emit_journal_event(&self, name: &str, entries: Json) -> Result<(), InternalError> {
+		let mut attrs = HashMap::new();
+		attrs.insert("unit".to_string(), Json::String(name.to_string()));
+		attrs.insert(JOURNAL_ATTR.to_string(), entries);
+		try!(self.sender.send(Arc::new(Update {
+			scope: UpdateScope::Partial,
+			source: self.source.clone(),
+			time: Time::now(),
+			data: Data::Event(Event {
+				id: Some(name.to_string()),
+				severity: Some(Severity::Error),
+				message: Some(format!("{} failed", name)),
+				attrs: Arc::new(attrs),
+			}),
+		})));
+		Ok(())
+	}
+
+	// re-reads the status of a unit, without emitting
+	fn refresh_unit(&mut self, path: &str) -> Result<(), InternalError> {
 		// XXX this seems a bit inefficient...
 		let unit: Option<DBusUnit> = self.units.get(path).map(|x| (*x).clone());
 		match unit {
 			Some(mut unit) => {
-				let status = try!(self.get_unit_status(&unit.path));
+				let (mut status, timer) = try!(self.get_unit_status(unit.name.deref(), &unit.path));
+				self.annotate_failure(unit.name.deref(), Some(&unit.status), &mut status);
 				unit.status = status;
-				self.unit_changed(unit)
+				unit.timer = timer;
+				self._update_unit(unit);
+				Ok(())
 			},
 			None => Ok(()),
 		}
 	}
 
+	fn check_unit(&mut self, path: &str) -> Result<(), InternalError> {
+		let name = match self.units.get(path) {
+			Some(unit) => unit.name.clone(),
+			None => return Ok(()),
+		};
+		try!(self.refresh_unit(path));
+
+		// timers report the result of the unit they trigger, so refresh those too
+		let timers: Vec<String> = self.units.values()
+			.filter(|unit| unit.timer.as_ref().map(|t| t.triggers == name).unwrap_or(false))
+			.map(|unit| unit.path.clone())
+			.collect();
+		for timer in timers {
+			try!(self.refresh_unit(timer.deref()));
+		}
+		self.emit()
+	}
+
+	// Timers may become overdue without any change on the bus
+	fn check_timers(&mut self) -> Result<(), InternalError> {
+		let grace = self.options.timer_grace;
+		let changed: Vec<String> = self.units.values()
+			.filter(|unit| match unit.timer {
+				Some(ref timer) => timer.overdue != is_overdue(timer.next_elapse, grace),
+				None => false,
+			})
+			.map(|unit| unit.path.clone())
+			.collect();
+		if changed.is_empty() {
+			return Ok(());
+		}
+		for path in changed {
+			try!(self.refresh_unit(path.deref()));
+		}
+		self.emit()
+	}
+
 	fn _update_unit(&mut self, unit: DBusUnit) {
 		let _:Option<Status> = self.state.insert(unit.name.clone(), unit.status.clone());
 		let _:Option<DBusUnit> = self.units.insert(unit.path.clone(), unit);
 	}
 
-	fn emit(&self) -> Result<(), InternalError> {
+	fn emit(&mut self) -> Result<(), InternalError> {
+		if self.reading_journals() {
+			let since = match self.held_since {
+				Some(since) => since,
+				None => {
+					let now = Instant::now();
+					self.held_since = Some(now);
+					now
+				},
+			};
+			if since.elapsed() < time::Duration::from_millis(JOURNAL_WAIT_MS) {
+				return Ok(());
+			}
+		}
+		self.held_since = None;
 		try!(self.sender.send(Arc::new(Update {
 			scope: UpdateScope::Snapshot,
 			source: self.source.clone(),
@@ -296,12 +688,6 @@ impl<'a> DBusState<'a> {
 		Ok(())
 	}
 
-	fn unit_changed(&mut self, unit: DBusUnit) -> Result<(), InternalError> {
-		self._update_unit(unit);
-		// NOTE: this sends the whole state - can we simplify this to an individual Update?
-		self.emit()
-	}
-
 	fn add_unit(&mut self, name: &String, path: &Path) -> Result<(),InternalError> {
 		let _path = path.to_string();
 		let path = &_path;
@@ -312,8 +698,10 @@ impl<'a> DBusState<'a> {
 		debug!("Adding unit {} with path {}", name, path);
 
 		try!(self.conn.add_match(property_match_rule(path).deref()));
-		let status = try!(self.get_unit_status(path));
-		let unit = DBusUnit { status: status, name: name.clone(), path: path.clone() };
+		let (mut status, timer) = try!(self.get_unit_status(name, path));
+		// units which are already failed have no transition, but we still want their logs
+		self.annotate_failure(name, None, &mut status);
+		let unit = DBusUnit { status: status, name: name.clone(), path: path.clone(), timer: timer };
 		self._update_unit(unit);
 		Ok(())
 	}
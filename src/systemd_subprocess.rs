@@ -260,9 +260,9 @@ impl DataSource for SystemdPoller {
 }
 
 impl PullDataSource for SystemdPoller {
-	fn poll(&self) -> Result<Data, InternalError> {
+	fn poll(&self) -> Result<Vec<Data>, InternalError> {
 		let mut child = try!(self.spawn());
 		let state = try!(self.parse(&mut child));
-		Ok(Data::State(state))
+		Ok(vec!(Data::State(state)))
 	}
 }
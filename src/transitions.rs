@@ -0,0 +1,266 @@
+// Derives transition events (e.g. "nginx.service: Active -> Error")
+// by comparing each state snapshot with the previous one from the same source.
+//
+// Items which change state too often are considered to be flapping. While
+// flapping, an item's state is reported as `State::Flapping` and its
+// individual transitions are suppressed in favour of a single event when
+// flapping starts and another once it stops.
+
+use std::collections::{HashMap,VecDeque};
+use std::sync::{Arc};
+use rustc_serialize::json::{Json};
+use monitor::*;
+use config::FlappingConfig;
+
+// set on every derived event, so that consumers can tell them apart
+// from events emitted by sources
+pub const TRANSITION_ATTR: &'static str = "transition";
+pub const FLAPPING_ATTR: &'static str = "flapping";
+
+pub fn is_transition(update: &Update) -> bool {
+	match update.data {
+		Data::Event(ref event) => event.attrs.contains_key(TRANSITION_ATTR),
+		_ => false,
+	}
+}
+
+fn severity_of_transition(old: &State, new: &State) -> Severity {
+	match (old, new) {
+		(_, &State::Error) => Severity::Error,
+		(_, &State::Unknown) => Severity::Warning,
+		(&State::Error, _) => Severity::Notice,
+		_ => Severity::Info,
+	}
+}
+
+struct ItemState {
+	// the state reported by the source (never `Flapping`)
+	state: State,
+	since: Time,
+	// times of recent transitions, within the flapping window
+	transitions: VecDeque<Time>,
+	flapping: bool,
+}
+
+struct SourceState {
+	items: HashMap<String, ItemState>,
+	// the latest snapshot as reported by the source
+	last: Arc<Update>,
+}
+
+pub struct TransitionDetector {
+	config: FlappingConfig,
+	sources: HashMap<String, SourceState>,
+}
+
+struct Evaluation {
+	state: HashMap<String, Status>,
+	events: Vec<Event>,
+	any_flapping: bool,
+	flapping_changed: bool,
+}
+
+fn event_attrs(id: &str, status: &Status) -> Attributes {
+	// include the new status' attrs, since they often explain the transition
+	let mut attrs = (*status.attrs).clone();
+	attrs.insert(TRANSITION_ATTR.to_string(), Json::Boolean(true));
+	attrs.insert("unit".to_string(), Json::String(id.to_string()));
+	attrs
+}
+
+fn transition_event(id: &str, old: &State, since: &Time, status: &Status, time: &Time) -> Event {
+	let duration = time.duration_since(since);
+	let mut attrs = event_attrs(id, status);
+	attrs.insert("old_state".to_string(), Json::String(format!("{:?}", old)));
+	attrs.insert("new_state".to_string(), Json::String(format!("{:?}", status.state)));
+	attrs.insert("previous_duration_ms".to_string(), Json::I64(duration.num_milliseconds()));
+	Event {
+		id: Some(id.to_string()),
+		severity: Some(severity_of_transition(old, &status.state)),
+		message: Some(format!("{}: {:?} -> {:?}", id, old, status.state)),
+		attrs: Arc::new(attrs),
+	}
+}
+
+fn flapping_event(id: &str, item: &ItemState, status: &Status) -> Event {
+	let mut attrs = event_attrs(id, status);
+	attrs.insert(FLAPPING_ATTR.to_string(), Json::Boolean(item.flapping));
+	let (severity, message) = if item.flapping {
+		(Severity::Warning, format!("{} started flapping", id))
+	} else {
+		(Severity::Notice, format!("{} stopped flapping ({:?})", id, status.state))
+	};
+	Event {
+		id: Some(id.to_string()),
+		severity: Some(severity),
+		message: Some(message),
+		attrs: Arc::new(attrs),
+	}
+}
+
+impl TransitionDetector {
+	pub fn new(config: FlappingConfig) -> TransitionDetector {
+		TransitionDetector {
+			config: config,
+			sources: HashMap::new(),
+		}
+	}
+
+	fn evaluate(&self,
+		previous: &mut HashMap<String, ItemState>,
+		state: &HashMap<String, Status>,
+		time: &Time) -> (HashMap<String, ItemState>, Evaluation)
+	{
+		let mut rv = Evaluation {
+			state: HashMap::with_capacity(state.len()),
+			events: Vec::new(),
+			any_flapping: false,
+			flapping_changed: false,
+		};
+		let mut items = HashMap::with_capacity(state.len());
+		for (id, status) in state.iter() {
+			// items seen for the first time (including the first snapshot
+			// from each source) don't produce events.
+			let mut item = previous.remove(id).unwrap_or_else(|| ItemState {
+				state: status.state.clone(),
+				since: time.clone(),
+				transitions: VecDeque::new(),
+				flapping: false,
+			});
+
+			let mut transition = None;
+			if item.state != status.state {
+				transition = Some(transition_event(id, &item.state, &item.since, status, time));
+				item.transitions.push_back(time.clone());
+				item.state = status.state.clone();
+				item.since = time.clone();
+			}
+
+			while item.transitions.front().map(|t| time.duration_since(t) > self.config.window).unwrap_or(false) {
+				let _: Option<Time> = item.transitions.pop_front();
+			}
+
+			let was_flapping = item.flapping;
+			if self.config.transitions > 0 {
+				if !item.flapping && item.transitions.len() >= self.config.transitions {
+					item.flapping = true;
+				} else if item.flapping && item.transitions.is_empty() {
+					item.flapping = false;
+				}
+			}
+
+			if item.flapping != was_flapping {
+				rv.flapping_changed = true;
+				rv.events.push(flapping_event(id, &item, status));
+			} else if !item.flapping {
+				rv.events.extend(transition);
+			}
+
+			let status = if item.flapping {
+				rv.any_flapping = true;
+				let mut attrs = (*status.attrs).clone();
+				attrs.insert(FLAPPING_ATTR.to_string(), Json::Boolean(true));
+				attrs.insert("actual_state".to_string(), Json::String(format!("{:?}", status.state)));
+				Status {
+					state: State::Flapping,
+					attrs: Arc::new(attrs),
+				}
+			} else {
+				status.clone()
+			};
+			rv.state.insert(id.clone(), status);
+			items.insert(id.clone(), item);
+		}
+		(items, rv)
+	}
+
+	fn derived(update: &Update, events: Vec<Event>, time: &Time) -> Vec<Arc<Update>> {
+		events.into_iter().map(|event| Arc::new(Update {
+			source: update.source.clone(),
+			scope: UpdateScope::Partial,
+			time: time.clone(),
+			data: Data::Event(event),
+		})).collect()
+	}
+
+	// Returns the update to deliver in place of `update` (which differs
+	// only if some items are flapping), followed by any derived events.
+	pub fn process(&mut self, update: Arc<Update>) -> Vec<Arc<Update>> {
+		let evaluation = match (&update.scope, &update.data) {
+			(&UpdateScope::Snapshot, &Data::State(ref state)) => {
+				let mut previous = self.sources.remove(&update.source.id)
+					.map(|source| source.items)
+					.unwrap_or_else(HashMap::new);
+				let (items, evaluation) = self.evaluate(&mut previous, state, &update.time);
+				self.sources.insert(update.source.id.clone(), SourceState {
+					items: items,
+					last: update.clone(),
+				});
+				Some(evaluation)
+			},
+			_ => None,
+		};
+		let evaluation = match evaluation {
+			Some(evaluation) => evaluation,
+			None => return vec!(update),
+		};
+
+		let mut rv = Vec::with_capacity(evaluation.events.len() + 1);
+		let events = Self::derived(&update, evaluation.events, &update.time);
+		if evaluation.any_flapping {
+			rv.push(Arc::new(Update {
+				source: update.source.clone(),
+				scope: UpdateScope::Snapshot,
+				time: update.time.clone(),
+				data: Data::State(evaluation.state),
+			}));
+		} else {
+			rv.push(update);
+		}
+		rv.extend(events);
+		rv
+	}
+
+	// Items stop flapping after a quiet period, which may pass without
+	// any new snapshot. This re-evaluates sources with flapping items,
+	// returning a new snapshot for each source where flapping has stopped.
+	pub fn tick(&mut self) -> Vec<Arc<Update>> {
+		let now = Time::now();
+		let flapping: Vec<String> = self.sources.iter()
+			.filter(|&(_, source)| source.items.values().any(|item| item.flapping))
+			.map(|(id, _)| id.clone())
+			.collect();
+
+		let mut rv = Vec::new();
+		for id in flapping {
+			let mut source = match self.sources.remove(&id) {
+				Some(source) => source,
+				None => continue,
+			};
+			let evaluation = match source.last.data {
+				Data::State(ref state) => {
+					let (items, evaluation) = self.evaluate(&mut source.items, state, &now);
+					source.items = items;
+					Some(evaluation)
+				},
+				_ => None,
+			};
+			match evaluation {
+				Some(evaluation) => if evaluation.flapping_changed {
+					let update = Arc::new(Update {
+						source: source.last.source.clone(),
+						scope: UpdateScope::Snapshot,
+						time: now.clone(),
+						data: Data::State(evaluation.state),
+					});
+					let events = Self::derived(&update, evaluation.events, &now);
+					rv.push(update);
+					rv.extend(events);
+				},
+				None => (),
+			}
+			self.sources.insert(id, source);
+		}
+		rv
+	}
+}